@@ -0,0 +1,279 @@
+//! An aging report of work in progress: every open PR with how old it is, how
+//! long since anything happened to it, and which phase it's in. PRs older than
+//! most merged PRs were when they merged are flagged, since they're probably
+//! stuck.
+
+use crate::business::{Calendar, Clock, WallClock};
+use crate::{db, Result};
+
+use rusqlite::Connection;
+use serde_derive::Serialize;
+use std::fmt;
+
+#[derive(Clone, Serialize, Debug)]
+pub struct Aging {
+    /// 85% of merged PRs were merged within this many minutes of being opened.
+    /// `None` if no PRs have been merged.
+    pub cycle_time_p85: Option<u32>,
+    /// Oldest first.
+    pub prs: Vec<AgingPr>,
+}
+
+#[derive(Clone, Serialize, Debug, Eq, PartialEq)]
+pub struct AgingPr {
+    pub repo: String,
+    pub number: u32,
+    pub title: String,
+    pub url: String,
+    pub author: String,
+    pub phase: Phase,
+    /// Minutes since the PR was opened.
+    pub age: u32,
+    /// Minutes since the PR was last active, see `db::OpenPr::last_active`.
+    pub idle: u32,
+    /// `age` and `idle` in business time, see `business`.
+    pub business_age: u32,
+    pub business_idle: u32,
+    /// `age` is beyond `Aging::cycle_time_p85`.
+    pub stuck: bool,
+}
+
+/// Which phase of `cycle::Phases` an open PR is in.
+#[derive(Clone, Copy, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Draft,
+    AwaitingReview,
+    InReview,
+    Approved,
+}
+
+impl Phase {
+    fn of(pr: &db::OpenPr) -> Phase {
+        if pr.draft {
+            Phase::Draft
+        } else if pr.approved {
+            Phase::Approved
+        } else if pr.reviewed {
+            Phase::InReview
+        } else {
+            Phase::AwaitingReview
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Draft => "draft",
+            Phase::AwaitingReview => "awaiting review",
+            Phase::InReview => "in review",
+            Phase::Approved => "approved",
+        })
+    }
+}
+
+/// The aging report as of now.
+pub fn aging(conn: &Connection) -> Result<Aging> {
+    aging_at(conn, "now")
+}
+
+/// The aging report as of `now`, which may be any time SQLite understands.
+fn aging_at(conn: &Connection, now: &str) -> Result<Aging> {
+    let calendar = Calendar::get()?;
+    let cycle_time_p85 = p85(db::times_to_merge(conn)?);
+    let prs = db::open_prs(conn, now)?
+        .into_iter()
+        .map(|pr| {
+            let age = WallClock.minutes(pr.created, pr.now);
+            AgingPr {
+                phase: Phase::of(&pr),
+                stuck: cycle_time_p85.map_or(false, |p85| age > p85),
+                age,
+                idle: WallClock.minutes(pr.last_active, pr.now),
+                business_age: calendar.minutes(pr.created, pr.now),
+                business_idle: calendar.minutes(pr.last_active, pr.now),
+                repo: pr.repo,
+                number: pr.number,
+                title: pr.title,
+                url: pr.url,
+                author: pr.author,
+            }
+        })
+        .collect();
+    Ok(Aging {
+        cycle_time_p85,
+        prs,
+    })
+}
+
+/// The smallest value which at least 85% of `values` are less than or equal to.
+fn p85(mut values: Vec<u32>) -> Option<u32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort();
+    let rank = (0.85 * values.len() as f64).ceil() as usize;
+    Some(values[rank.max(1) - 1])
+}
+
+impl fmt::Display for Aging {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cycle_time_p85 {
+            Some(p85) => writeln!(
+                f,
+                "85% of PRs are merged within {}, older PRs are marked with *",
+                Minutes(p85)
+            )?,
+            None => writeln!(f, "No PRs have been merged yet")?,
+        }
+        writeln!(
+            f,
+            "  {:<30} {:<16} {:>8} {:>8}  title",
+            "PR", "phase", "age", "idle"
+        )?;
+        for pr in &self.prs {
+            writeln!(
+                f,
+                "{} {:<30} {:<16} {:>8} {:>8}  {}",
+                if pr.stuck { '*' } else { ' ' },
+                format!("{}#{}", pr.repo, pr.number),
+                pr.phase.to_string(),
+                Minutes(pr.age).to_string(),
+                Minutes(pr.idle).to_string(),
+                pr.title
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A duration in minutes, displayed in days and hours (or just minutes, if it's
+/// less than an hour).
+pub(crate) struct Minutes(pub u32);
+
+impl fmt::Display for Minutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (days, hours, minutes) = (self.0 / 1440, self.0 % 1440 / 60, self.0 % 60);
+        match (days, hours) {
+            (0, 0) => write!(f, "{}m", minutes),
+            (0, _) => write!(f, "{}h", hours),
+            _ => write!(f, "{}d {}h", days, hours),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::{self, Date, Sha, Status};
+    use crate::source;
+
+    fn sample(id: u32, created: &str, time: &str, status: Status, draft: bool) -> data::Sample {
+        let host = "https://api.github.com".to_owned();
+        data::Sample {
+            host: host.clone(),
+            time: Date::new(time.to_owned()),
+            pr: data::PullRequest {
+                host: host.clone(),
+                id,
+                repo: "nrc/gh-velocity".to_owned(),
+                number: id,
+                title: format!("PR {}", id),
+                body: String::new(),
+                author: data::User {
+                    host,
+                    id: 42,
+                    username: "bob".to_owned(),
+                    url: String::new(),
+                    bot: false,
+                },
+                created: Date::new(created.to_owned()),
+                url: String::new(),
+                labels: vec![],
+            },
+            status,
+            commits: 1,
+            additions: 10,
+            deletions: 5,
+            changed_files: 2,
+            review_comments: 0,
+            first_commit: Sha("abc".to_owned()),
+            head: Sha("def".to_owned()),
+            merge_commit: None,
+            draft,
+            milestone: None,
+            files: vec![],
+        }
+    }
+
+    #[test]
+    fn test_aging_at() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+        let merged = "2019-05-08 00:00:00";
+        let samples = [
+            // Merged after two days.
+            sample(
+                1,
+                "2019-05-06 00:00:00",
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+                false,
+            ),
+            // Open since the Monday before, last updated on Friday.
+            sample(
+                2,
+                "2019-05-13 00:00:00",
+                "2019-05-17 00:00:00",
+                Status::Open,
+                false,
+            ),
+            // A draft opened on Sunday.
+            sample(
+                3,
+                "2019-05-19 12:00:00",
+                "2019-05-19 12:00:00",
+                Status::Open,
+                true,
+            ),
+        ];
+        for s in &samples {
+            source::record_sample(&conn, s)?;
+        }
+
+        let aging = aging_at(&conn, "2019-05-20")?;
+        assert_eq!(aging.cycle_time_p85, Some(2 * 1440));
+        let prs: Vec<_> = aging
+            .prs
+            .iter()
+            .map(|pr| (pr.number, pr.phase, pr.age, pr.idle, pr.stuck))
+            .collect();
+        assert_eq!(
+            prs,
+            vec![
+                (2, Phase::AwaitingReview, 7 * 1440, 3 * 1440, true),
+                (3, Phase::Draft, 720, 720, false),
+            ]
+        );
+        // Working hours are 9 to 5 on weekdays.
+        assert_eq!(aging.prs[0].business_age, 5 * 480);
+        assert_eq!(aging.prs[0].business_idle, 480);
+        assert_eq!(aging.prs[1].business_age, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_p85() {
+        assert_eq!(p85(vec![]), None);
+        assert_eq!(p85(vec![5]), Some(5));
+        assert_eq!(p85((1..=20).rev().collect()), Some(17));
+    }
+
+    #[test]
+    fn test_minutes() {
+        assert_eq!(Minutes(45).to_string(), "45m");
+        assert_eq!(Minutes(150).to_string(), "2h");
+        assert_eq!(Minutes(3 * 1440 + 125).to_string(), "3d 2h");
+    }
+}
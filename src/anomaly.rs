@@ -0,0 +1,364 @@
+//! Watching weekly stats for anomalies, so that a regression doesn't go
+//! unnoticed until someone looks at the charts. We use control limits, as in
+//! an XmR chart: each complete week is compared with the weeks before it, and
+//! is anomalous if it's more than three sigmas from their mean, or if it
+//! completes a run of weeks all on one side of the mean (i.e., the process
+//! has changed). Alerts are sent to each of `config::ALERT_SINKS`.
+
+use crate::config::{AlertSink, ALERT_SINKS, ANOMALY_BASELINE_WEEKS};
+use crate::{data, db, Result};
+
+use rusqlite::{Connection, NO_PARAMS};
+use serde_derive::Serialize;
+use std::fmt;
+
+/// The fewest weeks to compare with, fewer than this and we can't tell what's
+/// normal.
+const MIN_BASELINE_WEEKS: usize = 4;
+/// How many weeks in a row on one side of the mean count as a shift.
+const RUN_WEEKS: usize = 8;
+/// Converts the mean moving range to an estimate of the standard deviation.
+const D2: f64 = 1.128;
+
+#[derive(Clone, Copy, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Series {
+    /// Merged PRs.
+    Throughput,
+    /// The median time to merge, in minutes, for weeks with merged PRs.
+    TimeToMerge,
+    /// Open PRs at the end of the week.
+    OpenPrs,
+}
+
+impl Series {
+    const ALL: [Series; 3] = [Series::Throughput, Series::TimeToMerge, Series::OpenPrs];
+
+    /// Whether an increase in this series is bad news.
+    fn higher_is_worse(self) -> bool {
+        self != Series::Throughput
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Outside the control limits.
+    Outlier,
+    /// The last of `RUN_WEEKS` weeks on the same side of the mean.
+    Shift,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct Alert {
+    pub series: Series,
+    pub kind: Kind,
+    /// The start of the week which is anomalous.
+    pub week: String,
+    pub value: f64,
+    /// The mean and control limits of the weeks it was compared with.
+    pub mean: f64,
+    pub lower_limit: f64,
+    pub upper_limit: f64,
+    /// Whether the change is for the worse, e.g., throughput went down.
+    pub regression: bool,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {:?} in the week of {}: {:.1} (mean {:.1}, limits {:.1} to {:.1})",
+            if self.regression {
+                "Regression"
+            } else {
+                "Improvement"
+            },
+            self.series,
+            self.week,
+            self.value,
+            self.mean,
+            self.lower_limit,
+            self.upper_limit
+        )?;
+        if self.kind == Kind::Shift {
+            write!(f, ", {} weeks in a row", RUN_WEEKS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Look for anomalies in the most recent complete week, and send an alert to
+/// each sink for any which we haven't already alerted about.
+pub fn check() -> Result<()> {
+    let conn = db::connection()?;
+    deliver(&conn, &alerts(&conn)?, ALERT_SINKS)
+}
+
+/// Send each of `alerts` to each of `sinks` which it hasn't been sent to yet.
+/// Delivery is recorded per sink, so a failing sink doesn't stop the others
+/// and is retried next time.
+fn deliver(conn: &Connection, alerts: &[Alert], sinks: &[AlertSink]) -> Result<()> {
+    let mut result = Ok(());
+    for alert in alerts {
+        for sink in sinks {
+            let sent = data::SentAlert {
+                series: format!("{:?}", alert.series),
+                week: alert.week.clone(),
+                kind: format!("{:?}", alert.kind),
+                sink: sink_name(sink),
+            };
+            if db::alert_sent(conn, &sent)? {
+                continue;
+            }
+            match send(sink, alert) {
+                Ok(()) => sent.insert_into(conn)?,
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+    }
+    result
+}
+
+/// Identifies a sink in `data::SentAlert`.
+fn sink_name(sink: &AlertSink) -> String {
+    match sink {
+        AlertSink::Log => "log".to_owned(),
+        AlertSink::Webhook { url } => (*url).to_owned(),
+    }
+}
+
+fn alerts(conn: &Connection) -> Result<Vec<Alert>> {
+    let this_week: String = conn.query_row(
+        "SELECT date('now', 'weekday 0', '-6 days')",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let series = weekly_series(conn, &this_week)?;
+    Ok(Series::ALL
+        .iter()
+        .zip(&series)
+        .flat_map(|(&s, weeks)| detect(s, weeks))
+        .collect())
+}
+
+/// `(week, value)` for each complete week before `this_week`, oldest first, for
+/// each of `Series::ALL`. Weeks with no activity are included, right up to
+/// `this_week`, since a sudden stop is the biggest anomaly of all.
+fn weekly_series(conn: &Connection, this_week: &str) -> Result<[Vec<(String, f64)>; 3]> {
+    let weeks: Vec<_> = db::weekly_stats_through(conn, &db::PrFilter::default(), this_week)?
+        .into_iter()
+        .filter(|w| &*w.start_date < this_week)
+        .collect();
+    let throughput = weeks
+        .iter()
+        .map(|w| (w.start_date.clone(), f64::from(w.merged_prs)))
+        .collect();
+    let time_to_merge = weeks
+        .iter()
+        .filter(|w| w.merged_prs > 0)
+        .map(|w| (w.start_date.clone(), f64::from(w.time_to_merge.median)))
+        .collect();
+
+    let days = db::open_prs_per_day(conn, &db::PrFilter::default())?;
+    let open_prs = weeks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, w)| {
+            let end = weeks.get(i + 1).map_or(this_week, |w| &*w.start_date);
+            // The count from the last day we have a sample from, which may be
+            // before this week if nothing changed.
+            days.iter()
+                .filter(|d| &*d.date < end)
+                .last()
+                .map(|d| (w.start_date.clone(), f64::from(d.open_prs)))
+        })
+        .collect();
+
+    Ok([throughput, time_to_merge, open_prs])
+}
+
+/// Anomalies in the most recent of `weeks`.
+fn detect(series: Series, weeks: &[(String, f64)]) -> Vec<Alert> {
+    let values: Vec<f64> = weeks.iter().map(|&(_, v)| v).collect();
+    let (week, value) = match weeks.last() {
+        Some((week, value)) => (week, *value),
+        None => return vec![],
+    };
+    let alert = |kind, (mean, lower_limit, upper_limit)| Alert {
+        series,
+        kind,
+        week: week.clone(),
+        value,
+        mean,
+        lower_limit,
+        upper_limit,
+        regression: (value > mean) == series.higher_is_worse(),
+    };
+
+    let mut result = vec![];
+    let n = values.len();
+    if let Some(limits) = limits(&values[n.saturating_sub(ANOMALY_BASELINE_WEEKS + 1)..n - 1]) {
+        if value < limits.1 || value > limits.2 {
+            result.push(alert(Kind::Outlier, limits));
+        }
+    }
+    // Only alert on the week which completes the run, not every week after.
+    if let Some(limits) = shift(&values) {
+        if shift(&values[..n - 1]).is_none() {
+            result.push(alert(Kind::Shift, limits));
+        }
+    }
+    result
+}
+
+/// The limits of the weeks before the last `RUN_WEEKS` of `values`, if those
+/// weeks are all above or all below the mean.
+fn shift(values: &[f64]) -> Option<(f64, f64, f64)> {
+    let run_start = values.len().checked_sub(RUN_WEEKS)?;
+    let limits = limits(&values[run_start.saturating_sub(ANOMALY_BASELINE_WEEKS)..run_start])?;
+    let run = &values[run_start..];
+    if run.iter().all(|&v| v > limits.0) || run.iter().all(|&v| v < limits.0) {
+        Some(limits)
+    } else {
+        None
+    }
+}
+
+/// `(mean, lower limit, upper limit)` of `baseline`. `None` if there are too
+/// few values, or they never vary, so any change would be anomalous.
+fn limits(baseline: &[f64]) -> Option<(f64, f64, f64)> {
+    if baseline.len() < MIN_BASELINE_WEEKS {
+        return None;
+    }
+    let mean = baseline.iter().sum::<f64>() / baseline.len() as f64;
+    let moving_range = baseline
+        .windows(2)
+        .map(|w| (w[1] - w[0]).abs())
+        .sum::<f64>()
+        / (baseline.len() - 1) as f64;
+    if moving_range == 0.0 {
+        return None;
+    }
+    let sigma = moving_range / D2;
+    Some((mean, mean - 3.0 * sigma, mean + 3.0 * sigma))
+}
+
+fn send(sink: &AlertSink, alert: &Alert) -> Result<()> {
+    match sink {
+        AlertSink::Log => eprintln!("{}", alert),
+        AlertSink::Webhook { url } => {
+            reqwest::Client::new()
+                .post(*url)
+                .json(alert)
+                .send()?
+                .error_for_status()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use tiny_http::{Response, Server};
+
+    fn weeks(values: &[f64]) -> Vec<(String, f64)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| (format!("week {}", i), v))
+            .collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        let normal = [10.0, 12.0, 9.0, 11.0, 10.0, 12.0, 11.0, 9.0];
+        assert_eq!(detect(Series::Throughput, &weeks(&normal)), vec![]);
+        // Too few weeks to tell.
+        assert_eq!(
+            detect(Series::Throughput, &weeks(&[10.0, 11.0, 1.0])),
+            vec![]
+        );
+
+        let mut values = normal.to_vec();
+        values.push(2.0);
+        let alerts = detect(Series::Throughput, &weeks(&values));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, Kind::Outlier);
+        assert_eq!(alerts[0].week, "week 8");
+        assert!(alerts[0].regression);
+        assert!(!detect(Series::OpenPrs, &weeks(&values))[0].regression);
+
+        // Eight weeks a little above the mean is a shift, but only once.
+        let mut values = normal.to_vec();
+        values.extend(&[12.5; 7]);
+        assert_eq!(detect(Series::TimeToMerge, &weeks(&values)), vec![]);
+        values.push(12.5);
+        let alerts = detect(Series::TimeToMerge, &weeks(&values));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, Kind::Shift);
+        assert!(alerts[0].regression);
+        values.push(12.5);
+        assert_eq!(detect(Series::TimeToMerge, &weeks(&values)), vec![]);
+    }
+
+    #[test]
+    fn test_deliver() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        db::init(&conn)?;
+        let mut values = vec![10.0, 12.0, 9.0, 11.0, 10.0];
+        values.push(30.0);
+        let alerts = detect(Series::OpenPrs, &weeks(&values));
+        // Nothing listens on port 1.
+        let sinks = &[
+            AlertSink::Webhook {
+                url: "http://127.0.0.1:1/alerts",
+            },
+            AlertSink::Log,
+        ];
+
+        assert!(deliver(&conn, &alerts, sinks).is_err());
+        let sent: Vec<String> = {
+            let mut stmt = conn.prepare("SELECT sink FROM sent_alert")?;
+            let rows = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        assert_eq!(sent, vec!["log".to_owned()]);
+        // Only the webhook is retried.
+        assert!(deliver(&conn, &alerts, &sinks[1..]).is_ok());
+        assert!(deliver(&conn, &alerts, &sinks[..1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_webhook() -> Result<()> {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", server.server_addr());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            sender.send((request.url().to_owned(), body)).unwrap();
+            let _ = request.respond(Response::empty(204));
+        });
+
+        let mut values = vec![10.0, 12.0, 9.0, 11.0, 10.0];
+        values.push(30.0);
+        let alert = detect(Series::OpenPrs, &weeks(&values)).remove(0);
+        let url: &'static str = Box::leak(url.into_boxed_str());
+        send(&AlertSink::Webhook { url }, &alert)?;
+
+        let (path, body) = receiver.recv().unwrap();
+        assert_eq!(path, "/alerts");
+        let body: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(body["series"], "open_prs");
+        assert_eq!(body["kind"], "outlier");
+        assert_eq!(body["week"], "week 5");
+        assert_eq!(body["regression"], true);
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Mapping the paths a PR changes to areas of its repo, so we can compare,
+//! e.g., how long PRs to the compiler take to review with PRs to the docs.
+//! Areas come from a repo's CODEOWNERS file or `config::Areas::Rules`.
+
+use crate::config::{Areas, Repo, REPOS};
+use crate::git::Mirror;
+use crate::{db, Result};
+
+use rusqlite::Connection;
+
+/// Where GitHub and GitLab look for CODEOWNERS, in the order they look.
+const CODEOWNERS_PATHS: &[&str] = &[
+    ".github/CODEOWNERS",
+    "CODEOWNERS",
+    "docs/CODEOWNERS",
+    ".gitlab/CODEOWNERS",
+];
+
+/// Assign an area to every path changed by a PR, for every repo. Should be run
+/// after `git::analyse_all` so that mirrors are up to date. A repo which fails
+/// doesn't stop the others being updated, the first error is returned
+/// afterwards.
+pub fn update_all() -> Result<()> {
+    let conn = db::connection()?;
+    let mut result = Ok(());
+    for repo in REPOS {
+        result = result.and(update_repo(&conn, repo));
+    }
+    result
+}
+
+/// Assign an area to each of `paths` changed by a PR to `repo` (a full name)
+/// on `host`, if its areas come from `config::Areas::Rules`. Areas from
+/// CODEOWNERS need an up to date mirror, so wait for `update_all`.
+pub fn update_paths(conn: &Connection, host: &str, repo: &str, paths: &[String]) -> Result<()> {
+    match REPOS
+        .iter()
+        .find(|r| r.host == host && r.full_name() == repo)
+    {
+        Some(repo) => update_rule_paths(conn, repo, paths),
+        None => Ok(()),
+    }
+}
+
+fn update_rule_paths(conn: &Connection, repo: &Repo, paths: &[String]) -> Result<()> {
+    if let Areas::Rules(rules) = repo.areas {
+        let rules = configured_rules(rules);
+        let full_name = repo.full_name();
+        for path in paths {
+            db::set_area(conn, repo.host, &full_name, path, area(&rules, path))?;
+        }
+    }
+    Ok(())
+}
+
+fn update_repo(conn: &Connection, repo: &Repo) -> Result<()> {
+    let rules = match repo.areas {
+        Areas::None => return Ok(()),
+        Areas::CodeOwners => match codeowners(repo)? {
+            Some(rules) => rules,
+            None => return Ok(()),
+        },
+        Areas::Rules(rules) => configured_rules(rules),
+    };
+    let full_name = repo.full_name();
+    for path in db::changed_paths(conn, repo.host, &full_name)? {
+        db::set_area(conn, repo.host, &full_name, &path, area(&rules, &path))?;
+    }
+    Ok(())
+}
+
+/// `config::Areas::Rules` in the same form as `parse_codeowners`.
+fn configured_rules(rules: &[(&str, &str)]) -> Vec<(String, Option<String>)> {
+    rules
+        .iter()
+        .map(|&(pattern, area)| (pattern.to_owned(), Some(area.to_owned())))
+        .collect()
+}
+
+/// The rules in `repo`'s CODEOWNERS file, `None` if we don't keep a mirror of
+/// `repo` or it has no CODEOWNERS file.
+fn codeowners(repo: &Repo) -> Result<Option<Vec<(String, Option<String>)>>> {
+    if repo.clone_url.is_none() {
+        return Ok(None);
+    }
+    let mirror = Mirror::open(repo);
+    for path in CODEOWNERS_PATHS {
+        if let Some(text) = mirror.file(path)? {
+            return Ok(Some(parse_codeowners(&text)));
+        }
+    }
+    Ok(None)
+}
+
+/// `(pattern, owners)` for each rule. A rule without owners makes paths it
+/// matches unowned. GitLab's sections are ignored, so each path gets at most
+/// one area.
+fn parse_codeowners(text: &str) -> Vec<(String, Option<String>)> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('[') && !line.starts_with("^["))
+        .map(|line| {
+            let mut words = line.split_whitespace();
+            let pattern = words.next().unwrap().to_owned();
+            let owners: Vec<&str> = words.collect();
+            if owners.is_empty() {
+                (pattern, None)
+            } else {
+                (pattern, Some(owners.join(" ")))
+            }
+        })
+        .collect()
+}
+
+/// The area of the last rule which matches `path`.
+fn area<'a>(rules: &'a [(String, Option<String>)], path: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .rev()
+        .find(|(pattern, _)| matches(pattern, path))
+        .and_then(|(_, area)| area.as_ref().map(|a| &**a))
+}
+
+/// Whether a CODEOWNERS (i.e., gitignore-style) pattern matches `path` (which
+/// is relative to the repo's root), or a directory containing it.
+fn matches(pattern: &str, path: &str) -> bool {
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    // A pattern with a slash is relative to the root, otherwise it can match
+    // at any depth.
+    let mut segments: Vec<&str> = vec![];
+    if !pattern.contains('/') {
+        segments.push("**");
+    }
+    segments.extend(pattern.trim_start_matches('/').split('/'));
+    let path: Vec<&str> = path.split('/').collect();
+    matches_segments(&segments, &path, dir_only)
+}
+
+fn matches_segments(pattern: &[&str], path: &[&str], dir_only: bool) -> bool {
+    match pattern.split_first() {
+        // The whole path matched, or a directory containing it.
+        None => !(path.is_empty() && dir_only),
+        Some((&"**", rest)) => {
+            (0..=path.len()).any(|i| matches_segments(rest, &path[i..], dir_only))
+        }
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path)) => {
+                matches_glob(segment.as_bytes(), name.as_bytes())
+                    && matches_segments(rest, path, dir_only)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match a single path segment, `*` matches any characters and `?` matches one.
+fn matches_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => (0..=name.len()).any(|i| matches_glob(rest, &name[i..])),
+        (Some((b'?', rest)), Some((_, name))) => matches_glob(rest, name),
+        (Some((p, rest)), Some((n, name))) => p == n && matches_glob(rest, name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Deploys, Forge};
+    use crate::data::{self, Date};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*", "src/main.rs"));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "src/main.c"));
+        assert!(matches("docs", "docs/index.md"));
+        assert!(matches("docs/", "a/docs/index.md"));
+        assert!(!matches("docs/", "docs"));
+        assert!(matches("/compiler/", "compiler/parse/lexer.rs"));
+        assert!(!matches("/compiler/", "src/compiler/lexer.rs"));
+        assert!(matches("compiler/*.rs", "compiler/lib.rs"));
+        assert!(!matches("compiler/*.rs", "compiler/parse/lexer.rs"));
+        assert!(matches("compiler/**/lexer.rs", "compiler/parse/lexer.rs"));
+        assert!(matches("compiler/**/lexer.rs", "compiler/lexer.rs"));
+        assert!(matches("README.m?", "README.md"));
+    }
+
+    #[test]
+    fn test_codeowners() {
+        let rules = parse_codeowners(
+            "# Owners\n\
+             * @org/everyone\n\
+             /compiler/ @org/compiler @alice # the compiler\n\
+             [Docs]\n\
+             docs/ @org/docs\n\
+             docs/generated/\n",
+        );
+        assert_eq!(rules.len(), 4);
+        assert_eq!(area(&rules, "README.md"), Some("@org/everyone"));
+        assert_eq!(
+            area(&rules, "compiler/lib.rs"),
+            Some("@org/compiler @alice")
+        );
+        assert_eq!(area(&rules, "docs/index.md"), Some("@org/docs"));
+        assert_eq!(area(&rules, "docs/generated/api.md"), None);
+    }
+
+    #[test]
+    fn test_rules() -> Result<()> {
+        const REPO: Repo = Repo {
+            forge: Forge::GitHub,
+            host: "https://api.github.com",
+            ca_cert: None,
+            clone_url: None,
+            owner: "nrc",
+            name: "gh-velocity",
+            deploys: Deploys::None,
+            areas: Areas::Rules(&[("*", "everything"), ("docs/", "docs")]),
+        };
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+        let pr = |id| data::PullRequest {
+            host: REPO.host.to_owned(),
+            id,
+            repo: REPO.full_name(),
+            number: id,
+            title: String::new(),
+            body: String::new(),
+            author: data::User {
+                host: REPO.host.to_owned(),
+                id: 42,
+                username: "bob".to_owned(),
+                url: String::new(),
+                bot: false,
+            },
+            created: Date::new("2019-05-13T00:00:00Z".to_owned()),
+            url: String::new(),
+            labels: vec![],
+        };
+        pr(1).insert_into(&conn)?;
+        pr(2).insert_into(&conn)?;
+
+        let paths = &["docs/index.md".to_owned(), "src/main.rs".to_owned()];
+        db::replace_files(&conn, REPO.host, 1, paths)?;
+        update_rule_paths(&conn, &REPO, paths)?;
+        assert_eq!(db::areas(&conn)?, vec!["docs", "everything"]);
+
+        // Replacing a PR's files, or another PR changing the same paths,
+        // doesn't lose their areas.
+        db::replace_files(&conn, REPO.host, 2, &paths[1..])?;
+        db::replace_files(&conn, REPO.host, 1, &paths[..1])?;
+        let area_of = |pr: u32| -> Result<String> {
+            Ok(
+                conn.query_row("SELECT area FROM pr_file WHERE pr = ?1", &[&pr], |row| {
+                    row.get(0)
+                })?,
+            )
+        };
+        assert_eq!(area_of(1)?, "docs");
+        assert_eq!(area_of(2)?, "everything");
+        Ok(())
+    }
+}
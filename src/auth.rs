@@ -0,0 +1,239 @@
+//! Credentials for talking to GitHub, either a personal access token or
+//! short-lived installation tokens for a GitHub App.
+
+use crate::config::{GitHubApp, Repo, ACCESS_TOKEN, GITHUB_APPS, USER_AGENT};
+use crate::Result;
+
+use chrono::DateTime;
+use hubcaps::Credentials;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::header;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Installation tokens are valid for an hour, if GitHub doesn't tell us when
+/// they expire.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Refresh tokens this long before they expire, so that a token doesn't expire
+/// in the middle of an update.
+const TOKEN_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Caches installation tokens (keyed by host and org) across threads.
+#[derive(Clone)]
+pub struct Auth {
+    installations: Arc<Mutex<HashMap<(String, String), Installation>>>,
+}
+
+impl Auth {
+    pub fn new() -> Auth {
+        Auth {
+            installations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Credentials for accessing `repo`.
+    pub fn credentials(&self, repo: &Repo) -> Result<Credentials> {
+        Ok(Credentials::Token(self.token(repo)?))
+    }
+
+    /// A token for accessing `repo`, for requests which hubcaps doesn't support.
+    pub fn token(&self, repo: &Repo) -> Result<String> {
+        let app = match GITHUB_APPS.iter().find(|app| app.host == repo.host) {
+            Some(app) => app,
+            None => return Ok(ACCESS_TOKEN.to_owned()),
+        };
+
+        let key = (repo.host.to_owned(), repo.owner.to_owned());
+        if let Some(installation) = self.installations.lock()?.get(&key) {
+            if !installation.needs_refresh(Instant::now()) {
+                return Ok(installation.token.clone());
+            }
+        }
+
+        // Don't hold the lock while we fetch, so other threads can use tokens
+        // for other orgs. If two threads both fetch, either token will do.
+        let installation = Installation::fetch(app, repo)?;
+        let token = installation.token.clone();
+        self.installations.lock()?.insert(key, installation);
+        Ok(token)
+    }
+}
+
+struct Installation {
+    token: String,
+    expires: Instant,
+}
+
+impl Installation {
+    /// Find the app's installation for `repo` and get a new token for it.
+    fn fetch(app: &GitHubApp, repo: &Repo) -> Result<Installation> {
+        let jwt = jwt(app)?;
+        let client = http_client(repo)?;
+
+        let id: InstallationResponse = client
+            .get(&format!("{}/installation", repo.api_url()))
+            .bearer_auth(&jwt)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(
+                header::ACCEPT,
+                "application/vnd.github.machine-man-preview+json",
+            )
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let requested = Instant::now();
+        let token: TokenResponse = client
+            .post(&format!(
+                "{}/app/installations/{}/access_tokens",
+                repo.host, id.id
+            ))
+            .bearer_auth(&jwt)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(
+                header::ACCEPT,
+                "application/vnd.github.machine-man-preview+json",
+            )
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        Ok(Installation {
+            token: token.token,
+            expires: expires(requested, now, &token.expires_at),
+        })
+    }
+
+    fn needs_refresh(&self, now: Instant) -> bool {
+        now + TOKEN_MARGIN >= self.expires
+    }
+}
+
+#[derive(Deserialize)]
+struct InstallationResponse {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+    /// E.g., `2016-07-11T22:14:10Z`.
+    expires_at: String,
+}
+
+/// When a token requested at `requested` expires, given `expires_at` from the
+/// response and the current Unix time, `now`.
+fn expires(requested: Instant, now: u64, expires_at: &str) -> Instant {
+    match DateTime::parse_from_rfc3339(expires_at).map(|t| t.timestamp()) {
+        Ok(expires_at) => {
+            let lifetime = (expires_at - now as i64).max(0) as u64;
+            requested + Duration::from_secs(lifetime)
+        }
+        Err(_) => requested + TOKEN_LIFETIME,
+    }
+}
+
+/// An HTTP client for talking to `repo`'s host, trusting its CA certificate
+/// (if any).
+pub fn http_client(repo: &Repo) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(path) = repo.ca_cert {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(path)?)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Mint a JWT for authenticating as the app itself.
+fn jwt(app: &GitHubApp) -> Result<String> {
+    let key = fs::read(app.private_key_path)?;
+    let key = EncodingKey::from_rsa_pem(&key)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let token = jsonwebtoken::encode(
+        &Header::new(Algorithm::RS256),
+        &Claims::new(app.id, now),
+        &key,
+    )?;
+    Ok(token)
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct Claims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+impl Claims {
+    fn new(app_id: u64, now: u64) -> Claims {
+        Claims {
+            // Allow for some clock drift between us and GitHub.
+            iat: now - 60,
+            // GitHub allows at most ten minutes.
+            exp: now + 9 * 60,
+            iss: app_id.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        assert_eq!(
+            Claims::new(42, 1_000_000),
+            Claims {
+                iat: 999_940,
+                exp: 1_000_540,
+                iss: "42".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_expires() {
+        let requested = Instant::now();
+        assert_eq!(
+            expires(requested, 1_468_275_250 - 600, "2016-07-11T22:14:10Z"),
+            requested + Duration::from_secs(600)
+        );
+        // Fractional seconds and offsets other than `Z`.
+        assert_eq!(
+            expires(
+                requested,
+                1_468_275_250 - 600,
+                "2016-07-11T23:14:10.5+01:00"
+            ),
+            requested + Duration::from_secs(600)
+        );
+        assert_eq!(
+            expires(requested, 1_468_275_250 + 600, "2016-07-11T22:14:10Z"),
+            requested
+        );
+        assert_eq!(expires(requested, 0, "soon"), requested + TOKEN_LIFETIME);
+    }
+
+    #[test]
+    fn test_needs_refresh() {
+        let now = Instant::now();
+        let installation = Installation {
+            token: String::new(),
+            expires: now + TOKEN_LIFETIME,
+        };
+        assert!(!installation.needs_refresh(now));
+        assert!(!installation.needs_refresh(now + Duration::from_secs(50 * 60)));
+        assert!(installation.needs_refresh(now + Duration::from_secs(56 * 60)));
+        assert!(installation.needs_refresh(now + TOKEN_LIFETIME));
+    }
+}
@@ -0,0 +1,215 @@
+//! Grouping stats by periods of time other than weeks: days, calendar months,
+//! quarters or sprints (see `config::SPRINT_START`). Periods are computed in
+//! SQL, so each granularity is an SQL expression for the start of the period
+//! containing a time.
+
+use crate::aging::Minutes;
+use crate::config::{SPRINT_DAYS, SPRINT_START};
+use crate::{db, frontend, GhvError, Result};
+
+use rusqlite::Connection;
+use serde_derive::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// PR, open PR and deployment stats grouped by a granularity.
+#[derive(Clone, Serialize, Debug)]
+pub struct Stats {
+    pub granularity: Granularity,
+    /// Every period from the first to the last in which a PR was merged or
+    /// closed. `start_date` is the start of the period.
+    pub periods: Vec<frontend::Week>,
+    /// Open PRs and issues on the last day of each period we have a sample
+    /// from, `date` is that day.
+    pub open: Vec<frontend::Day>,
+    pub deploys: Vec<frontend::DeployWeek>,
+}
+
+/// Stats for PRs matching `filter`, grouped by `granularity`.
+pub fn stats(conn: &Connection, filter: &db::PrFilter, granularity: Granularity) -> Result<Stats> {
+    Ok(Stats {
+        granularity,
+        periods: db::stats(conn, filter, granularity)?,
+        open: db::open_prs_per_bucket(conn, filter, granularity)?,
+        deploys: db::deploy_stats(conn, filter, granularity)?,
+    })
+}
+
+#[derive(Clone, Copy, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Day,
+    /// ISO weeks, starting on Monday.
+    Week,
+    Month,
+    Quarter,
+    Sprint,
+}
+
+impl Granularity {
+    /// An SQL expression for the start date of the period containing `time`,
+    /// which should be an SQL expression for a time.
+    pub fn start(self, time: &str) -> String {
+        match self {
+            Granularity::Day => format!("date({})", time),
+            Granularity::Week => format!("date({}, 'weekday 0', '-6 days')", time),
+            Granularity::Month => format!("date({}, 'start of month')", time),
+            Granularity::Quarter => format!(
+                "date({0}, 'start of month', '-' || ((CAST(strftime('%m', {0}) AS INTEGER) - 1) % 3) || ' months')",
+                time
+            ),
+            Granularity::Sprint => {
+                // Whole days since the first sprint started, rounded down to a
+                // multiple of the sprint length. SQLite's `%` rounds towards
+                // zero, so we fix it up for times before the first sprint.
+                let days = format!(
+                    "CAST(julianday(date({})) - julianday('{}') AS INTEGER)",
+                    time, SPRINT_START
+                );
+                format!(
+                    "date('{start}', ({days} - (({days} % {length}) + {length}) % {length}) || ' days')",
+                    start = SPRINT_START,
+                    days = days,
+                    length = SPRINT_DAYS
+                )
+            }
+        }
+    }
+
+    /// A modifier for SQLite's `date` function which moves from the start of a
+    /// period to the start of the next.
+    pub fn next(self) -> String {
+        match self {
+            Granularity::Day => "+1 day".to_owned(),
+            Granularity::Week => "+7 days".to_owned(),
+            Granularity::Month => "+1 month".to_owned(),
+            Granularity::Quarter => "+3 months".to_owned(),
+            Granularity::Sprint => format!("+{} days", SPRINT_DAYS),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>8} {:>14} {:>14}",
+            "period", "merged", "closed", "time to merge", "business time"
+        )?;
+        for period in &self.periods {
+            writeln!(
+                f,
+                "{:<12} {:>8} {:>8} {:>14} {:>14}",
+                period.start_date,
+                period.merged_prs,
+                period.closed_prs,
+                Minutes(period.time_to_merge.median).to_string(),
+                Minutes(period.business.time_to_merge.median).to_string()
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>8} {:>8}",
+            "as of", "open", "drafts", "issues"
+        )?;
+        for day in &self.open {
+            writeln!(
+                f,
+                "{:<12} {:>8} {:>8} {:>8}",
+                day.date, day.open_prs, day.draft_prs, day.open_issues
+            )?;
+        }
+        if !self.deploys.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{:<12} {:>8} {:>8} {:>14}",
+                "period", "deploys", "failed", "lead time"
+            )?;
+            for period in &self.deploys {
+                writeln!(
+                    f,
+                    "{:<12} {:>8} {:>8} {:>14}",
+                    period.start_date,
+                    period.deployments,
+                    period.failed_deployments,
+                    Minutes(period.lead_time_for_changes.median).to_string()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = GhvError;
+
+    fn from_str(s: &str) -> Result<Granularity> {
+        match s {
+            "day" => Ok(Granularity::Day),
+            "week" => Ok(Granularity::Week),
+            "month" => Ok(Granularity::Month),
+            "quarter" => Ok(Granularity::Quarter),
+            "sprint" => Ok(Granularity::Sprint),
+            _ => Err(GhvError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::{Connection, NO_PARAMS};
+
+    fn start(granularity: Granularity, date: &str) -> String {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.query_row(
+            &format!("SELECT {}", granularity.start(&format!("'{}'", date))),
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_start() {
+        let time = "2019-05-16 09:25:34";
+        assert_eq!(start(Granularity::Day, time), "2019-05-16");
+        assert_eq!(start(Granularity::Week, time), "2019-05-13");
+        assert_eq!(start(Granularity::Week, "2019-05-13"), "2019-05-13");
+        assert_eq!(start(Granularity::Month, time), "2019-05-01");
+        assert_eq!(start(Granularity::Quarter, time), "2019-04-01");
+        assert_eq!(start(Granularity::Quarter, "2019-12-31"), "2019-10-01");
+        assert_eq!(start(Granularity::Quarter, "2019-01-01"), "2019-01-01");
+    }
+
+    #[test]
+    fn test_sprint() {
+        let conn = Connection::open_in_memory().unwrap();
+        // The start of the sprint plus `days`.
+        let date = |days: i64| -> String {
+            conn.query_row(
+                "SELECT date(?1, ?2)",
+                &[SPRINT_START, &format!("{} days", days)],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let length = i64::from(SPRINT_DAYS);
+        assert_eq!(start(Granularity::Sprint, SPRINT_START), SPRINT_START);
+        assert_eq!(start(Granularity::Sprint, &date(length - 1)), SPRINT_START);
+        assert_eq!(start(Granularity::Sprint, &date(length)), date(length));
+        assert_eq!(start(Granularity::Sprint, &date(-1)), date(-length));
+        assert_eq!(start(Granularity::Sprint, &date(-length)), date(-length));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            "quarter".parse::<Granularity>().ok(),
+            Some(Granularity::Quarter)
+        );
+        assert!("fortnight".parse::<Granularity>().is_err());
+    }
+}
@@ -0,0 +1,458 @@
+//! Durations in business time, i.e., only counting working hours on working
+//! days which aren't holidays (see `config::BUSINESS_HOURS`), so a PR opened on
+//! Friday evening and merged on Monday morning doesn't look slow. Holidays are
+//! loaded from iCalendar files.
+
+use crate::config::{BusinessHours, BUSINESS_HOURS};
+use crate::Result;
+
+use chrono::{Datelike, Duration, NaiveDate, Offset, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use std::collections::BTreeSet;
+use std::{fs, io};
+
+/// The Julian day of 1970-01-01T00:00:00Z.
+const UNIX_EPOCH: f64 = 2_440_587.5;
+/// How far yearly recurring holidays without a `COUNT` or `UNTIL` are repeated.
+const LAST_RECURRENCE_YEAR: i32 = 2100;
+
+lazy_static! {
+    static ref CALENDAR: io::Result<Calendar> = Calendar::load();
+}
+
+/// A way of measuring the time between two Julian days (as returned by
+/// SQLite's `julianday`).
+pub trait Clock {
+    /// Minutes from `start` to `end`, zero if `end` is before `start`.
+    fn elapsed(&self, start: f64, end: f64) -> f64;
+
+    /// `elapsed`, rounded to whole minutes.
+    fn minutes(&self, start: f64, end: f64) -> u32 {
+        self.elapsed(start, end).round() as u32
+    }
+}
+
+/// Every minute counts.
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn elapsed(&self, start: f64, end: f64) -> f64 {
+        (end - start).max(0.0) * 1440.0
+    }
+}
+
+/// Only working hours count.
+pub struct Calendar {
+    timezone: Tz,
+    /// Monday first.
+    working_days: [bool; 7],
+    /// Start and end of the working day, in minutes since local midnight.
+    start: u32,
+    end: u32,
+    holidays: BTreeSet<NaiveDate>,
+}
+
+impl Calendar {
+    /// The calendar described by `config::BUSINESS_HOURS`, loaded the first
+    /// time it's needed. An error if any of its holiday files can't be read.
+    pub fn get() -> Result<&'static Calendar> {
+        match &*CALENDAR {
+            Ok(calendar) => Ok(calendar),
+            // `io::Error` isn't `Clone`.
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string()).into()),
+        }
+    }
+
+    fn load() -> io::Result<Calendar> {
+        let mut holidays = BTreeSet::new();
+        for path in BUSINESS_HOURS.holidays {
+            holidays.extend(parse_ical(&fs::read_to_string(path)?));
+        }
+        Ok(Calendar::new(&BUSINESS_HOURS, holidays))
+    }
+
+    fn new(hours: &BusinessHours, holidays: BTreeSet<NaiveDate>) -> Calendar {
+        Calendar {
+            timezone: hours.timezone,
+            working_days: hours.working_days,
+            start: hours.start,
+            end: hours.end,
+            holidays,
+        }
+    }
+
+    fn is_working_day(&self, day: NaiveDate) -> bool {
+        self.working_days[day.weekday().num_days_from_monday() as usize]
+            && !self.holidays.contains(&day)
+    }
+
+    /// The local date of a Julian day.
+    fn local_day(&self, time: f64) -> NaiveDate {
+        let seconds = ((time - UNIX_EPOCH) * 86400.0).round() as i64;
+        // Julian days from SQLite are always in range.
+        Utc.timestamp_opt(seconds, 0)
+            .unwrap()
+            .with_timezone(&self.timezone)
+            .naive_local()
+            .date()
+    }
+
+    /// The Julian day of `minutes` after midnight, local time, on `day`.
+    fn instant(&self, day: NaiveDate, minutes: u32) -> f64 {
+        let local = day.and_hms_opt(0, 0, 0).unwrap() + Duration::minutes(i64::from(minutes));
+        let seconds = match self.timezone.from_local_datetime(&local).earliest() {
+            Some(time) => time.timestamp(),
+            // Skipped when the clocks went forward, so use the offset from
+            // before they did.
+            None => {
+                let offset = self
+                    .timezone
+                    .offset_from_utc_datetime(&(local - Duration::days(1)))
+                    .fix()
+                    .local_minus_utc();
+                Utc.from_utc_datetime(&(local - Duration::seconds(i64::from(offset))))
+                    .timestamp()
+            }
+        };
+        UNIX_EPOCH + seconds as f64 / 86400.0
+    }
+}
+
+impl Clock for Calendar {
+    fn elapsed(&self, start: f64, end: f64) -> f64 {
+        if end <= start {
+            return 0.0;
+        }
+        let mut total = 0.0;
+        let mut day = self.local_day(start);
+        while day <= self.local_day(end) {
+            if self.is_working_day(day) {
+                let open = self.instant(day, self.start);
+                let close = self.instant(day, self.end);
+                total += (close.min(end) - open.max(start)).max(0.0);
+            }
+            day += Duration::days(1);
+        }
+        total * 1440.0
+    }
+}
+
+/// The days covered by each event in an iCalendar file. Only the date part of
+/// `DTSTART` and `DTEND` is used. Yearly recurring events are repeated (see
+/// `Recurrence`), other recurring events only count once.
+fn parse_ical(text: &str) -> Vec<NaiveDate> {
+    // Long lines are folded by starting continuation lines with whitespace.
+    let text = text
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut days = vec![];
+    let (mut start, mut end, mut recurrence) = (None, None, None);
+    for line in text.lines() {
+        let line = line.trim_end();
+        let (name, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => continue,
+        };
+        // Ignore parameters, e.g., `DTSTART;VALUE=DATE`.
+        match name.split(';').next().unwrap() {
+            "BEGIN" if value == "VEVENT" => {
+                start = None;
+                end = None;
+                recurrence = None;
+            }
+            "DTSTART" => start = parse_date(value),
+            "DTEND" => end = parse_date(value),
+            "RRULE" => recurrence = Recurrence::parse(value),
+            "END" if value == "VEVENT" => {
+                if let Some(start) = start {
+                    // The end is exclusive.
+                    let length = end.map_or(1, |end: NaiveDate| (end - start).num_days().max(1));
+                    let starts = match &recurrence {
+                        Some(recurrence) => recurrence.occurrences(start),
+                        None => vec![start],
+                    };
+                    for start in starts {
+                        days.extend((0..length).map(|i| start + Duration::days(i)));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    days
+}
+
+/// An `RRULE` with `FREQ=YEARLY`, either on the same date every year or, with
+/// `BYDAY`, on the nth (or, if negative, nth from last) weekday of the month.
+#[derive(Debug, PartialEq)]
+struct Recurrence {
+    interval: i32,
+    count: Option<usize>,
+    /// Inclusive.
+    until: Option<NaiveDate>,
+    month: Option<u32>,
+    weekday: Option<(i32, Weekday)>,
+}
+
+impl Recurrence {
+    /// `None` for other frequencies and rules we don't understand.
+    fn parse(value: &str) -> Option<Recurrence> {
+        let mut yearly = false;
+        let mut recurrence = Recurrence {
+            interval: 1,
+            count: None,
+            until: None,
+            month: None,
+            weekday: None,
+        };
+        for part in value.split(';') {
+            let (name, value) = match part.find('=') {
+                Some(i) => (&part[..i], &part[i + 1..]),
+                None => return None,
+            };
+            match name {
+                "FREQ" => yearly = value == "YEARLY",
+                "INTERVAL" => recurrence.interval = value.parse().ok().filter(|&i| i > 0)?,
+                "COUNT" => recurrence.count = Some(value.parse().ok()?),
+                "UNTIL" => recurrence.until = Some(parse_date(value)?),
+                "BYMONTH" => {
+                    recurrence.month = Some(value.parse().ok().filter(|m| (1..=12).contains(m))?)
+                }
+                "BYDAY" => recurrence.weekday = Some(parse_weekday(value)?),
+                "WKST" => {}
+                _ => return None,
+            }
+        }
+        if yearly {
+            Some(recurrence)
+        } else {
+            None
+        }
+    }
+
+    /// The days on which an event first starting on `first` starts.
+    fn occurrences(&self, first: NaiveDate) -> Vec<NaiveDate> {
+        let mut result = vec![];
+        let mut year = first.year();
+        while year <= LAST_RECURRENCE_YEAR && self.count.map_or(true, |c| result.len() < c) {
+            if let Some(start) = self.occurrence(year, first) {
+                if self.until.map_or(false, |until| start > until) {
+                    break;
+                }
+                if start >= first {
+                    result.push(start);
+                }
+            }
+            year += self.interval;
+        }
+        result
+    }
+
+    /// The occurrence in `year`, if there is one, e.g., not for the 29th of
+    /// February in a year which isn't a leap year.
+    fn occurrence(&self, year: i32, first: NaiveDate) -> Option<NaiveDate> {
+        let month = self.month.unwrap_or_else(|| first.month());
+        match self.weekday {
+            None => NaiveDate::from_ymd_opt(year, month, first.day()),
+            Some((n, weekday)) if n > 0 => {
+                NaiveDate::from_weekday_of_month_opt(year, month, weekday, n as u8)
+            }
+            Some((n, weekday)) => {
+                let next_month = match month {
+                    12 => NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+                    _ => NaiveDate::from_ymd_opt(year, month + 1, 1)?,
+                };
+                let last = next_month - Duration::days(1);
+                let back = (7 + last.weekday().num_days_from_monday()
+                    - weekday.num_days_from_monday())
+                    % 7;
+                let day = last - Duration::days(i64::from(back) + 7 * i64::from(-n - 1));
+                Some(day).filter(|day| day.month() == month)
+            }
+        }
+    }
+}
+
+/// A `BYDAY` value with a single, numbered weekday, e.g., `4TH` or `-1MO`.
+fn parse_weekday(value: &str) -> Option<(i32, Weekday)> {
+    if value.len() < 3 || !value.is_char_boundary(value.len() - 2) {
+        return None;
+    }
+    let (n, day) = value.split_at(value.len() - 2);
+    let n: i32 = n.trim_start_matches('+').parse().ok()?;
+    let weekday = match day {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    };
+    if n == 0 || n.abs() > 5 {
+        return None;
+    }
+    Some((n, weekday))
+}
+
+/// A `YYYYMMDD` date, ignoring any time after it.
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.get(..8)?, "%Y%m%d").ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    /// Julian day of a UTC time in 2019.
+    fn utc(month: u32, day: u32, hour: u32) -> f64 {
+        let time = date(2019, month, day).and_hms_opt(hour, 0, 0).unwrap();
+        UNIX_EPOCH + Utc.from_utc_datetime(&time).timestamp() as f64 / 86400.0
+    }
+
+    /// Julian day of a UTC time in May 2019. The 13th was a Monday.
+    fn may(day: u32, hour: u32) -> f64 {
+        utc(5, day, hour)
+    }
+
+    fn nine_to_five() -> BusinessHours {
+        BusinessHours {
+            timezone: chrono_tz::UTC,
+            working_days: [true, true, true, true, true, false, false],
+            start: 9 * 60,
+            end: 17 * 60,
+            holidays: &[],
+        }
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        assert_eq!(WallClock.minutes(may(13, 9), may(13, 11)), 120);
+        assert_eq!(WallClock.minutes(may(13, 11), may(13, 9)), 0);
+    }
+
+    #[test]
+    fn test_calendar() {
+        let calendar = Calendar::new(&nine_to_five(), BTreeSet::new());
+        // Within a day, and starting before and ending after working hours.
+        assert_eq!(calendar.minutes(may(13, 10), may(13, 12)), 120);
+        assert_eq!(calendar.minutes(may(13, 6), may(13, 20)), 480);
+        // Friday evening to Monday morning.
+        assert_eq!(calendar.minutes(may(17, 18), may(20, 10)), 60);
+        // Friday afternoon to Tuesday afternoon.
+        assert_eq!(calendar.minutes(may(17, 16), may(21, 15)), 60 + 480 + 360);
+        assert_eq!(calendar.minutes(may(20, 10), may(17, 18)), 0);
+
+        // The 20th is a holiday.
+        let holidays = [date(2019, 5, 20)].iter().cloned().collect();
+        let calendar = Calendar::new(&nine_to_five(), holidays);
+        assert_eq!(calendar.minutes(may(17, 16), may(21, 15)), 60 + 360);
+
+        // 9 to 5 in Berlin is 7 to 3 in UTC in summer, but 8 to 4 in winter.
+        let hours = BusinessHours {
+            timezone: chrono_tz::Europe::Berlin,
+            ..nine_to_five()
+        };
+        let calendar = Calendar::new(&hours, BTreeSet::new());
+        assert_eq!(calendar.minutes(may(13, 6), may(13, 20)), 480);
+        assert_eq!(calendar.minutes(may(13, 14), may(13, 16)), 60);
+        // The 14th of January 2019 was a Monday.
+        let january = |hour| utc(1, 14, hour);
+        assert_eq!(calendar.minutes(january(6), january(20)), 480);
+        assert_eq!(calendar.minutes(january(14), january(16)), 120);
+        // The clocks went forward on Sunday the 31st of March 2019, and back
+        // on Sunday the 27th of October.
+        let hours = BusinessHours {
+            timezone: chrono_tz::Europe::Berlin,
+            working_days: [true; 7],
+            start: 0,
+            end: 24 * 60,
+            holidays: &[],
+        };
+        let calendar = Calendar::new(&hours, BTreeSet::new());
+        let day = |month, day| utc(month, day, 0);
+        assert_eq!(calendar.minutes(day(3, 30), day(4, 2)), 3 * 1440);
+        assert_eq!(calendar.minutes(day(10, 26), day(10, 29)), 3 * 1440);
+    }
+
+    #[test]
+    fn test_parse_ical() {
+        let days = parse_ical(
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20191225\r\n\
+             DTEND;VALUE=DATE:20191227\r\n\
+             SUMMARY:Christmas Day and\r\n \
+             Boxing Day\r\n\
+             END:VEVENT\r\n\
+             BEGIN:VEVENT\r\n\
+             DTSTART:20200101T000000Z\r\n\
+             SUMMARY:New Year's Day\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+        );
+        assert_eq!(
+            days,
+            vec![date(2019, 12, 25), date(2019, 12, 26), date(2020, 1, 1),]
+        );
+    }
+
+    #[test]
+    fn test_recurrence() {
+        let days = parse_ical(
+            "BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20181225\r\n\
+             RRULE:FREQ=YEARLY;UNTIL=20201231\r\n\
+             END:VEVENT\r\n\
+             BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20191128\r\n\
+             DTEND;VALUE=DATE:20191130\r\n\
+             RRULE:FREQ=YEARLY;BYMONTH=11;BYDAY=4TH;COUNT=2\r\n\
+             END:VEVENT\r\n\
+             BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20190527\r\n\
+             RRULE:FREQ=YEARLY;INTERVAL=2;BYMONTH=5;BYDAY=-1MO;COUNT=2\r\n\
+             END:VEVENT\r\n\
+             BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20190506\r\n\
+             RRULE:FREQ=MONTHLY;BYDAY=1MO\r\n\
+             END:VEVENT\r\n",
+        );
+        assert_eq!(
+            days,
+            vec![
+                date(2018, 12, 25),
+                date(2019, 12, 25),
+                date(2020, 12, 25),
+                // Thanksgiving and the day after.
+                date(2019, 11, 28),
+                date(2019, 11, 29),
+                date(2020, 11, 26),
+                date(2020, 11, 27),
+                // The last Monday in May, every other year.
+                date(2019, 5, 27),
+                date(2021, 5, 31),
+                // Only yearly recurrences are repeated.
+                date(2019, 5, 6),
+            ]
+        );
+
+        // Unbounded, and skipping years without a 29th of February.
+        let leap = Recurrence::parse("FREQ=YEARLY").unwrap();
+        let days = leap.occurrences(date(2016, 2, 29));
+        assert_eq!(days[..2], [date(2016, 2, 29), date(2020, 2, 29)]);
+        assert_eq!(days.last(), Some(&date(2096, 2, 29)));
+        assert_eq!(Recurrence::parse("FREQ=YEARLY;BYDAY=MO,TU"), None);
+        assert_eq!(Recurrence::parse("FREQ=YEARLY;BYMONTHDAY=1"), None);
+    }
+}
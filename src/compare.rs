@@ -0,0 +1,377 @@
+//! Comparing two periods on each metric, e.g., this sprint with the last, or
+//! this quarter with the same quarter last year. For each metric we report the
+//! absolute and relative change, and whether the change is statistically
+//! significant, i.e., unlikely to be chance. Counts are compared as rates per
+//! week, so periods of different lengths can be compared, and durations and
+//! sizes by their medians over PRs merged in each period.
+
+use crate::data::{Date, Status};
+use crate::frontend::Distribution;
+use crate::{db, GhvError, Result};
+
+use rusqlite::{params, Connection};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A change is significant if its z-score is at least this far from zero, i.e.,
+/// at the 5% level.
+const Z_CRITICAL: f64 = 1.96;
+/// The fewest values in each period for comparing medians, fewer than this and
+/// the normal approximation of the Mann-Whitney U test is unreliable.
+const MIN_VALUES: usize = 8;
+
+#[derive(Clone, Serialize, Debug)]
+pub struct Comparison {
+    pub current: Period,
+    pub previous: Period,
+    pub metrics: Vec<Delta>,
+}
+
+/// From `start` (inclusive) to `end` (exclusive).
+#[derive(Clone, Serialize, Debug)]
+pub struct Period {
+    pub start: String,
+    pub end: String,
+    pub weeks: f64,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct Delta {
+    pub metric: &'static str,
+    pub current: f64,
+    pub previous: f64,
+    /// `current - previous`.
+    pub absolute: f64,
+    /// `absolute / previous`, `None` if `previous` is zero.
+    pub relative: Option<f64>,
+    /// `None` if there are too few PRs to tell.
+    pub z_score: Option<f64>,
+    pub significant: bool,
+}
+
+/// Compare `current` with `previous`, or with the period of the same length
+/// immediately before `current` if `previous` is `None`. Periods are usually
+/// whole days, e.g., `2019-05-13..2019-05-27`, but may be any times SQLite
+/// understands.
+pub fn compare(
+    conn: &Connection,
+    current: Range<Date>,
+    previous: Option<Range<Date>>,
+) -> Result<Comparison> {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => {
+            let start: Option<String> = conn.query_row(
+                "SELECT date(?1, '-' || (julianday(?2) - julianday(?1)) || ' days')",
+                params![current.start, current.end],
+                |row| row.get(0),
+            )?;
+            Date::new(start.ok_or(GhvError::Other)?)..Date::new(current.start.date.clone())
+        }
+    };
+
+    let times: HashMap<String, db::PrTimes> = db::pr_times(conn)?
+        .into_iter()
+        .map(|pr| (pr.url.clone(), pr))
+        .collect();
+    let (current, current_prs) = period(conn, current, &times)?;
+    let (previous, previous_prs) = period(conn, previous, &times)?;
+    let metrics = deltas(&current_prs, current.weeks, &previous_prs, previous.weeks);
+    Ok(Comparison {
+        current,
+        previous,
+        metrics,
+    })
+}
+
+/// The values of each metric for PRs in a period.
+#[derive(Clone, Debug, Default)]
+struct PeriodPrs {
+    opened: u32,
+    merged: u32,
+    closed: u32,
+    // For PRs merged in the period, durations in minutes.
+    time_to_merge: Vec<u32>,
+    business_time_to_merge: Vec<u32>,
+    waiting_for_review: Vec<u32>,
+    business_waiting_for_review: Vec<u32>,
+    review_comments: Vec<u32>,
+    // Lines added plus lines deleted.
+    size: Vec<u32>,
+    commits: Vec<u32>,
+}
+
+fn period(
+    conn: &Connection,
+    range: Range<Date>,
+    times: &HashMap<String, db::PrTimes>,
+) -> Result<(Period, PeriodPrs)> {
+    let (start, end, days): (Option<String>, Option<String>, Option<f64>) = conn.query_row(
+        "SELECT datetime(?1), datetime(?2), julianday(?2) - julianday(?1)",
+        params![range.start, range.end],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let (start, end, days) = match (start, end, days) {
+        (Some(start), Some(end), Some(days)) if days > 0.0 => (start, end, days),
+        _ => return Err(GhvError::Other),
+    };
+    let period = Period {
+        start: range.start.date.clone(),
+        end: range.end.date.clone(),
+        weeks: days / 7.0,
+    };
+    let contains = |time: &Date| {
+        // Normalise ISO 8601 times, as from GitLab, to SQLite's format.
+        let time = time.date.replacen('T', " ", 1);
+        *time >= *start && *time < *end
+    };
+
+    let mut prs = PeriodPrs::default();
+    for pr in db::read_prs(conn, range)? {
+        if contains(&pr.created) {
+            prs.opened += 1;
+        }
+        let latest = match pr.samples.last() {
+            Some(latest) => latest,
+            None => continue,
+        };
+        match &latest.status {
+            Status::Closed(closed) if contains(closed) => prs.closed += 1,
+            Status::Merged(merged) if contains(merged) => {
+                prs.merged += 1;
+                if let Some(times) = times.get(&pr.url) {
+                    prs.time_to_merge.push(times.time_to_merge);
+                    prs.business_time_to_merge
+                        .push(times.business_time_to_merge);
+                    prs.waiting_for_review.push(times.waiting_for_review);
+                    prs.business_waiting_for_review
+                        .push(times.business_waiting_for_review);
+                }
+                prs.review_comments.push(latest.review_comments);
+                prs.size.push(latest.additions + latest.deletions);
+                prs.commits.push(latest.commits);
+            }
+            _ => {}
+        }
+    }
+
+    Ok((period, prs))
+}
+
+fn deltas(
+    current: &PeriodPrs,
+    current_weeks: f64,
+    previous: &PeriodPrs,
+    previous_weeks: f64,
+) -> Vec<Delta> {
+    let rate = |metric, current: u32, previous: u32| {
+        delta(
+            metric,
+            f64::from(current) / current_weeks,
+            f64::from(previous) / previous_weeks,
+            rate_z_score(current, current_weeks, previous, previous_weeks),
+        )
+    };
+    let median = |metric, current: &[u32], previous: &[u32]| {
+        delta(
+            metric,
+            f64::from(Distribution::new(current).median),
+            f64::from(Distribution::new(previous).median),
+            mann_whitney_z_score(current, previous),
+        )
+    };
+    vec![
+        rate("opened_prs_per_week", current.opened, previous.opened),
+        rate("merged_prs_per_week", current.merged, previous.merged),
+        rate("closed_prs_per_week", current.closed, previous.closed),
+        median(
+            "time_to_merge",
+            &current.time_to_merge,
+            &previous.time_to_merge,
+        ),
+        median(
+            "business_time_to_merge",
+            &current.business_time_to_merge,
+            &previous.business_time_to_merge,
+        ),
+        median(
+            "waiting_for_review",
+            &current.waiting_for_review,
+            &previous.waiting_for_review,
+        ),
+        median(
+            "business_waiting_for_review",
+            &current.business_waiting_for_review,
+            &previous.business_waiting_for_review,
+        ),
+        median(
+            "review_comments",
+            &current.review_comments,
+            &previous.review_comments,
+        ),
+        median("size", &current.size, &previous.size),
+        median("commits", &current.commits, &previous.commits),
+    ]
+}
+
+fn delta(metric: &'static str, current: f64, previous: f64, z_score: Option<f64>) -> Delta {
+    let absolute = current - previous;
+    Delta {
+        metric,
+        current,
+        previous,
+        absolute,
+        relative: if previous == 0.0 {
+            None
+        } else {
+            Some(absolute / previous)
+        },
+        z_score,
+        significant: z_score.map_or(false, |z| z.abs() >= Z_CRITICAL),
+    }
+}
+
+/// Whether two counts of events over different lengths of time differ. If the
+/// rates are the same then, of all the events, the number in the current period
+/// is binomial with `p` proportional to its length; we use the normal
+/// approximation, so there must be enough events.
+fn rate_z_score(
+    current: u32,
+    current_weeks: f64,
+    previous: u32,
+    previous_weeks: f64,
+) -> Option<f64> {
+    let n = f64::from(current + previous);
+    let p = current_weeks / (current_weeks + previous_weeks);
+    let variance = n * p * (1.0 - p);
+    if variance < 5.0 {
+        return None;
+    }
+    Some((f64::from(current) - n * p) / variance.sqrt())
+}
+
+/// The Mann-Whitney U test of whether values from one period tend to be larger
+/// than from the other, using the normal approximation with a correction for
+/// ties.
+fn mann_whitney_z_score(current: &[u32], previous: &[u32]) -> Option<f64> {
+    if current.len() < MIN_VALUES || previous.len() < MIN_VALUES {
+        return None;
+    }
+    let mut values: Vec<(u32, bool)> = current
+        .iter()
+        .map(|&v| (v, true))
+        .chain(previous.iter().map(|&v| (v, false)))
+        .collect();
+    values.sort();
+
+    // Sum the ranks of the current period's values, giving tied values the mean
+    // of their ranks.
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < values.len() {
+        let j = i + values[i..]
+            .iter()
+            .take_while(|v| v.0 == values[i].0)
+            .count();
+        let rank = (i + 1 + j) as f64 / 2.0;
+        rank_sum += rank * values[i..j].iter().filter(|v| v.1).count() as f64;
+        let t = (j - i) as f64;
+        ties += t * t * t - t;
+        i = j;
+    }
+
+    let (n1, n2) = (current.len() as f64, previous.len() as f64);
+    let n = n1 + n2;
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return None;
+    }
+    Some((u - n1 * n2 / 2.0) / variance.sqrt())
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} to {} compared with {} to {}, significant changes are marked with *",
+            self.current.start, self.current.end, self.previous.start, self.previous.end
+        )?;
+        writeln!(
+            f,
+            "  {:<28} {:>10} {:>10} {:>10} {:>8}",
+            "metric", "previous", "current", "change", "%"
+        )?;
+        for delta in &self.metrics {
+            writeln!(
+                f,
+                "{} {:<28} {:>10.1} {:>10.1} {:>+10.1} {:>8}",
+                if delta.significant { '*' } else { ' ' },
+                delta.metric,
+                delta.previous,
+                delta.current,
+                delta.absolute,
+                delta
+                    .relative
+                    .map_or(String::new(), |r| format!("{:+.0}%", r * 100.0))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_z_score() {
+        // Too few PRs.
+        assert_eq!(rate_z_score(3, 1.0, 1, 1.0), None);
+        assert_eq!(rate_z_score(20, 2.0, 10, 1.0), Some(0.0));
+        assert!(rate_z_score(40, 1.0, 20, 1.0).unwrap() > Z_CRITICAL);
+        assert!(rate_z_score(24, 1.0, 20, 1.0).unwrap() < Z_CRITICAL);
+    }
+
+    #[test]
+    fn test_mann_whitney_z_score() {
+        let slow: Vec<u32> = (100..110).collect();
+        let fast: Vec<u32> = (10..20).collect();
+        assert_eq!(mann_whitney_z_score(&slow[..5], &fast), None);
+        assert!(mann_whitney_z_score(&slow, &fast).unwrap() > Z_CRITICAL);
+        assert!(mann_whitney_z_score(&fast, &slow).unwrap() < -Z_CRITICAL);
+        assert_eq!(mann_whitney_z_score(&fast, &fast), Some(0.0));
+        // Every value is tied.
+        assert_eq!(mann_whitney_z_score(&[5; 10], &[5; 10]), None);
+    }
+
+    #[test]
+    fn test_deltas() {
+        let current = PeriodPrs {
+            opened: 10,
+            merged: 8,
+            time_to_merge: vec![60, 120, 180],
+            ..PeriodPrs::default()
+        };
+        let previous = PeriodPrs {
+            opened: 10,
+            merged: 0,
+            time_to_merge: vec![],
+            ..PeriodPrs::default()
+        };
+        let deltas = deltas(&current, 2.0, &previous, 1.0);
+        assert_eq!(deltas[0].metric, "opened_prs_per_week");
+        assert_eq!(
+            (deltas[0].current, deltas[0].previous, deltas[0].absolute),
+            (5.0, 10.0, -5.0)
+        );
+        assert_eq!(deltas[0].relative, Some(-0.5));
+        assert_eq!(deltas[1].relative, None);
+        assert_eq!(deltas[3].metric, "time_to_merge");
+        assert_eq!((deltas[3].current, deltas[3].z_score), (120.0, None));
+        assert!(!deltas[3].significant);
+    }
+}
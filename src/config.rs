@@ -1,9 +1,229 @@
+use crate::db::Bots;
+
 /// Time between updates in seconds
 pub(crate) const UPDATE_TIMEOUT: u64 = 60 * 60;
 
 pub(crate) const USER_AGENT: &str = "gh-velocity";
+/// Personal access token, only used for hosts without an entry in
+/// `GITHUB_APPS`.
 pub(crate) const ACCESS_TOKEN: &str = "TODO personal-access-token";
-// TODO we should work across multiple orgs/repos
-pub(crate) const OWNER: &str = "nrc";
-pub(crate) const REPO: &str = "gh-velocity";
+/// Authenticate as a GitHub App installed on each tracked repo's org, one app
+/// per host (e.g., github.com and a GitHub Enterprise Server).
+pub(crate) const GITHUB_APPS: &[GitHubApp] = &[];
+pub(crate) const REPOS: &[Repo] = &[Repo {
+    forge: Forge::GitHub,
+    host: GITHUB_API,
+    ca_cert: None,
+    clone_url: None,
+    owner: "nrc",
+    name: "gh-velocity",
+    deploys: Deploys::None,
+    areas: Areas::None,
+}];
+/// How to recognise failures in production, for change failure rate and time
+/// to restore (see `dora`).
+pub(crate) const FAILURE_RULES: &[FailureRule] = &[
+    FailureRule::Revert,
+    FailureRule::PrLabel("hotfix"),
+    FailureRule::IssueLabel("incident"),
+];
+/// Usernames of bots which the forge doesn't identify as bots, e.g., a
+/// self-hosted Renovate. Names ending in `[bot]` are always bots.
+pub(crate) const BOTS: &[&str] = &["renovate-bot", "dependabot-preview"];
+/// Whether stats include PRs by bots unless asked otherwise, for every
+/// aggregate (including those per label and area, and DORA metrics). Stats for
+/// people and for bots are also reported separately.
+pub(crate) const BOT_PRS: Bots = Bots::Include;
+/// The most lines a PR can change (additions plus deletions) and still be
+/// `XS`, `S`, `M`, and `L`, bigger PRs are `XL` (see `size::Size`).
+pub(crate) const SIZE_THRESHOLDS: [u32; 4] = [10, 50, 250, 1000];
+/// How many of the most recent complete weeks of throughput to resample when
+/// forecasting (see `forecast`), and how many simulations to run.
+pub(crate) const FORECAST_HISTORY_WEEKS: usize = 12;
+pub(crate) const FORECAST_TRIALS: usize = 10_000;
+/// How many complete weeks to compare each week's stats with when looking for
+/// anomalies (see `anomaly`), and where to send alerts about them.
+pub(crate) const ANOMALY_BASELINE_WEEKS: usize = 12;
+pub(crate) const ALERT_SINKS: &[AlertSink] = &[AlertSink::Log];
+/// Working hours, for durations in business time (see `business`).
+pub(crate) const BUSINESS_HOURS: BusinessHours = BusinessHours {
+    timezone: chrono_tz::UTC,
+    working_days: [true, true, true, true, true, false, false],
+    start: 9 * 60,
+    end: 17 * 60,
+    holidays: &[],
+};
+/// For cohorts of PRs by the week they were opened, the fraction merged within
+/// each of these numbers of days of being opened (see `db::cohorts`).
+pub(crate) const COHORT_DAYS: &[u32] = &[1, 3, 7, 14];
+/// When the first sprint started and how long sprints are, for grouping stats
+/// by sprint (see `bucket`).
+pub(crate) const SPRINT_START: &str = "2019-01-07";
+pub(crate) const SPRINT_DAYS: u32 = 14;
+/// Service-level objectives for merged PRs, see `slo`.
+pub(crate) const SLOS: &[Slo] = &[Slo {
+    name: "First review within a business day",
+    metric: SloMetric::TimeToFirstReview,
+    threshold: 8 * 60,
+    business_time: true,
+    target: 0.9,
+    window_weeks: 4,
+}];
 pub(crate) const DB_PATH: &str = "ghv-staging.db";
+/// Where to keep bare mirrors of repos which have a `clone_url`.
+pub(crate) const MIRROR_DIR: &str = "mirrors";
+
+/// Address to listen on for webhook deliveries and other HTTP requests.
+pub(crate) const SERVER_ADDR: &str = "0.0.0.0:8080";
+/// Shared secret configured on the GitHub webhook, used to verify deliveries.
+pub(crate) const WEBHOOK_SECRET: &str = "TODO webhook-secret";
+
+pub(crate) struct GitHubApp {
+    /// API root the app is registered on, as in `Repo::host`.
+    pub host: &'static str,
+    pub id: u64,
+    /// Path to the app's PEM-encoded private key.
+    pub private_key_path: &'static str,
+}
+
+pub(crate) const GITHUB_API: &str = "https://api.github.com";
+
+/// A repository to track.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Repo {
+    pub forge: Forge,
+    /// API root, e.g., `GITHUB_API`, `https://github.example.com/api/v3` for
+    /// GitHub Enterprise Server, `https://gitlab.com/api/v4`, or
+    /// `https://gitea.example.com/api/v1`.
+    pub host: &'static str,
+    /// Path to a PEM-encoded CA certificate to trust when connecting to `host`.
+    pub ca_cert: Option<&'static str>,
+    /// If present, we keep a mirror of the repo and use it to analyse PRs'
+    /// commits (see `git::analyse_all`).
+    pub clone_url: Option<&'static str>,
+    pub owner: &'static str,
+    pub name: &'static str,
+    /// What counts as a deployment, for DORA metrics (see `dora`).
+    pub deploys: Deploys,
+    /// How to group PRs by the parts of the repo they change (see `area`).
+    pub areas: Areas,
+}
+
+impl Repo {
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+
+    /// The repo's API URL.
+    pub fn api_url(&self) -> String {
+        format!("{}/repos/{}/{}", self.host, self.owner, self.name)
+    }
+
+    /// The repo's web URL on GitHub or GitHub Enterprise Server, as found in
+    /// webhook payloads.
+    pub fn html_url(&self) -> String {
+        let web = match self.host {
+            GITHUB_API => "https://github.com",
+            host => host.trim_end_matches("/api/v3"),
+        };
+        format!("{}/{}", web, self.full_name())
+    }
+}
+
+/// The kind of service hosting a repo.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Forge {
+    /// GitHub or GitHub Enterprise Server, authenticated using the host's app
+    /// in `GITHUB_APPS` or `ACCESS_TOKEN`.
+    GitHub,
+    /// GitLab, authenticated with a personal or project access token. `owner`
+    /// is the project's namespace (which may include subgroups).
+    GitLab { token: &'static str },
+    /// Gitea or Forgejo, authenticated with an access token.
+    Gitea { token: &'static str },
+}
+
+/// What counts as a deployment of a repo.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Deploys {
+    /// Don't track deployments.
+    None,
+    /// Published releases, GitHub only.
+    Releases,
+    /// Successful deployments to `environment`, GitHub only.
+    Deployments { environment: &'static str },
+    /// Tags matching `pattern` (a `git for-each-ref` pattern such as `v*`),
+    /// requires a `clone_url`.
+    Tags { pattern: &'static str },
+}
+
+/// How to map the paths a PR changes to areas of a repo.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Areas {
+    /// Don't track areas.
+    None,
+    /// A path's area is its owners (e.g., `@org/compiler-team`) in the repo's
+    /// CODEOWNERS file, requires a `clone_url`.
+    CodeOwners,
+    /// `(pattern, area)` pairs, using CODEOWNERS pattern syntax. As in
+    /// CODEOWNERS, the last pattern which matches a path wins.
+    Rules(&'static [(&'static str, &'static str)]),
+}
+
+pub(crate) struct BusinessHours {
+    /// The timezone, e.g., `chrono_tz::Europe::London`.
+    pub timezone: chrono_tz::Tz,
+    /// Monday first.
+    pub working_days: [bool; 7],
+    /// Start and end of the working day, in minutes since midnight.
+    pub start: u32,
+    pub end: u32,
+    /// Paths to iCalendar files, every day with an event is a holiday. Stats
+    /// in business time fail if any of them can't be read.
+    pub holidays: &'static [&'static str],
+}
+
+/// A service-level objective: at least `target` (e.g., 0.9 for 90%) of PRs
+/// merged in the last `window_weeks` weeks should have `metric` within
+/// `threshold` minutes (of business time, see `BUSINESS_HOURS`, if
+/// `business_time`).
+#[derive(Debug, PartialEq)]
+pub(crate) struct Slo {
+    pub name: &'static str,
+    pub metric: SloMetric,
+    pub threshold: u32,
+    pub business_time: bool,
+    pub target: f64,
+    pub window_weeks: u32,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum SloMetric {
+    /// From being ready for review until the first review (or merge, if it
+    /// wasn't reviewed), see `cycle::Phases::waiting_for_review`.
+    TimeToFirstReview,
+    /// Cycle time, from being opened until merge, excluding time as a draft.
+    TimeToMerge,
+}
+
+/// Somewhere to send alerts about anomalies.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum AlertSink {
+    /// Print to stderr.
+    Log,
+    /// POST each alert as JSON to `url`.
+    Webhook { url: &'static str },
+}
+
+/// A way to recognise a failure. A failure is restored when the PR which fixes
+/// it is merged, or the issue which reports it is closed.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum FailureRule {
+    /// A PR which reverts another, e.g., made by GitHub's revert button or
+    /// `git revert`.
+    Revert,
+    /// A PR with this label fixes a failure.
+    PrLabel(&'static str),
+    /// An issue with this label reports a failure.
+    IssueLabel(&'static str),
+}
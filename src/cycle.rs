@@ -0,0 +1,314 @@
+//! Splitting a merged PR's life into phases, so we can see where PRs spend
+//! their time, and how much of it is spent on CI. Durations can be measured
+//! with any `business::Clock`.
+
+use crate::business::Clock;
+
+/// What happened to a PR between being opened and merged. Times are Julian
+/// days (as returned by SQLite's `julianday`), so they can be subtracted.
+pub struct Activity {
+    pub created: f64,
+    pub merged: f64,
+    /// `(time, draft)` for each time the PR was converted to a draft (`draft`
+    /// is true) or marked as ready for review, oldest first. A PR which was
+    /// opened as a draft has no event for that, so if the first event marks it
+    /// as ready, we assume it was.
+    pub draft_events: Vec<(f64, bool)>,
+    /// `(time, approved)` for each review by someone other than the author,
+    /// oldest first.
+    pub reviews: Vec<(f64, bool)>,
+    /// Finished CI runs which succeeded or failed, oldest first.
+    pub checks: Vec<Check>,
+}
+
+/// A run of a CI check, or a commit status.
+pub struct Check {
+    pub sha: String,
+    pub name: String,
+    pub started: f64,
+    pub finished: f64,
+    pub failed: bool,
+}
+
+impl Activity {
+    /// `(start, end)` of each period when the PR was a draft.
+    fn draft_spells(&self) -> Vec<(f64, f64)> {
+        let mut since = match self.draft_events.first() {
+            Some(&(_, false)) => Some(self.created),
+            _ => None,
+        };
+        let mut spells = vec![];
+        for &(time, draft) in &self.draft_events {
+            match (since, draft) {
+                (None, true) => since = Some(time),
+                (Some(start), false) => {
+                    spells.push((start, time));
+                    since = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = since {
+            spells.push((start, self.merged));
+        }
+        spells
+    }
+}
+
+/// Durations in minutes, these add up to the PR's time to merge. Phases which
+/// didn't happen are zero, e.g., a PR merged without approval spends no time
+/// between approval and merge. Time as a draft only counts towards `draft`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Phases {
+    pub draft: u32,
+    /// From first ready for review until the first review.
+    pub waiting_for_review: u32,
+    /// From the first review until the last approval.
+    pub in_review: u32,
+    pub approval_to_merge: u32,
+}
+
+impl Phases {
+    pub fn new(activity: &Activity, clock: &dyn Clock) -> Phases {
+        let Activity {
+            created, merged, ..
+        } = *activity;
+
+        let spells = activity.draft_spells();
+        let ready = match spells.first() {
+            Some(&(start, end)) if start == created => end,
+            _ => created,
+        };
+        let first_review = activity
+            .reviews
+            .first()
+            .map(|&(time, _)| time)
+            .unwrap_or(merged);
+        let approved = activity
+            .reviews
+            .iter()
+            .filter(|&&(time, approved)| approved && time <= merged)
+            .last()
+            .map(|&(time, _)| time)
+            .unwrap_or(merged);
+
+        // Each phase starts when the previous one ends, even if, e.g., someone
+        // reviewed a draft.
+        let ready = clamp(ready, created, merged);
+        let first_review = clamp(first_review, ready, merged);
+        let approved = clamp(approved, first_review, merged);
+        let not_draft = |start: f64, end: f64| {
+            let draft: f64 = spells
+                .iter()
+                .map(|&(s, e)| clock.elapsed(s.max(start), e.min(end)))
+                .sum();
+            round(clock.elapsed(start, end) - draft)
+        };
+        Phases {
+            draft: round(spells.iter().map(|&(s, e)| clock.elapsed(s, e)).sum()),
+            waiting_for_review: not_draft(ready, first_review),
+            in_review: not_draft(first_review, approved),
+            approval_to_merge: not_draft(approved, merged),
+        }
+    }
+}
+
+/// How a PR's CI went. Durations are in minutes and only count time between the
+/// PR being opened and merged.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Ci {
+    /// Time spent waiting for CI, i.e., from the first check on each commit
+    /// starting to the last one finishing.
+    pub wait: u32,
+    /// Time when some check's most recent run had failed. A failed check is
+    /// fixed when a later run of it (on any commit) succeeds.
+    pub blocked: u32,
+    pub runs: u32,
+    pub failed: u32,
+    /// Runs of a check on a commit after the first.
+    pub reruns: u32,
+    /// Checks which both failed and succeeded on the same commit.
+    pub flaky: u32,
+}
+
+impl Ci {
+    pub fn new(activity: &Activity, clock: &dyn Clock) -> Ci {
+        let Activity {
+            created, merged, ..
+        } = *activity;
+        let checks = &activity.checks;
+        let clip =
+            |(start, end): (f64, f64)| (clamp(start, created, merged), clamp(end, created, merged));
+
+        let mut commits: Vec<(&str, (f64, f64))> = vec![];
+        let mut attempts: Vec<(&str, &str, bool, bool)> = vec![];
+        for check in checks {
+            match commits.iter_mut().find(|(sha, _)| *sha == check.sha) {
+                Some((_, (start, end))) => {
+                    *start = start.min(check.started);
+                    *end = end.max(check.finished);
+                }
+                None => commits.push((&check.sha, (check.started, check.finished))),
+            }
+            match attempts
+                .iter_mut()
+                .find(|(sha, name, ..)| *sha == check.sha && *name == check.name)
+            {
+                Some((_, _, failed, succeeded)) => {
+                    *failed |= check.failed;
+                    *succeeded |= !check.failed;
+                }
+                None => attempts.push((&check.sha, &check.name, check.failed, !check.failed)),
+            }
+        }
+
+        let mut by_finish: Vec<&Check> = checks.iter().collect();
+        by_finish.sort_by(|a, b| a.finished.partial_cmp(&b.finished).unwrap());
+        let mut red: Vec<(&str, f64)> = vec![];
+        let mut blocked = vec![];
+        for check in by_finish {
+            let index = red.iter().position(|(name, _)| *name == check.name);
+            match (index, check.failed) {
+                (None, true) => red.push((&check.name, check.finished)),
+                (Some(i), false) => blocked.push((red.remove(i).1, check.finished)),
+                _ => {}
+            }
+        }
+        blocked.extend(red.into_iter().map(|(_, since)| (since, merged)));
+
+        Ci {
+            wait: round(union(
+                commits.into_iter().map(|(_, span)| clip(span)).collect(),
+                clock,
+            )),
+            blocked: round(union(blocked.into_iter().map(clip).collect(), clock)),
+            runs: checks.len() as u32,
+            failed: checks.iter().filter(|c| c.failed).count() as u32,
+            reruns: (checks.len() - attempts.len()) as u32,
+            flaky: attempts.iter().filter(|(_, _, f, s)| *f && *s).count() as u32,
+        }
+    }
+}
+
+/// The total length in minutes of some possibly overlapping `(start, end)`
+/// intervals.
+fn union(mut intervals: Vec<(f64, f64)>, clock: &dyn Clock) -> f64 {
+    intervals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut total = 0.0;
+    let mut covered = std::f64::NEG_INFINITY;
+    for (start, end) in intervals {
+        if end > covered {
+            total += clock.elapsed(start.max(covered), end);
+            covered = end;
+        }
+    }
+    total
+}
+
+fn clamp(time: f64, min: f64, max: f64) -> f64 {
+    time.max(min).min(max)
+}
+
+fn round(minutes: f64) -> u32 {
+    minutes.round().max(0.0) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::business::WallClock;
+
+    /// Julian day of an hour on some day.
+    fn hour(h: u32) -> f64 {
+        2_458_618.5 + f64::from(h) / 24.0
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut activity = Activity {
+            created: hour(0),
+            merged: hour(10),
+            draft_events: vec![],
+            reviews: vec![],
+            checks: vec![],
+        };
+        assert_eq!(
+            Phases::new(&activity, &WallClock),
+            Phases {
+                draft: 0,
+                waiting_for_review: 600,
+                in_review: 0,
+                approval_to_merge: 0,
+            }
+        );
+
+        // Opened as a draft, converted back and forth, reviewed, approved,
+        // re-reviewed and approved again.
+        activity.draft_events = vec![(hour(1), false), (hour(2), true), (hour(3), false)];
+        activity.reviews = vec![
+            (hour(4), false),
+            (hour(5), true),
+            (hour(6), false),
+            (hour(7), true),
+        ];
+        assert_eq!(
+            Phases::new(&activity, &WallClock),
+            Phases {
+                draft: 120,
+                waiting_for_review: 120,
+                in_review: 180,
+                approval_to_merge: 180,
+            }
+        );
+
+        // Converted to draft after being opened, reviewed while a draft.
+        activity.draft_events = vec![(hour(1), true), (hour(3), false)];
+        activity.reviews = vec![(hour(2), true)];
+        assert_eq!(
+            Phases::new(&activity, &WallClock),
+            Phases {
+                draft: 120,
+                waiting_for_review: 60,
+                in_review: 0,
+                approval_to_merge: 420,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ci() {
+        let check = |sha: &str, name: &str, started, finished, failed| Check {
+            sha: sha.to_owned(),
+            name: name.to_owned(),
+            started: hour(started),
+            finished: hour(finished),
+            failed,
+        };
+        let activity = Activity {
+            created: hour(0),
+            merged: hour(10),
+            draft_events: vec![],
+            reviews: vec![],
+            // `test` fails on the first commit, passes when re-run, then fails
+            // on the second commit and is fixed by the third.
+            checks: vec![
+                check("a", "test", 0, 1, true),
+                check("a", "lint", 0, 2, false),
+                check("a", "test", 2, 3, false),
+                check("b", "test", 4, 5, true),
+                check("c", "test", 6, 8, false),
+            ],
+        };
+        assert_eq!(
+            Ci::new(&activity, &WallClock),
+            Ci {
+                wait: 360,
+                blocked: 300,
+                runs: 5,
+                failed: 2,
+                reruns: 1,
+                flaky: 1,
+            }
+        );
+    }
+}
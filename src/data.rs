@@ -1,6 +1,8 @@
 //! Data received from GitHub to be inserted into the database.
 
 pub struct Sample {
+    /// The API host of `pr`.
+    pub host: String,
     pub time: Date,
     pub pr: PullRequest,
     pub status: Status,
@@ -10,22 +12,209 @@ pub struct Sample {
     pub changed_files: u32,
     pub review_comments: u32,
     pub first_commit: Sha,
+    pub head: Sha,
+    /// Only meaningful once the PR is merged.
+    pub merge_commit: Option<Sha>,
+    pub draft: bool,
+    /// The milestone's title.
+    pub milestone: Option<String>,
+    /// Paths of the files the PR changes, see `source::record_sample`.
+    pub files: Vec<String>,
 }
 
 pub struct PullRequest {
+    /// The API host the PR came from, `id` is only unique per host.
+    pub host: String,
     pub id: u32,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
     pub number: u32,
     pub title: String,
     pub body: String,
     pub author: User,
     pub created: Date,
     pub url: String,
+    /// The labels when the PR was sampled, see `source::record_labels`.
+    pub labels: Vec<Label>,
 }
 
 pub struct User {
+    /// The API host the user came from, `id` is only unique per host.
+    pub host: String,
     pub id: u32,
     pub username: String,
     pub url: String,
+    /// See `source::is_bot`.
+    pub bot: bool,
+}
+
+/// A review of a PR, or for forges without reviews, a comment by someone other
+/// than the PR's author.
+pub struct Review {
+    pub host: String,
+    pub pr: u32,
+    pub reviewer: String,
+    pub approved: bool,
+    pub submitted: Date,
+}
+
+/// A PR was converted to a draft (`draft` is true) or marked as ready for
+/// review (`draft` is false).
+pub struct DraftEvent {
+    pub host: String,
+    pub pr: u32,
+    pub draft: bool,
+    pub time: Date,
+}
+
+/// Derived from a local mirror of the PR's repo, see `git::analyse`.
+pub struct LeadTime {
+    pub host: String,
+    pub pr: u32,
+    /// When the earliest commit in the PR was authored.
+    pub first_authored: Date,
+    pub merged: Date,
+    pub rebases: u32,
+    pub force_pushes: u32,
+}
+
+/// A label in a repo.
+pub struct Label {
+    pub host: String,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
+    pub name: String,
+    /// Hex RGB, empty if the forge doesn't tell us.
+    pub color: String,
+}
+
+/// A label applied to a PR. Times are from the samples where we first saw the
+/// label and where we first saw it had gone.
+pub struct PrLabel {
+    pub host: String,
+    pub pr: u32,
+    pub label: String,
+    pub added: Date,
+    pub removed: Option<Date>,
+}
+
+/// A run of a CI check (or a commit status) on one of a PR's head commits.
+/// Re-runs are separate runs.
+pub struct CheckRun {
+    pub host: String,
+    pub pr: u32,
+    pub sha: Sha,
+    pub name: String,
+    pub started: Date,
+    /// `None` while running.
+    pub finished: Option<Date>,
+    /// `success`, `failure`, or `other` (e.g., cancelled or skipped), `None`
+    /// while running.
+    pub conclusion: Option<String>,
+}
+
+/// A file changed by a PR, as of the PR's most recent sample.
+pub struct PrFile {
+    pub host: String,
+    pub pr: u32,
+    pub path: String,
+    /// Set by `area::update_all` (or `area::update_paths`), `None` if the path
+    /// isn't in any area.
+    pub area: Option<String>,
+}
+
+/// An issue, which PRs may fix (see `PrIssue`).
+pub struct Issue {
+    /// The API host the issue came from, `id` is only unique per host.
+    pub host: String,
+    pub id: u32,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
+    pub number: u32,
+    pub title: String,
+    pub body: String,
+    pub created: Date,
+    pub url: String,
+}
+
+pub struct IssueSample {
+    /// The API host of `issue`.
+    pub host: String,
+    pub time: Date,
+    pub issue: Issue,
+    pub closed: Option<Date>,
+}
+
+/// An issue which a PR says it fixes, e.g., with "Fixes #12" in its body (see
+/// `source::fixed_issues`). The issue might not have been recorded (yet).
+pub struct PrIssue {
+    pub host: String,
+    pub pr: u32,
+    pub repo: String,
+    /// The issue's number in `repo`.
+    pub issue: u32,
+}
+
+/// An issue with a label from a `FailureRule::IssueLabel`.
+pub struct Incident {
+    pub host: String,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
+    pub number: u32,
+    /// The label which makes this an incident.
+    pub label: String,
+    pub title: String,
+    pub body: String,
+    pub opened: Date,
+    pub closed: Option<Date>,
+}
+
+/// A release, tag, or deployment of a repo, see `config::Deploys`.
+pub struct Deployment {
+    pub host: String,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
+    /// The release's tag, the tag, or the deployment's id. Unique per repo.
+    pub name: String,
+    /// Empty if unknown (e.g., for releases), in which case `name` is looked up
+    /// as a tag in the repo's mirror.
+    pub sha: Sha,
+    pub time: Date,
+}
+
+/// Links a merged PR to the first deployment which contains it.
+pub struct PrDeployment {
+    pub host: String,
+    pub pr: u32,
+    pub repo: String,
+    pub deployment: String,
+}
+
+/// An alert which has been sent, so we don't send it again, see `anomaly`.
+pub struct SentAlert {
+    pub series: String,
+    /// The start of the week the alert is about.
+    pub week: String,
+    pub kind: String,
+    /// Where it was sent, see `anomaly::sink_name`.
+    pub sink: String,
+}
+
+/// A failure in production, found using `config::FAILURE_RULES`.
+pub struct Failure {
+    pub host: String,
+    /// The repo's full name, i.e., `owner/name`.
+    pub repo: String,
+    /// The PR or issue which fixes or reports the failure, e.g., `pr 12` or
+    /// `issue 34`.
+    pub source: String,
+    pub started: Date,
+    /// `None` if not yet restored.
+    pub restored: Option<Date>,
+    /// The id of the PR which caused the failure, if known.
+    pub cause_pr: Option<u32>,
+    /// The deployment which caused the failure, if known.
+    pub cause_deployment: Option<String>,
 }
 
 // TODO perhaps move out the below stuff (or separate generic data from GH data)
@@ -57,5 +246,5 @@ impl Date {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Sha(pub String);
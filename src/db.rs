@@ -1,65 +1,1807 @@
-use crate::config::DB_PATH;
+use crate::bucket::Granularity;
+use crate::business::{Calendar, Clock, WallClock};
+use crate::config::{BOT_PRS, COHORT_DAYS, DB_PATH, GITHUB_API};
 use crate::data::{self, Date, Sha, Status};
-use crate::frontend;
-use crate::Result;
+use crate::size::Size;
+use crate::{cycle, frontend, GhvError, Result};
 
 use rusqlite::{
     self, params,
     types::{self, FromSql},
-    Connection, Row, Statement, ToSql, NO_PARAMS,
+    Connection, OptionalExtension, Row, Statement, ToSql, NO_PARAMS,
 };
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Range;
+use std::str::FromStr;
 
-/// Create a new database from scratch. Will panic if the db already exists.
+/// Create a new database from scratch.
 pub fn init(conn: &Connection) -> Result<()> {
+    create_tables(conn)?;
+    conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+    Ok(())
+}
+
+/// Create any tables which don't exist yet.
+fn create_tables(conn: &Connection) -> Result<()> {
     data::PullRequest::init(conn)?;
     data::User::init(conn)?;
     data::Sample::init(conn)?;
+    data::LeadTime::init(conn)?;
+    data::Deployment::init(conn)?;
+    data::PrDeployment::init(conn)?;
+    data::Label::init(conn)?;
+    data::PrLabel::init(conn)?;
+    data::PrFile::init(conn)?;
+    data::CheckRun::init(conn)?;
+    data::Issue::init(conn)?;
+    data::IssueSample::init(conn)?;
+    data::PrIssue::init(conn)?;
+    data::Review::init(conn)?;
+    data::DraftEvent::init(conn)?;
+    data::Incident::init(conn)?;
+    data::Failure::init(conn)?;
+    data::SentAlert::init(conn)?;
 
     // TODO indexes
 
     Ok(())
 }
 
+/// Schema changes to apply to dbs created before them, in order; `init` creates
+/// the up-to-date schema. The number applied is the db's `user_version`. Dbs
+/// from before we tracked that are at version zero whatever their schema, so
+/// each migration checks whether it's needed.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] =
+    &[hosts, unique_issue_samples, alert_sinks, label_history];
+
+/// Bring an existing db's schema up to date, including creating any new tables.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(conn)?;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", i + 1))?;
+    }
+    create_tables(conn)
+}
+
+/// PRs, users and samples are keyed by their host as well as their id, and PRs
+/// have a repo, users a bot flag, and samples a head commit and draft flag.
+/// Everything from before then came from GitHub, and we didn't know the head
+/// commits or drafts.
+fn hosts(conn: &Connection) -> Result<()> {
+    if !has_column(conn, "pr", "id")? || has_column(conn, "pr", "host")? {
+        return Ok(());
+    }
+    conn.execute_batch(
+        "ALTER TABLE pr RENAME TO old_pr;
+        ALTER TABLE user RENAME TO old_user;
+        ALTER TABLE sample RENAME TO old_sample;
+        CREATE TABLE pr (
+            host TEXT NOT NULL,
+            id INTEGER NOT NULL,
+            repo TEXT NOT NULL,
+            number INTEGER,
+            title TEXT NOT NULL,
+            body TEXT NOT NULL,
+            author INTEGER,
+            created TEXT NOT NULL,
+            url TEXT NOT NULL,
+            PRIMARY KEY (host, id)
+        );
+        CREATE TABLE user (
+            host TEXT NOT NULL,
+            id INTEGER NOT NULL,
+            username TEXT NOT NULL,
+            url TEXT NOT NULL,
+            bot INTEGER NOT NULL,
+            PRIMARY KEY (host, id)
+        );
+        CREATE TABLE sample (
+            id INTEGER PRIMARY KEY,
+            host TEXT NOT NULL,
+            pr INTEGER,
+            status TEXT NOT NULL,
+            time TEXT NOT NULL,
+            commits INTEGER,
+            additions INTEGER,
+            deletions INTEGER,
+            changed_files INTEGER,
+            review_comments INTEGER,
+            first_commit TEXT NOT NULL,
+            head TEXT NOT NULL,
+            merge_commit TEXT,
+            draft INTEGER NOT NULL,
+            milestone TEXT
+        );",
+    )?;
+    // The repo is in the PR's URL, e.g., `https://github.com/owner/repo/pull/1`.
+    conn.execute(
+        "INSERT INTO pr
+            SELECT ?1, id,
+                    CASE WHEN url LIKE 'https://github.com/%/pull/%'
+                        THEN substr(url, 20, instr(url, '/pull/') - 20)
+                        ELSE ''
+                    END,
+                    number, title, body, author, created, url
+                FROM old_pr",
+        params![GITHUB_API],
+    )?;
+    conn.execute(
+        "INSERT INTO user SELECT ?1, id, username, url, username LIKE '%[bot]' FROM old_user",
+        params![GITHUB_API],
+    )?;
+    conn.execute(
+        "INSERT INTO sample
+            SELECT id, ?1, pr, status, time, commits, additions, deletions, changed_files,
+                    review_comments, first_commit, '', NULL, 0, NULL
+                FROM old_sample",
+        params![GITHUB_API],
+    )?;
+    conn.execute_batch(
+        "DROP TABLE old_pr;
+        DROP TABLE old_user;
+        DROP TABLE old_sample;",
+    )?;
+    Ok(())
+}
+
+/// Issue samples are unique per issue and time.
+fn unique_issue_samples(conn: &Connection) -> Result<()> {
+    if has_column(conn, "issue_sample", "id")? {
+        conn.execute_batch(
+            "DELETE FROM issue_sample WHERE id NOT IN (
+                SELECT MIN(id) FROM issue_sample GROUP BY host, issue, time
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS issue_sample_time
+                ON issue_sample (host, issue, time);",
+        )?;
+    }
+    Ok(())
+}
+
+/// Alerts are recorded per sink. Those sent before were sent to every sink, see
+/// `alert_sent`.
+fn alert_sinks(conn: &Connection) -> Result<()> {
+    if has_column(conn, "sent_alert", "series")? && !has_column(conn, "sent_alert", "sink")? {
+        conn.execute_batch(
+            "ALTER TABLE sent_alert RENAME TO old_sent_alert;
+            CREATE TABLE sent_alert (
+                series TEXT NOT NULL,
+                week TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                sink TEXT NOT NULL,
+                PRIMARY KEY (series, week, kind, sink)
+            );
+            INSERT INTO sent_alert SELECT series, week, kind, '' FROM old_sent_alert;
+            DROP TABLE old_sent_alert;",
+        )?;
+    }
+    Ok(())
+}
+
+/// Labels have a history, and samples a milestone. We didn't know when existing
+/// labels were added, so assume they were there from the start.
+fn label_history(conn: &Connection) -> Result<()> {
+    if has_column(conn, "pr_label", "label")? && !has_column(conn, "pr_label", "added")? {
+        conn.execute_batch(
+            "ALTER TABLE pr_label RENAME TO old_pr_label;
+            CREATE TABLE pr_label (
+                host TEXT NOT NULL,
+                pr INTEGER NOT NULL,
+                label TEXT NOT NULL,
+                added TEXT NOT NULL,
+                removed TEXT,
+                PRIMARY KEY (host, pr, label, added)
+            );
+            INSERT INTO pr_label
+                SELECT old_pr_label.host, old_pr_label.pr, old_pr_label.label,
+                        COALESCE(pr.created, ''), NULL
+                    FROM old_pr_label
+                    LEFT JOIN pr ON pr.host = old_pr_label.host AND pr.id = old_pr_label.pr;
+            DROP TABLE old_pr_label;",
+        )?;
+    }
+    if has_column(conn, "sample", "id")? && !has_column(conn, "sample", "milestone")? {
+        conn.execute_batch("ALTER TABLE sample ADD COLUMN milestone TEXT")?;
+    }
+    Ok(())
+}
+
+/// Whether `table` exists and has `column`.
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Vec<String> = collect_query(&mut stmt, NO_PARAMS, |row| row.get(1))?;
+    Ok(columns.iter().any(|c| c == column))
+}
+
 pub fn connection() -> Result<Connection> {
     Connection::open(DB_PATH).map_err(Into::into)
 }
 
+/// PRs which were open at some time in `times`, i.e., which were opened before
+/// its end and not merged or closed before its start (according to their most
+/// recent sample).
 pub fn read_prs(conn: &Connection, times: Range<Date>) -> Result<Vec<PullRequest>> {
     let reader = PrReader::init(conn)?;
     reader.read(times)
 }
 
-// TODO test
-pub fn open_prs_per_day(conn: &Connection) -> Result<Vec<frontend::Day>> {
+/// A range for `read_prs` which includes every PR.
+pub fn all_time() -> Range<Date> {
+    Date::new("0000-01-01".to_owned())..Date::new("9999-12-31".to_owned())
+}
+
+/// A condition selecting the most recent sample for each PR, which tells us if
+/// and when the PR was merged or closed.
+macro_rules! latest_sample {
+    () => {
+        "sample.id = (
+            SELECT s.id FROM sample s
+                WHERE s.host = pr.host AND s.pr = pr.id
+                ORDER BY s.time DESC, s.id DESC
+                LIMIT 1
+        )"
+    };
+}
+
+/// Which PRs to include in aggregates.
+#[derive(Clone, Debug, Default)]
+pub struct PrFilter {
+    /// Only PRs which had this label at the time being aggregated, e.g., when
+    /// they were merged.
+    pub label: Option<String>,
+    pub bots: Bots,
+    /// Only PRs which change a file in this area, see `area`.
+    pub area: Option<String>,
+}
+
+/// Whether to include PRs authored by bots (see `source::is_bot`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bots {
+    Include,
+    Exclude,
+    /// Only PRs by bots, to report them separately.
+    Only,
+}
+
+/// `config::BOT_PRS`.
+impl Default for Bots {
+    fn default() -> Bots {
+        BOT_PRS
+    }
+}
+
+impl FromStr for Bots {
+    type Err = GhvError;
+
+    fn from_str(s: &str) -> Result<Bots> {
+        match s {
+            "include" => Ok(Bots::Include),
+            "exclude" => Ok(Bots::Exclude),
+            "only" => Ok(Bots::Only),
+            _ => Err(GhvError::Other),
+        }
+    }
+}
+
+impl PrFilter {
+    /// The parameters for `pr_filter!`, in order.
+    fn params(&self) -> [&dyn ToSql; 4] {
+        [
+            &self.label,
+            if self.bots == Bots::Exclude {
+                &true
+            } else {
+                &false
+            },
+            if self.bots == Bots::Only {
+                &true
+            } else {
+                &false
+            },
+            &self.area,
+        ]
+    }
+}
+
+/// A condition selecting PRs which match a `PrFilter` at `$time` (an SQL
+/// expression), using its `params` as the first parameters of the query. `user`
+/// must be the PR's author.
+macro_rules! pr_filter {
+    ($time: tt) => {
+        concat!(
+            "(?1 IS NULL OR EXISTS (
+            SELECT * FROM pr_label
+                WHERE pr_label.host = pr.host AND pr_label.pr = pr.id AND pr_label.label = ?1
+                    AND julianday(pr_label.added) <= julianday(",
+            $time,
+            ")
+                    AND (pr_label.removed IS NULL OR julianday(pr_label.removed) > julianday(",
+            $time,
+            "))
+        ))
+        AND ",
+            pr_timeless_filter!()
+        )
+    };
+}
+
+/// The parts of `pr_filter!` which don't depend on the time, i.e., ignoring
+/// the label.
+macro_rules! pr_timeless_filter {
+    () => {
+        "(NOT ?2 OR NOT user.bot)
+        AND (NOT ?3 OR user.bot)
+        AND (?4 IS NULL OR EXISTS (
+            SELECT * FROM pr_file
+                WHERE pr_file.host = pr.host AND pr_file.pr = pr.id AND pr_file.area = ?4
+        ))"
+    };
+}
+
+/// A condition selecting deployments which include a PR matching a `PrFilter`
+/// (as `pr_filter!`), or every deployment if the filter is the default.
+macro_rules! deployment_filter {
+    () => {
+        concat!(
+            "((?1 IS NULL AND NOT ?2 AND NOT ?3 AND ?4 IS NULL) OR EXISTS (
+            SELECT * FROM pr_deployment
+                JOIN pr ON pr.host = pr_deployment.host AND pr.id = pr_deployment.pr
+                JOIN user ON user.host = pr.host AND user.id = pr.author
+                WHERE pr_deployment.host = deployment.host
+                    AND pr_deployment.repo = deployment.repo
+                    AND pr_deployment.deployment = deployment.name
+                    AND ",
+            pr_filter!("deployment.time"),
+            "
+        ))"
+        )
+    };
+}
+
+/// For every day we have a sample from, the number of PRs which were open (and
+/// not drafts) and which were drafts, according to each PR's most recent sample
+/// from that day or earlier. Likewise for issues which were open on that day
+/// (according to when they were created and, from their most recent sample,
+/// closed), which aren't filtered.
+pub fn open_prs_per_day(conn: &Connection, filter: &PrFilter) -> Result<Vec<frontend::Day>> {
+    open_prs_per_bucket(conn, filter, Granularity::Day)
+}
+
+/// As `open_prs_per_day`, but only for the last day we have a sample from in
+/// each period.
+pub fn open_prs_per_bucket(
+    conn: &Connection,
+    filter: &PrFilter,
+    granularity: Granularity,
+) -> Result<Vec<frontend::Day>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT MAX(date) AS date
+            FROM (
+                SELECT date(time) AS date FROM sample
+                UNION SELECT date(time) FROM issue_sample
+            )
+            GROUP BY {}
+            ORDER BY date",
+        granularity.start("date"),
+    ))?;
+    let days: Vec<String> = collect_query(&mut stmt, NO_PARAMS, |row| row.get(0))?;
+
+    // Rather than finding each PR's sample for each day, each sample counts
+    // from its day until the day of the PR's next sample, and likewise for
+    // labels and issues. These are the changes in the counts at each day.
+    let mut open_prs = vec![0i64; days.len() + 1];
+    let mut draft_prs = vec![0i64; days.len() + 1];
+    let mut open_issues = vec![0i64; days.len() + 1];
+
+    // With a label filter, when each PR had the label, as ranges of days.
+    let mut labelled: HashMap<(String, u32), Vec<(usize, usize)>> = HashMap::new();
+    if let Some(label) = &filter.label {
+        let mut stmt = conn.prepare(
+            "SELECT host, pr, date(added), date(removed)
+                FROM pr_label
+                WHERE label = ?1
+                ORDER BY host, pr, added",
+        )?;
+        let labels = collect_query(&mut stmt, params![label], |row| {
+            Ok((
+                (row.get(0)?, row.get(1)?),
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+        for (pr, added, removed) in labels {
+            let from = day_index(&days, &added);
+            let to = removed.map_or(days.len(), |r| day_index(&days, &r));
+            let ranges = labelled.entry(pr).or_default();
+            // Merge overlapping ranges, so a PR is never counted twice.
+            match ranges.last_mut() {
+                Some(last) if from <= last.1 => last.1 = last.1.max(to),
+                _ => ranges.push((from, to)),
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare(concat!(
+        "SELECT pr.host, pr.id, date(sample.time),
+                sample.status = 'Open' AND NOT sample.draft,
+                sample.status = 'Open' AND sample.draft
+            FROM sample
+            JOIN pr ON pr.host = sample.host AND pr.id = sample.pr
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            WHERE ",
+        pr_timeless_filter!(),
+        "
+            ORDER BY pr.host, pr.id, sample.time, sample.id",
+    ))?;
+    let samples = collect_query(&mut stmt, &filter.params(), |row| {
+        Ok((
+            (row.get::<_, String>(0)?, row.get::<_, u32>(1)?),
+            row.get::<_, String>(2)?,
+            row.get::<_, bool>(3)?,
+            row.get::<_, bool>(4)?,
+        ))
+    })?;
+    for (i, (pr, date, open, draft)) in samples.iter().enumerate() {
+        let counts = match (open, draft) {
+            (true, _) => &mut open_prs,
+            (_, true) => &mut draft_prs,
+            _ => continue,
+        };
+        let from = day_index(&days, date);
+        let to = match samples.get(i + 1) {
+            Some((next_pr, next_date, ..)) if next_pr == pr => day_index(&days, next_date),
+            _ => days.len(),
+        };
+        if filter.label.is_none() {
+            count_range(counts, from, to);
+        } else if let Some(ranges) = labelled.get(pr) {
+            for &(added, removed) in ranges {
+                count_range(counts, from.max(added), to.min(removed));
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT date(issue.created), date(issue_sample.closed)
+            FROM issue
+            JOIN issue_sample ON issue_sample.id = (
+                SELECT s.id FROM issue_sample s
+                    WHERE s.host = issue.host AND s.issue = issue.id
+                    ORDER BY s.time DESC, s.id DESC
+                    LIMIT 1
+            )",
+    )?;
+    let issues = collect_query(&mut stmt, NO_PARAMS, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+    })?;
+    for (created, closed) in issues {
+        let to = closed.map_or(days.len(), |c| day_index(&days, &c));
+        count_range(&mut open_issues, day_index(&days, &created), to);
+    }
+
+    let (mut open, mut draft, mut issues) = (0, 0, 0);
+    let result = days
+        .into_iter()
+        .enumerate()
+        .map(|(i, date)| {
+            open += open_prs[i];
+            draft += draft_prs[i];
+            issues += open_issues[i];
+            frontend::Day {
+                date,
+                open_prs: open as u32,
+                draft_prs: draft as u32,
+                open_issues: issues as u32,
+            }
+        })
+        .collect();
+
+    Ok(result)
+}
+
+/// The index of the first of `days` (sorted dates) which is `date` or later.
+fn day_index(days: &[String], date: &str) -> usize {
+    match days.binary_search_by(|d| d.as_str().cmp(date)) {
+        Ok(i) | Err(i) => i,
+    }
+}
+
+/// Count one more for each day in `from..to`, as changes in the count.
+fn count_range(changes: &mut [i64], from: usize, to: usize) {
+    if from < to {
+        changes[from] += 1;
+        changes[to] -= 1;
+    }
+}
+
+/// For every day we have a sample from, the number of PRs in each state,
+/// according to each PR's most recent sample from that day or earlier. A PR is
+/// in review once someone other than its author has reviewed it, and approved
+/// once someone has approved it.
+pub fn cumulative_flow(conn: &Connection, filter: &PrFilter) -> Result<Vec<frontend::FlowDay>> {
+    let mut stmt = conn.prepare(concat!(
+        "SELECT date,
+                COALESCE(SUM(state = 'draft'), 0),
+                COALESCE(SUM(state = 'awaiting_review'), 0),
+                COALESCE(SUM(state = 'in_review'), 0),
+                COALESCE(SUM(state = 'approved'), 0),
+                COALESCE(SUM(state = 'merged'), 0),
+                COALESCE(SUM(state = 'closed'), 0)
+            FROM (
+                SELECT day.date AS date,
+                    CASE
+                        WHEN sample.id IS NULL THEN NULL
+                        WHEN substr(sample.status, 1, 1) = 'M' THEN 'merged'
+                        WHEN substr(sample.status, 1, 1) = 'C' THEN 'closed'
+                        WHEN sample.draft THEN 'draft'
+                        WHEN EXISTS (
+                            SELECT * FROM review
+                                WHERE review.host = pr.host AND review.pr = pr.id
+                                    AND review.reviewer != user.username AND review.approved
+                                    AND date(review.submitted) <= day.date
+                        ) THEN 'approved'
+                        WHEN EXISTS (
+                            SELECT * FROM review
+                                WHERE review.host = pr.host AND review.pr = pr.id
+                                    AND review.reviewer != user.username
+                                    AND date(review.submitted) <= day.date
+                        ) THEN 'in_review'
+                        ELSE 'awaiting_review'
+                    END AS state
+                FROM (SELECT DISTINCT date(time) AS date FROM sample) AS day
+                LEFT JOIN (pr JOIN user ON user.host = pr.host AND user.id = pr.author)
+                    ON ",
+        pr_filter!("day.date || ' 23:59:59'"),
+        "
+                LEFT JOIN sample ON sample.id = (
+                    SELECT s.id FROM sample s
+                        WHERE s.host = pr.host AND s.pr = pr.id AND date(s.time) <= day.date
+                        ORDER BY s.time DESC, s.id DESC
+                        LIMIT 1
+                )
+            )
+            GROUP BY date
+            ORDER BY date",
+    ))?;
+
+    let result = collect_query(&mut stmt, &filter.params(), |row| {
+        Ok(frontend::FlowDay {
+            date: row.get(0)?,
+            draft: row.get(1)?,
+            awaiting_review: row.get(2)?,
+            in_review: row.get(3)?,
+            approved: row.get(4)?,
+            merged: row.get(5)?,
+            closed: row.get(6)?,
+        })
+    })?;
+
+    Ok(result)
+}
+
+pub fn weekly_stats(conn: &Connection, filter: &PrFilter) -> Result<Vec<frontend::Week>> {
+    stats(conn, filter, Granularity::Week)
+}
+
+/// As `weekly_stats`, but continuing through `this_week` (the start of a week),
+/// so that recent weeks where nothing was merged or closed are included.
+pub fn weekly_stats_through(
+    conn: &Connection,
+    filter: &PrFilter,
+    this_week: &str,
+) -> Result<Vec<frontend::Week>> {
+    stats_through(conn, filter, Granularity::Week, Some(this_week))
+}
+
+/// Stats for PRs merged or closed in each period, every period from the first
+/// to the last is included. A `frontend::Week`'s `start_date` is the start of
+/// its period.
+pub fn stats(
+    conn: &Connection,
+    filter: &PrFilter,
+    granularity: Granularity,
+) -> Result<Vec<frontend::Week>> {
+    stats_through(conn, filter, granularity, None)
+}
+
+fn stats_through(
+    conn: &Connection,
+    filter: &PrFilter,
+    granularity: Granularity,
+    through: Option<&str>,
+) -> Result<Vec<frontend::Week>> {
+    let calendar = Calendar::get()?;
+    let mut weeks = BTreeMap::new();
+    for pr in finished_prs(conn, filter, granularity)? {
+        let FinishedPr {
+            bucket: week,
+            merged: is_merged,
+            host,
+            id,
+            author,
+            created,
+            finished: merged,
+            time_to_merge,
+            review_comments,
+            lead_time,
+            coding_time,
+            review_time,
+            lines,
+            first_authored,
+            lead_time_merged,
+            ..
+        } = pr;
+        let values = weeks
+            .entry(week.clone())
+            .or_insert_with(|| WeekValues::new(week));
+
+        if !is_merged {
+            values.closed_prs += 1;
+            continue;
+        }
+        // Time as a draft doesn't count towards review metrics.
+        let activity = read_activity(conn, &host, id, &author, created, merged)?;
+        let phases = cycle::Phases::new(&activity, &WallClock);
+        let ci = cycle::Ci::new(&activity, &WallClock);
+        let issues_opened = fixed_issues_opened(conn, &host, id)?;
+        values.issue_to_merge.extend(
+            issues_opened
+                .iter()
+                .map(|&opened| WallClock.minutes(opened, merged)),
+        );
+        values.ci_wait.push(ci.wait);
+        values.blocked_on_ci.push(ci.blocked);
+        values.ci_runs += ci.runs;
+        values.ci_failures += ci.failed;
+        values.ci_reruns += ci.reruns;
+        values.flaky_checks += ci.flaky;
+        let time_to_merge = time_to_merge.saturating_sub(phases.draft);
+        values.merged_prs += 1;
+        values.time_to_merge.push(time_to_merge);
+        values
+            .sizes
+            .entry(Size::of(lines))
+            .or_insert_with(Vec::new)
+            .push(time_to_merge);
+        values.review_comments.push(review_comments);
+        values.lead_time.extend(lead_time);
+        values.coding_time.extend(coding_time);
+        values
+            .review_time
+            .extend(review_time.map(|t| t.saturating_sub(phases.draft)));
+        values.draft_time.push(phases.draft);
+        values.waiting_for_review.push(phases.waiting_for_review);
+        values.in_review.push(phases.in_review);
+        values.approval_to_merge.push(phases.approval_to_merge);
+
+        let phases = cycle::Phases::new(&activity, calendar);
+        let ci = cycle::Ci::new(&activity, calendar);
+        let business = &mut values.business;
+        let time_to_merge = calendar
+            .minutes(created, merged)
+            .saturating_sub(phases.draft);
+        business.time_to_merge.push(time_to_merge);
+        business
+            .sizes
+            .entry(Size::of(lines))
+            .or_insert_with(Vec::new)
+            .push(time_to_merge);
+        if let (Some(first_authored), Some(lead_time_merged)) = (first_authored, lead_time_merged) {
+            business
+                .lead_time
+                .push(calendar.minutes(first_authored, lead_time_merged));
+            business
+                .coding_time
+                .push(calendar.minutes(first_authored, created));
+            business.review_time.push(
+                calendar
+                    .minutes(created, lead_time_merged)
+                    .saturating_sub(phases.draft),
+            );
+        }
+        business.draft_time.push(phases.draft);
+        business.waiting_for_review.push(phases.waiting_for_review);
+        business.in_review.push(phases.in_review);
+        business.approval_to_merge.push(phases.approval_to_merge);
+        business.issue_to_merge.extend(
+            issues_opened
+                .iter()
+                .map(|&opened| calendar.minutes(opened, merged)),
+        );
+        business.ci_wait.push(ci.wait);
+        business.blocked_on_ci.push(ci.blocked);
+    }
+
+    Ok(
+        fill_buckets(conn, granularity, weeks, through, WeekValues::new)?
+            .into_iter()
+            .map(WeekValues::into_week)
+            .collect(),
+    )
+}
+
+/// A PR which was closed or merged according to its latest sample, see
+/// `finished_prs`. Durations are in minutes and times are Julian days.
+struct FinishedPr {
+    /// The start of the period it was closed or merged in.
+    bucket: String,
+    merged: bool,
+    repo: String,
+    number: u32,
+    title: String,
+    url: String,
+    host: String,
+    id: u32,
+    author: String,
+    created: f64,
+    /// When it was closed or merged.
+    finished: f64,
+    time_to_merge: u32,
+    review_comments: u32,
+    lead_time: Option<u32>,
+    coding_time: Option<u32>,
+    review_time: Option<u32>,
+    /// Lines added plus lines deleted.
+    lines: u32,
+    first_authored: Option<f64>,
+    lead_time_merged: Option<f64>,
+}
+
+/// Every PR matching `filter` which was closed or merged, in order of closing
+/// or merging, with the start of the period of `granularity` it was in.
+fn finished_prs(
+    conn: &Connection,
+    filter: &PrFilter,
+    granularity: Granularity,
+) -> Result<Vec<FinishedPr>> {
+    // Weeks start on Monday.
+    let mut stmt = conn.prepare(&format!(
+        concat!(
+        "SELECT {},
+                substr(sample.status, 1, 1) = 'M',
+                pr.repo, pr.number, pr.title, pr.url,
+                pr.host, pr.id, user.username, julianday(pr.created), julianday(substr(sample.status, 8)),
+                CAST(ROUND(MAX(julianday(substr(sample.status, 8)) - julianday(pr.created), 0) * 1440) AS INTEGER),
+                sample.review_comments,
+                CAST(ROUND(MAX(julianday(lead_time.merged) - julianday(lead_time.first_authored), 0) * 1440) AS INTEGER),
+                CAST(ROUND(MAX(julianday(pr.created) - julianday(lead_time.first_authored), 0) * 1440) AS INTEGER),
+                CAST(ROUND(MAX(julianday(lead_time.merged) - julianday(pr.created), 0) * 1440) AS INTEGER),
+                sample.additions + sample.deletions,
+                julianday(lead_time.first_authored), julianday(lead_time.merged)
+            FROM pr
+            JOIN sample ON sample.host = pr.host AND sample.pr = pr.id
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            LEFT JOIN lead_time ON lead_time.host = pr.host AND lead_time.pr = pr.id
+            WHERE sample.status != 'Open' AND ",
+        pr_filter!("substr(sample.status, 8)"),
+        " AND ",
+        latest_sample!(),
+        "
+            ORDER BY julianday(substr(sample.status, 8))",
+        ),
+        granularity.start("substr(sample.status, 8)"),
+    ))?;
+    Ok(collect_query(&mut stmt, &filter.params(), |row| {
+        Ok(FinishedPr {
+            bucket: row.get(0)?,
+            merged: row.get(1)?,
+            repo: row.get(2)?,
+            number: row.get(3)?,
+            title: row.get(4)?,
+            url: row.get(5)?,
+            host: row.get(6)?,
+            id: row.get(7)?,
+            author: row.get(8)?,
+            created: row.get(9)?,
+            finished: row.get(10)?,
+            time_to_merge: row.get(11)?,
+            review_comments: row.get(12)?,
+            lead_time: row.get(13)?,
+            coding_time: row.get(14)?,
+            review_time: row.get(15)?,
+            lines: row.get(16)?,
+            first_authored: row.get(17)?,
+            lead_time_merged: row.get(18)?,
+        })
+    })?)
+}
+
+/// A merged or open PR and how long it took (so far), see `slo`.
+pub struct PrTimes {
+    pub repo: String,
+    pub number: u32,
+    pub title: String,
+    pub url: String,
+    /// The start of the week it was merged, or for an open PR, the current
+    /// week.
+    pub week: String,
+    /// In minutes, excluding time as a draft, as in `frontend::Week`.
+    pub waiting_for_review: u32,
+    pub time_to_merge: u32,
+    /// The same in business time, see `business`.
+    pub business_waiting_for_review: u32,
+    pub business_time_to_merge: u32,
+}
+
+impl PrTimes {
+    fn new(
+        conn: &Connection,
+        (repo, number, title, url, week): (String, u32, String, String, String),
+        (host, id, author): (&str, u32, &str),
+        created: f64,
+        end: f64,
+    ) -> Result<PrTimes> {
+        let calendar = Calendar::get()?;
+        let activity = read_activity(conn, host, id, author, created, end)?;
+        let phases = cycle::Phases::new(&activity, &WallClock);
+        let business_phases = cycle::Phases::new(&activity, calendar);
+        Ok(PrTimes {
+            repo,
+            number,
+            title,
+            url,
+            week,
+            waiting_for_review: phases.waiting_for_review,
+            time_to_merge: WallClock.minutes(created, end).saturating_sub(phases.draft),
+            business_waiting_for_review: business_phases.waiting_for_review,
+            business_time_to_merge: calendar
+                .minutes(created, end)
+                .saturating_sub(business_phases.draft),
+        })
+    }
+}
+
+/// Every merged PR, in order of merging.
+pub fn pr_times(conn: &Connection) -> Result<Vec<PrTimes>> {
+    let mut result = vec![];
+    for pr in finished_prs(conn, &PrFilter::default(), Granularity::Week)? {
+        if pr.merged {
+            result.push(PrTimes::new(
+                conn,
+                (pr.repo, pr.number, pr.title, pr.url, pr.bucket),
+                (&pr.host, pr.id, &pr.author),
+                pr.created,
+                pr.finished,
+            )?);
+        }
+    }
+    Ok(result)
+}
+
+/// Every PR which is open according to its latest sample, measured as if it
+/// were merged at `now`, which may be any time SQLite understands.
+pub fn open_pr_times(conn: &Connection, now: &str) -> Result<Vec<PrTimes>> {
+    let filter = PrFilter::default();
+    let mut stmt = conn.prepare(concat!(
+        "SELECT pr.repo, pr.number, pr.title, pr.url, date(?5, 'weekday 0', '-6 days'),
+                pr.host, pr.id, user.username, julianday(pr.created), julianday(?5)
+            FROM pr
+            JOIN sample ON sample.host = pr.host AND sample.pr = pr.id
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            WHERE sample.status = 'Open' AND ",
+        pr_filter!("?5"),
+        " AND ",
+        latest_sample!(),
+        "
+            ORDER BY pr.created",
+    ))?;
+    let params = filter.params();
+    let rows = collect_query(
+        &mut stmt,
+        &[params[0], params[1], params[2], params[3], &now],
+        |row| {
+            Ok((
+                (
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ),
+                (
+                    row.get::<_, String>(5)?,
+                    row.get(6)?,
+                    row.get::<_, String>(7)?,
+                ),
+                row.get(8)?,
+                row.get(9)?,
+            ))
+        },
+    )?;
+    rows.into_iter()
+        .map(|(pr, (host, id, author), created, now)| {
+            PrTimes::new(conn, pr, (&host, id, &author), created, now)
+        })
+        .collect()
+}
+
+/// When each recorded issue which PR `id` fixes was opened.
+fn fixed_issues_opened(conn: &Connection, host: &str, id: u32) -> Result<Vec<f64>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT julianday(issue.created)
+            FROM pr_issue
+            JOIN issue ON issue.host = pr_issue.host
+                AND issue.repo = pr_issue.repo
+                AND issue.number = pr_issue.issue
+            WHERE pr_issue.host = ?1 AND pr_issue.pr = ?2",
+    )?;
+    Ok(collect_query(&mut stmt, params![host, id], |row| {
+        row.get(0)
+    })?)
+}
+
+/// A PR which is open according to its latest sample, see `aging`.
+pub struct OpenPr {
+    pub repo: String,
+    pub number: u32,
+    pub title: String,
+    pub url: String,
+    pub author: String,
+    pub draft: bool,
+    /// Whether anyone other than the author has reviewed or approved the PR.
+    pub reviewed: bool,
+    pub approved: bool,
+    /// Julian days of when the PR was opened, when it was last active, and
+    /// `now`. It's active when it's updated on the forge (e.g., pushed to or
+    /// commented on, as of our most recent sample), reviewed, converted to or
+    /// from a draft, or CI runs on it.
+    pub created: f64,
+    pub last_active: f64,
+    pub now: f64,
+}
+
+/// PRs by the week they were opened, oldest first, with how many were merged
+/// within each of `config::COHORT_DAYS` of being opened. `now` may be any time
+/// SQLite understands. PRs are filtered by their labels when they were merged
+/// or closed, or as of `now` if they're open.
+pub fn cohorts(conn: &Connection, filter: &PrFilter, now: &str) -> Result<Vec<frontend::Cohort>> {
+    let now: f64 = conn.query_row("SELECT julianday(?1)", params![now], |row| row.get(0))?;
+    let mut stmt = conn.prepare(concat!(
+        "SELECT date(pr.created, 'weekday 0', '-6 days') AS week,
+                julianday(date(pr.created, 'weekday 0', '+1 day')),
+                julianday(pr.created),
+                CASE WHEN substr(sample.status, 1, 1) = 'M'
+                    THEN julianday(substr(sample.status, 8))
+                END
+            FROM pr
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            LEFT JOIN sample ON ",
+        latest_sample!(),
+        "
+            WHERE ",
+        pr_filter!("COALESCE(NULLIF(substr(sample.status, 8), ''), ?5)"),
+        "
+            ORDER BY week",
+    ))?;
+    let params = filter.params();
+    let rows = collect_query(
+        &mut stmt,
+        &[params[0], params[1], params[2], params[3], &now],
+        |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, f64>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, Option<f64>>(3)?,
+            ))
+        },
+    )?;
+
+    // Cohorts with the end of their week.
+    let mut cohorts: Vec<(frontend::Cohort, f64)> = vec![];
+    for (week, end, created, merged) in rows {
+        if cohorts.last().map_or(true, |(c, _)| c.start_date != week) {
+            let cohort = frontend::Cohort {
+                start_date: week,
+                opened_prs: 0,
+                merged_within: COHORT_DAYS
+                    .iter()
+                    .map(|&days| frontend::MergedWithin {
+                        days,
+                        merged_prs: 0,
+                        fraction: None,
+                    })
+                    .collect(),
+            };
+            cohorts.push((cohort, end));
+        }
+        let cohort = &mut cohorts.last_mut().unwrap().0;
+        cohort.opened_prs += 1;
+        for within in &mut cohort.merged_within {
+            if merged.map_or(false, |merged| merged - created <= f64::from(within.days)) {
+                within.merged_prs += 1;
+            }
+        }
+    }
+
+    Ok(cohorts
+        .into_iter()
+        .map(|(mut cohort, end)| {
+            for within in &mut cohort.merged_within {
+                // Otherwise the PRs opened at the end of the week haven't had
+                // long enough.
+                if end + f64::from(within.days) <= now {
+                    within.fraction =
+                        Some(f64::from(within.merged_prs) / f64::from(cohort.opened_prs));
+                }
+            }
+            cohort
+        })
+        .collect())
+}
+
+/// Every open PR, oldest first. `now` may be any time SQLite understands.
+pub fn open_prs(conn: &Connection, now: &str) -> Result<Vec<OpenPr>> {
+    let mut stmt = conn.prepare(concat!(
+        "SELECT pr.repo, pr.number, pr.title, pr.url, user.username, sample.draft,
+                EXISTS (
+                    SELECT * FROM review
+                        WHERE review.host = pr.host AND review.pr = pr.id
+                            AND review.reviewer != user.username
+                ),
+                EXISTS (
+                    SELECT * FROM review
+                        WHERE review.host = pr.host AND review.pr = pr.id
+                            AND review.reviewer != user.username AND review.approved
+                ),
+                julianday(pr.created),
+                MAX(
+                    julianday(pr.created),
+                    julianday(sample.time),
+                    COALESCE((
+                        SELECT MAX(julianday(submitted)) FROM review
+                            WHERE review.host = pr.host AND review.pr = pr.id
+                    ), 0),
+                    COALESCE((
+                        SELECT MAX(julianday(time)) FROM draft_event
+                            WHERE draft_event.host = pr.host AND draft_event.pr = pr.id
+                    ), 0),
+                    COALESCE((
+                        SELECT MAX(julianday(started)) FROM check_run
+                            WHERE check_run.host = pr.host AND check_run.pr = pr.id
+                    ), 0)
+                ),
+                julianday(?1)
+            FROM pr
+            JOIN sample ON sample.host = pr.host AND sample.pr = pr.id
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            WHERE sample.status = 'Open' AND ",
+        latest_sample!(),
+        "
+            ORDER BY pr.created",
+    ))?;
+
+    let result = collect_query(&mut stmt, params![now], |row| {
+        Ok(OpenPr {
+            repo: row.get(0)?,
+            number: row.get(1)?,
+            title: row.get(2)?,
+            url: row.get(3)?,
+            author: row.get(4)?,
+            draft: row.get(5)?,
+            reviewed: row.get(6)?,
+            approved: row.get(7)?,
+            created: row.get(8)?,
+            last_active: row.get(9)?,
+            now: row.get(10)?,
+        })
+    })?;
+
+    Ok(result)
+}
+
+/// Whether `alert` has already been sent to its sink. Alerts recorded without a
+/// sink were sent to every sink.
+pub fn alert_sent(conn: &Connection, alert: &data::SentAlert) -> Result<bool> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT EXISTS (
+            SELECT * FROM sent_alert
+                WHERE series = ?1 AND week = ?2 AND kind = ?3 AND sink IN (?4, '')
+        )",
+    )?;
+    Ok(stmt.query_row(
+        params![alert.series, alert.week, alert.kind, alert.sink],
+        |row| row.get(0),
+    )?)
+}
+
+/// Minutes from being opened to being merged, for every merged PR.
+pub fn times_to_merge(conn: &Connection) -> Result<Vec<u32>> {
+    let mut stmt = conn.prepare(concat!(
+        "SELECT CAST(ROUND(MAX(julianday(substr(sample.status, 8)) - julianday(pr.created), 0) * 1440) AS INTEGER)
+            FROM pr
+            JOIN sample ON sample.host = pr.host AND sample.pr = pr.id
+            WHERE substr(sample.status, 1, 1) = 'M' AND ",
+        latest_sample!(),
+    ))?;
+    Ok(collect_query(&mut stmt, NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Draft events, reviews (except those by `author`) and finished CI runs of PR
+/// `id`.
+fn read_activity(
+    conn: &Connection,
+    host: &str,
+    id: u32,
+    author: &str,
+    created: f64,
+    merged: f64,
+) -> Result<cycle::Activity> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT julianday(time) AS t, draft
+            FROM draft_event
+            WHERE host = ?1 AND pr = ?2
+            ORDER BY t",
+    )?;
+    let draft_events = collect_query(&mut stmt, params![host, id], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT julianday(submitted) AS t, approved
+            FROM review
+            WHERE host = ?1 AND pr = ?2 AND reviewer != ?3
+            ORDER BY t",
+    )?;
+    let reviews = collect_query(&mut stmt, params![host, id, author], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT sha, name, julianday(started) AS t, julianday(finished), conclusion = 'failure'
+            FROM check_run
+            WHERE host = ?1 AND pr = ?2 AND conclusion IN ('success', 'failure')
+                AND finished IS NOT NULL
+            ORDER BY t",
+    )?;
+    let checks = collect_query(&mut stmt, params![host, id], |row| {
+        Ok(cycle::Check {
+            sha: row.get(0)?,
+            name: row.get(1)?,
+            started: row.get(2)?,
+            finished: row.get(3)?,
+            failed: row.get(4)?,
+        })
+    })?;
+
+    Ok(cycle::Activity {
+        created,
+        merged,
+        draft_events,
+        reviews,
+        checks,
+    })
+}
+
+/// Deployments per week and, for PRs deployed that week, the lead time for
+/// changes: from the first commit being authored (or the PR being opened, if we
+/// don't have a mirror) to the first deployment containing the PR. With a
+/// non-default `filter`, only deployments (and their failures) which include a
+/// matching PR are counted, and only matching PRs' lead times.
+pub fn weekly_deploy_stats(
+    conn: &Connection,
+    filter: &PrFilter,
+) -> Result<Vec<frontend::DeployWeek>> {
+    deploy_stats(conn, filter, Granularity::Week)
+}
+
+/// As `weekly_deploy_stats`, but per period of `granularity`.
+pub fn deploy_stats(
+    conn: &Connection,
+    filter: &PrFilter,
+    granularity: Granularity,
+) -> Result<Vec<frontend::DeployWeek>> {
+    let calendar = Calendar::get()?;
+    let mut weeks = BTreeMap::new();
+
+    let mut stmt = conn.prepare(&format!(
+        concat!(
+            "SELECT {} AS week, COUNT(*)
+            FROM deployment
+            WHERE ",
+            deployment_filter!(),
+            "
+            GROUP BY week"
+        ),
+        granularity.start("time")
+    ))?;
+    for (week, count) in collect_query(&mut stmt, &filter.params(), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+    })? {
+        weeks
+            .entry(week.clone())
+            .or_insert_with(|| DeployWeekValues::new(week))
+            .deployments = count;
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        concat!(
+            "SELECT {} AS week,
+                CAST(ROUND(MAX(julianday(deployment.time) - julianday(COALESCE(lead_time.first_authored, pr.created)), 0) * 1440) AS INTEGER),
+                julianday(COALESCE(lead_time.first_authored, pr.created)), julianday(deployment.time)
+            FROM pr_deployment
+            JOIN deployment ON deployment.host = pr_deployment.host
+                AND deployment.repo = pr_deployment.repo
+                AND deployment.name = pr_deployment.deployment
+            JOIN pr ON pr.host = pr_deployment.host AND pr.id = pr_deployment.pr
+            JOIN user ON user.host = pr.host AND user.id = pr.author
+            LEFT JOIN lead_time ON lead_time.host = pr.host AND lead_time.pr = pr.id
+            WHERE ",
+            pr_filter!("deployment.time"),
+        ),
+        granularity.start("deployment.time")
+    ))?;
+    for (week, lead_time, start, deployed) in collect_query(&mut stmt, &filter.params(), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, u32>(1)?,
+            row.get::<_, f64>(2)?,
+            row.get::<_, f64>(3)?,
+        ))
+    })? {
+        let values = weeks
+            .entry(week.clone())
+            .or_insert_with(|| DeployWeekValues::new(week));
+        values.lead_time_for_changes.push(lead_time);
+        values
+            .business_lead_time_for_changes
+            .push(calendar.minutes(start, deployed));
+    }
+
+    // Failures are counted in the week of the deployment which caused them, and
+    // their time to restore in the week they were restored.
+    let mut stmt = conn.prepare(&format!(
+        concat!(
+            "SELECT {} AS week, COUNT(DISTINCT deployment.rowid)
+            FROM failure
+            JOIN deployment ON deployment.host = failure.host
+                AND deployment.repo = failure.repo
+                AND deployment.name = failure.cause_deployment
+            WHERE ",
+            deployment_filter!(),
+            "
+            GROUP BY week"
+        ),
+        granularity.start("deployment.time")
+    ))?;
+    for (week, count) in collect_query(&mut stmt, &filter.params(), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+    })? {
+        weeks
+            .entry(week.clone())
+            .or_insert_with(|| DeployWeekValues::new(week))
+            .failed_deployments = count;
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        concat!(
+            "SELECT {} AS week,
+                CAST(ROUND(MAX(julianday(restored) - julianday(started), 0) * 1440) AS INTEGER),
+                julianday(started), julianday(restored)
+            FROM failure
+            LEFT JOIN deployment ON deployment.host = failure.host
+                AND deployment.repo = failure.repo
+                AND deployment.name = failure.cause_deployment
+            WHERE restored IS NOT NULL AND ",
+            deployment_filter!(),
+        ),
+        granularity.start("restored")
+    ))?;
+    for (week, time_to_restore, started, restored) in
+        collect_query(&mut stmt, &filter.params(), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, f64>(3)?,
+            ))
+        })?
+    {
+        let values = weeks
+            .entry(week.clone())
+            .or_insert_with(|| DeployWeekValues::new(week));
+        values.time_to_restore.push(time_to_restore);
+        values
+            .business_time_to_restore
+            .push(calendar.minutes(started, restored));
+    }
+
+    Ok(
+        fill_buckets(conn, granularity, weeks, None, DeployWeekValues::new)?
+            .into_iter()
+            .map(DeployWeekValues::into_week)
+            .collect(),
+    )
+}
+
+/// The values for every period from the first to the last in `weeks` (or to
+/// `through`, the start of a period, if that's later), using `new` for periods
+/// where nothing happened, so the result is continuous.
+fn fill_buckets<T>(
+    conn: &Connection,
+    granularity: Granularity,
+    mut weeks: BTreeMap<String, T>,
+    through: Option<&str>,
+    new: impl Fn(String) -> T,
+) -> Result<Vec<T>> {
+    let (mut week, mut last) = match (weeks.keys().next(), weeks.keys().next_back()) {
+        (Some(first), Some(last)) => (first.clone(), last.clone()),
+        _ => return Ok(vec![]),
+    };
+    if let Some(through) = through {
+        if *through > *last {
+            last = through.to_owned();
+        }
+    }
+
+    let mut result = vec![];
+    loop {
+        result.push(weeks.remove(&week).unwrap_or_else(|| new(week.clone())));
+        if week >= last {
+            return Ok(result);
+        }
+        week = conn.query_row(
+            "SELECT date(?1, ?2)",
+            params![week, granularity.next()],
+            |row| row.get(0),
+        )?;
+    }
+}
+
+/// Raw values for a single week, before computing distributions.
+struct WeekValues {
+    start_date: String,
+    merged_prs: u32,
+    closed_prs: u32,
+    time_to_merge: Vec<u32>,
+    review_comments: Vec<u32>,
+    lead_time: Vec<u32>,
+    coding_time: Vec<u32>,
+    review_time: Vec<u32>,
+    draft_time: Vec<u32>,
+    waiting_for_review: Vec<u32>,
+    in_review: Vec<u32>,
+    approval_to_merge: Vec<u32>,
+    /// Time to merge of PRs of each size.
+    sizes: BTreeMap<Size, Vec<u32>>,
+    issue_to_merge: Vec<u32>,
+    ci_wait: Vec<u32>,
+    blocked_on_ci: Vec<u32>,
+    ci_runs: u32,
+    ci_failures: u32,
+    ci_reruns: u32,
+    flaky_checks: u32,
+    business: BusinessValues,
+}
+
+impl WeekValues {
+    fn new(start_date: String) -> WeekValues {
+        WeekValues {
+            start_date,
+            merged_prs: 0,
+            closed_prs: 0,
+            time_to_merge: vec![],
+            review_comments: vec![],
+            lead_time: vec![],
+            coding_time: vec![],
+            review_time: vec![],
+            draft_time: vec![],
+            waiting_for_review: vec![],
+            in_review: vec![],
+            approval_to_merge: vec![],
+            sizes: BTreeMap::new(),
+            issue_to_merge: vec![],
+            ci_wait: vec![],
+            blocked_on_ci: vec![],
+            ci_runs: 0,
+            ci_failures: 0,
+            ci_reruns: 0,
+            flaky_checks: 0,
+            business: BusinessValues::default(),
+        }
+    }
+
+    fn into_week(self) -> frontend::Week {
+        let sizes = Size::ALL
+            .iter()
+            .map(|size| {
+                let values = self.sizes.get(size).map(|v| &**v).unwrap_or(&[]);
+                let business = self.business.sizes.get(size).map(|v| &**v).unwrap_or(&[]);
+                frontend::SizeWeek {
+                    size: *size,
+                    merged_prs: values.len() as u32,
+                    time_to_merge: frontend::Distribution::new(values),
+                    business_time_to_merge: frontend::Distribution::new(business),
+                }
+            })
+            .collect();
+        frontend::Week {
+            start_date: self.start_date,
+            merged_prs: self.merged_prs,
+            closed_prs: self.closed_prs,
+            time_to_merge: frontend::Distribution::new(&self.time_to_merge),
+            review_comments: frontend::Distribution::new(&self.review_comments),
+            lead_time: frontend::Distribution::new(&self.lead_time),
+            coding_time: frontend::Distribution::new(&self.coding_time),
+            review_time: frontend::Distribution::new(&self.review_time),
+            draft_time: frontend::Distribution::new(&self.draft_time),
+            waiting_for_review: frontend::Distribution::new(&self.waiting_for_review),
+            in_review: frontend::Distribution::new(&self.in_review),
+            approval_to_merge: frontend::Distribution::new(&self.approval_to_merge),
+            sizes,
+            issue_to_merge: frontend::Distribution::new(&self.issue_to_merge),
+            ci_wait: frontend::Distribution::new(&self.ci_wait),
+            blocked_on_ci: frontend::Distribution::new(&self.blocked_on_ci),
+            ci_runs: self.ci_runs,
+            ci_failure_rate: if self.ci_runs == 0 {
+                0.0
+            } else {
+                f64::from(self.ci_failures) / f64::from(self.ci_runs)
+            },
+            ci_reruns: self.ci_reruns,
+            flaky_checks: self.flaky_checks,
+            business: self.business.into_business_time(),
+        }
+    }
+}
+
+/// Raw values for a single week's durations in business time.
+#[derive(Default)]
+struct BusinessValues {
+    time_to_merge: Vec<u32>,
+    lead_time: Vec<u32>,
+    coding_time: Vec<u32>,
+    review_time: Vec<u32>,
+    draft_time: Vec<u32>,
+    waiting_for_review: Vec<u32>,
+    in_review: Vec<u32>,
+    approval_to_merge: Vec<u32>,
+    sizes: BTreeMap<Size, Vec<u32>>,
+    issue_to_merge: Vec<u32>,
+    ci_wait: Vec<u32>,
+    blocked_on_ci: Vec<u32>,
+}
+
+impl BusinessValues {
+    fn into_business_time(self) -> frontend::BusinessTime {
+        frontend::BusinessTime {
+            time_to_merge: frontend::Distribution::new(&self.time_to_merge),
+            lead_time: frontend::Distribution::new(&self.lead_time),
+            coding_time: frontend::Distribution::new(&self.coding_time),
+            review_time: frontend::Distribution::new(&self.review_time),
+            draft_time: frontend::Distribution::new(&self.draft_time),
+            waiting_for_review: frontend::Distribution::new(&self.waiting_for_review),
+            in_review: frontend::Distribution::new(&self.in_review),
+            approval_to_merge: frontend::Distribution::new(&self.approval_to_merge),
+            issue_to_merge: frontend::Distribution::new(&self.issue_to_merge),
+            ci_wait: frontend::Distribution::new(&self.ci_wait),
+            blocked_on_ci: frontend::Distribution::new(&self.blocked_on_ci),
+        }
+    }
+}
+
+/// Raw values for a single week of deployments.
+struct DeployWeekValues {
+    start_date: String,
+    deployments: u32,
+    lead_time_for_changes: Vec<u32>,
+    business_lead_time_for_changes: Vec<u32>,
+    failed_deployments: u32,
+    time_to_restore: Vec<u32>,
+    business_time_to_restore: Vec<u32>,
+}
+
+impl DeployWeekValues {
+    fn new(start_date: String) -> DeployWeekValues {
+        DeployWeekValues {
+            start_date,
+            deployments: 0,
+            lead_time_for_changes: vec![],
+            business_lead_time_for_changes: vec![],
+            failed_deployments: 0,
+            time_to_restore: vec![],
+            business_time_to_restore: vec![],
+        }
+    }
+
+    fn into_week(self) -> frontend::DeployWeek {
+        frontend::DeployWeek {
+            start_date: self.start_date,
+            deployments: self.deployments,
+            lead_time_for_changes: frontend::Distribution::new(&self.lead_time_for_changes),
+            business_lead_time_for_changes: frontend::Distribution::new(
+                &self.business_lead_time_for_changes,
+            ),
+            failed_deployments: self.failed_deployments,
+            change_failure_rate: if self.deployments == 0 {
+                0.0
+            } else {
+                f64::from(self.failed_deployments) / f64::from(self.deployments)
+            },
+            time_to_restore: frontend::Distribution::new(&self.time_to_restore),
+            business_time_to_restore: frontend::Distribution::new(&self.business_time_to_restore),
+        }
+    }
+}
+
+/// Labels currently applied to PR `id`.
+pub fn current_labels(conn: &Connection, host: &str, id: u32) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT label FROM pr_label WHERE host = ?1 AND pr = ?2 AND removed IS NULL",
+    )?;
+    Ok(collect_query(&mut stmt, params![host, id], |row| {
+        row.get(0)
+    })?)
+}
+
+pub fn remove_label(
+    conn: &Connection,
+    host: &str,
+    id: u32,
+    label: &str,
+    time: &Date,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE pr_label SET removed = ?4
+            WHERE host = ?1 AND pr = ?2 AND label = ?3 AND removed IS NULL",
+        params![host, id, label, time],
+    )?;
+    Ok(())
+}
+
+/// The name of every label in any repo.
+pub fn labels(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT name FROM label ORDER BY name")?;
+    Ok(collect_query(&mut stmt, NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Replace the files PR `id` changes with `paths`. Paths keep the area they
+/// have in any PR to the same repo, paths we haven't seen before have no area
+/// until `area::update_paths` or `area::update_all`.
+pub fn replace_files(conn: &Connection, host: &str, id: u32, paths: &[String]) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT pr_file.area FROM pr_file
+            JOIN pr ON pr.host = pr_file.host AND pr.id = pr_file.pr
+            WHERE pr_file.host = ?1 AND pr_file.path = ?3 AND pr_file.area IS NOT NULL
+                AND pr.repo = (SELECT repo FROM pr WHERE host = ?1 AND id = ?2)
+            LIMIT 1",
+    )?;
+    let mut areas = vec![];
+    for path in paths {
+        let area = collect_query(&mut stmt, params![host, id, path], |row| row.get(0))?;
+        areas.push(area.into_iter().next());
+    }
+
+    conn.execute(
+        "DELETE FROM pr_file WHERE host = ?1 AND pr = ?2",
+        params![host, id],
+    )?;
+    for (path, area) in paths.iter().zip(areas) {
+        data::PrFile {
+            host: host.to_owned(),
+            pr: id,
+            path: path.clone(),
+            area,
+        }
+        .insert_into(conn)?;
+    }
+    Ok(())
+}
+
+/// Replace the issues PR `id` fixes with `numbers`, which are in `repo`.
+pub fn replace_fixed_issues(
+    conn: &Connection,
+    host: &str,
+    id: u32,
+    repo: &str,
+    numbers: &[u32],
+) -> Result<()> {
+    conn.execute(
+        "DELETE FROM pr_issue WHERE host = ?1 AND pr = ?2",
+        params![host, id],
+    )?;
+    for &number in numbers {
+        data::PrIssue {
+            host: host.to_owned(),
+            pr: id,
+            repo: repo.to_owned(),
+            issue: number,
+        }
+        .insert_into(conn)?;
+    }
+    Ok(())
+}
+
+/// Just after the time of the most recent sample of any issue in `repo`, so we
+/// only need to fetch issues updated since (forges treat `since` as inclusive).
+pub fn last_issue_sample(conn: &Connection, host: &str, repo: &str) -> Result<Option<String>> {
+    Ok(conn.query_row(
+        "SELECT strftime('%Y-%m-%dT%H:%M:%SZ', MAX(julianday(issue_sample.time)), '+1 second')
+            FROM issue_sample
+            JOIN issue ON issue.host = issue_sample.host AND issue.id = issue_sample.issue
+            WHERE issue.host = ?1 AND issue.repo = ?2",
+        params![host, repo],
+        |row| row.get(0),
+    )?)
+}
+
+/// Every path changed by any PR to `repo`.
+pub fn changed_paths(conn: &Connection, host: &str, repo: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT path FROM pr_file
+            JOIN pr ON pr.host = pr_file.host AND pr.id = pr_file.pr
+            WHERE pr.host = ?1 AND pr.repo = ?2
+            ORDER BY path",
+    )?;
+    Ok(collect_query(&mut stmt, params![host, repo], |row| {
+        row.get(0)
+    })?)
+}
+
+/// Set the area of `path` in every PR to `repo` which changes it.
+pub fn set_area(
+    conn: &Connection,
+    host: &str,
+    repo: &str,
+    path: &str,
+    area: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE pr_file SET area = ?4
+            WHERE host = ?1 AND path = ?3
+                AND pr IN (SELECT id FROM pr WHERE host = ?1 AND repo = ?2)",
+        params![host, repo, path, area],
+    )?;
+    Ok(())
+}
+
+/// Every area of any PR.
+pub fn areas(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt =
+        conn.prepare("SELECT DISTINCT area FROM pr_file WHERE area IS NOT NULL ORDER BY area")?;
+    Ok(collect_query(&mut stmt, NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Whether PR `id` was a draft in its most recent sample, `None` if there are no
+/// samples.
+pub fn last_sample_draft(conn: &Connection, host: &str, id: u32) -> Result<Option<bool>> {
+    Ok(conn
+        .query_row(
+            "SELECT draft FROM sample
+                WHERE host = ?1 AND pr = ?2
+                ORDER BY time DESC, id DESC
+                LIMIT 1",
+            params![host, id],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Numbers of the PRs in `repo` whose most recent sample is open.
+pub fn open_pr_numbers(conn: &Connection, host: &str, repo: &str) -> Result<Vec<u32>> {
+    let mut stmt = conn.prepare(concat!(
+        "SELECT pr.number FROM pr
+            JOIN sample ON ",
+        latest_sample!(),
+        "
+            WHERE pr.host = ?1 AND pr.repo = ?2 AND sample.status = 'Open'
+            ORDER BY pr.number",
+    ))?;
+    let result = collect_query(&mut stmt, params![host, repo], |row| row.get(0))?;
+    Ok(result)
+}
+
+/// Whether we have already analysed PR `id`'s commits, see `git::analyse`.
+pub fn has_lead_time(conn: &Connection, host: &str, id: u32) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM lead_time WHERE host = ?1 AND pr = ?2",
+        params![host, id],
+        |row| row.get::<_, u32>(0),
+    )? > 0)
+}
+
+/// Merged PRs in `repo` which aren't linked to a deployment yet, oldest first.
+pub fn undeployed_prs(conn: &Connection, host: &str, repo: &str) -> Result<Vec<MergedPr>> {
+    let mut stmt = conn.prepare(concat!(
+        "SELECT pr.id, substr(sample.status, 8) AS merged, sample.merge_commit
+            FROM pr
+            JOIN sample ON sample.host = pr.host AND sample.pr = pr.id
+            WHERE pr.host = ?1 AND pr.repo = ?2
+                AND substr(sample.status, 1, 1) = 'M'
+                AND NOT EXISTS (
+                    SELECT * FROM pr_deployment
+                        WHERE pr_deployment.host = pr.host AND pr_deployment.pr = pr.id
+                )
+                AND ",
+        latest_sample!(),
+        " ORDER BY julianday(merged)",
+    ))?;
+    Ok(collect_query(
+        &mut stmt,
+        params![host, repo],
+        MergedPr::from_query,
+    )?)
+}
+
+/// Deployments of `repo` at or after `time`, oldest first.
+pub fn deployments_since(
+    conn: &Connection,
+    host: &str,
+    repo: &str,
+    time: &Date,
+) -> Result<Vec<Deployment>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, sha, time
+            FROM deployment
+            WHERE host = ?1 AND repo = ?2 AND julianday(time) >= julianday(?3)
+            ORDER BY julianday(time), name",
+    )?;
+    Ok(collect_query(
+        &mut stmt,
+        params![host, repo, time],
+        Deployment::from_query,
+    )?)
+}
+
+/// The deployment PR `id` is linked to, see `dora::link_deployments`.
+pub fn pr_deployment(conn: &Connection, host: &str, id: u32) -> Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT deployment FROM pr_deployment WHERE host = ?1 AND pr = ?2",
+            params![host, id],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// The most recent deployment of `repo` at or before `time`.
+pub fn last_deployment_before(
+    conn: &Connection,
+    host: &str,
+    repo: &str,
+    time: &Date,
+) -> Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT name
+                FROM deployment
+                WHERE host = ?1 AND repo = ?2 AND julianday(time) <= julianday(?3)
+                ORDER BY julianday(time) DESC, name DESC
+                LIMIT 1",
+            params![host, repo, time],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+pub fn read_incidents(conn: &Connection, host: &str, repo: &str) -> Result<Vec<Incident>> {
     let mut stmt = conn.prepare(
-        "SELECT date(sample.time), COUNT(*)
-            FROM sample
-            WHERE sample.status = 'Open'
-            ORDER BY sample.time
-            GROUP BY date(sample.time)",
+        "SELECT number, label, title, body, opened, closed
+            FROM incident
+            WHERE host = ?1 AND repo = ?2
+            ORDER BY number",
     )?;
-
-    let result = collect_query(&mut stmt, NO_PARAMS, |row| {
-        Ok(frontend::Day {
-            date: row.get(0)?,
-            open_prs: row.get(1)?,
-        })
-    })?;
-
-    Ok(result)
+    Ok(collect_query(
+        &mut stmt,
+        params![host, repo],
+        Incident::from_query,
+    )?)
 }
 
-pub fn weekly_stats(conn: &Connection) -> Result<Vec<frontend::Week>> {
-    // TODO
-    // figure out the start date, then iterate over weeks
-    // for every PR compute the most recent sample
-    //   for each week select all 'most recent sample's which occur in that week
-    //   count the status's of those samples to get `Week::merged_prs` and `Week::closed_prs`
-    //   for each merged, lookup the PR and compute time from when it was opened to when it was merged (table a)
-    //                    record the number of review comments (table b)
-    // compute `frontend::Distribution`s from the above tables
-    unimplemented!();
+/// Replace all the failures in `repo` with `failures`.
+pub fn replace_failures(
+    conn: &Connection,
+    host: &str,
+    repo: &str,
+    failures: &[data::Failure],
+) -> Result<()> {
+    conn.execute(
+        "DELETE FROM failure WHERE host = ?1 AND repo = ?2",
+        params![host, repo],
+    )?;
+    for failure in failures {
+        failure.insert_into(conn)?;
+    }
+    Ok(())
 }
 
 pub(crate) trait ConnectionProvider {
@@ -87,11 +1829,15 @@ impl ConnectionProvider for TestConnProvider {
 // FIXME: we could go further and generate the structs and CREATE statements.
 macro_rules! table {
     ($ty: ty, $table: ident, [$($params: ident),*], $create_stmt: expr) => {
+        table!($ty, $table, [$($params),*], $create_stmt, "IGNORE");
+    };
+    ($ty: ty, $table: ident, [$($params: ident),*], $create_stmt: expr, $conflict: expr) => {
         impl $ty {
             pub fn insert_into(&self, conn: &Connection) -> Result<()> {
                 conn.execute_named(
                     &format!(
-                        "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+                        "INSERT OR {} INTO {} ({}) VALUES ({})",
+                        $conflict,
                         stringify!($table),
                         stringify!($($params),*),
                         vec![$(format!(":{}", stringify!($params))),*].join(","),
@@ -102,7 +1848,10 @@ macro_rules! table {
             }
 
             fn init(conn: &Connection) -> Result<()> {
-                conn.execute($create_stmt, NO_PARAMS)?;
+                conn.execute(
+                    &$create_stmt.replacen("CREATE TABLE", "CREATE TABLE IF NOT EXISTS", 1),
+                    NO_PARAMS,
+                )?;
                 Ok(())
             }
         }
@@ -110,35 +1859,44 @@ macro_rules! table {
 }
 
 // If the PR/User already exists in the DB then nothing is inserted and the old value
-// is kept.
+// is kept. IDs are only unique per host, so the host is part of the key.
 table!(
     data::PullRequest,
     pr,
-    [id, number, title, body, author, created, url],
+    [host, id, repo, number, title, body, author, created, url],
     "CREATE TABLE pr (
-        id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        repo TEXT NOT NULL,
         number INTEGER,
         title TEXT NOT NULL,
         body TEXT NOT NULL,
         author INTEGER,
         created TEXT NOT NULL,
-        url TEXT NOT NULL
+        url TEXT NOT NULL,
+        PRIMARY KEY (host, id)
     )"
 );
+// Users are replaced, so that changes to `config::BOTS` take effect.
 table!(
     data::User,
     user,
-    [id, username, url],
+    [host, id, username, url, bot],
     "CREATE TABLE user (
-        id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        id INTEGER NOT NULL,
         username TEXT NOT NULL,
-        url TEXT NOT NULL
-    )"
+        url TEXT NOT NULL,
+        bot INTEGER NOT NULL,
+        PRIMARY KEY (host, id)
+    )",
+    "REPLACE"
 );
 table!(
     data::Sample,
     sample,
     [
+        host,
         pr,
         status,
         time,
@@ -147,10 +1905,15 @@ table!(
         deletions,
         changed_files,
         review_comments,
-        first_commit
+        first_commit,
+        head,
+        merge_commit,
+        draft,
+        milestone
     ],
     "CREATE TABLE sample (
         id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
         pr INTEGER,
         status TEXT NOT NULL,
         time TEXT NOT NULL,
@@ -159,10 +1922,236 @@ table!(
         deletions INTEGER,
         changed_files INTEGER,
         review_comments INTEGER,
-        first_commit TEXT NOT NULL
+        first_commit TEXT NOT NULL,
+        head TEXT NOT NULL,
+        merge_commit TEXT,
+        draft INTEGER NOT NULL,
+        milestone TEXT
+    )"
+);
+table!(
+    data::LeadTime,
+    lead_time,
+    [host, pr, first_authored, merged, rebases, force_pushes],
+    "CREATE TABLE lead_time (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        first_authored TEXT NOT NULL,
+        merged TEXT NOT NULL,
+        rebases INTEGER,
+        force_pushes INTEGER,
+        PRIMARY KEY (host, pr)
+    )"
+);
+
+table!(
+    data::Deployment,
+    deployment,
+    [host, repo, name, sha, time],
+    "CREATE TABLE deployment (
+        host TEXT NOT NULL,
+        repo TEXT NOT NULL,
+        name TEXT NOT NULL,
+        sha TEXT NOT NULL,
+        time TEXT NOT NULL,
+        PRIMARY KEY (host, repo, name)
+    )"
+);
+table!(
+    data::PrDeployment,
+    pr_deployment,
+    [host, pr, repo, deployment],
+    "CREATE TABLE pr_deployment (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        repo TEXT NOT NULL,
+        deployment TEXT NOT NULL,
+        PRIMARY KEY (host, pr)
+    )"
+);
+
+// Labels are inserted every time they are seen, so keep the latest color.
+table!(
+    data::Label,
+    label,
+    [host, repo, name, color],
+    "CREATE TABLE label (
+        host TEXT NOT NULL,
+        repo TEXT NOT NULL,
+        name TEXT NOT NULL,
+        color TEXT NOT NULL,
+        PRIMARY KEY (host, repo, name)
+    )",
+    "REPLACE"
+);
+table!(
+    data::PrLabel,
+    pr_label,
+    [host, pr, label, added, removed],
+    "CREATE TABLE pr_label (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        label TEXT NOT NULL,
+        added TEXT NOT NULL,
+        removed TEXT,
+        PRIMARY KEY (host, pr, label, added)
+    )"
+);
+table!(
+    data::PrFile,
+    pr_file,
+    [host, pr, path, area],
+    "CREATE TABLE pr_file (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        area TEXT,
+        PRIMARY KEY (host, pr, path)
+    )"
+);
+table!(
+    data::Issue,
+    issue,
+    [host, id, repo, number, title, body, created, url],
+    "CREATE TABLE issue (
+        host TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        repo TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        created TEXT NOT NULL,
+        url TEXT NOT NULL,
+        PRIMARY KEY (host, id)
+    )"
+);
+table!(
+    data::IssueSample,
+    issue_sample,
+    [host, issue, time, closed],
+    "CREATE TABLE issue_sample (
+        id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        issue INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        closed TEXT,
+        UNIQUE (host, issue, time)
+    )"
+);
+table!(
+    data::PrIssue,
+    pr_issue,
+    [host, pr, repo, issue],
+    "CREATE TABLE pr_issue (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        repo TEXT NOT NULL,
+        issue INTEGER NOT NULL,
+        PRIMARY KEY (host, pr, repo, issue)
+    )"
+);
+table!(
+    data::CheckRun,
+    check_run,
+    [host, pr, sha, name, started, finished, conclusion],
+    "CREATE TABLE check_run (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        sha TEXT NOT NULL,
+        name TEXT NOT NULL,
+        started TEXT NOT NULL,
+        finished TEXT,
+        conclusion TEXT,
+        PRIMARY KEY (host, pr, sha, name, started)
+    )",
+    "REPLACE"
+);
+table!(
+    data::Review,
+    review,
+    [host, pr, reviewer, approved, submitted],
+    "CREATE TABLE review (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        reviewer TEXT NOT NULL,
+        approved INTEGER NOT NULL,
+        submitted TEXT NOT NULL,
+        PRIMARY KEY (host, pr, reviewer, submitted)
+    )"
+);
+table!(
+    data::DraftEvent,
+    draft_event,
+    [host, pr, draft, time],
+    "CREATE TABLE draft_event (
+        host TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        draft INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        PRIMARY KEY (host, pr, draft, time)
+    )"
+);
+// Incidents are inserted again when they change (e.g., when closed), so the old
+// value is replaced.
+table!(
+    data::Incident,
+    incident,
+    [host, repo, number, label, title, body, opened, closed],
+    "CREATE TABLE incident (
+        host TEXT NOT NULL,
+        repo TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        label TEXT NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        opened TEXT NOT NULL,
+        closed TEXT,
+        PRIMARY KEY (host, repo, number)
+    )",
+    "REPLACE"
+);
+table!(
+    data::Failure,
+    failure,
+    [
+        host,
+        repo,
+        source,
+        started,
+        restored,
+        cause_pr,
+        cause_deployment
+    ],
+    "CREATE TABLE failure (
+        host TEXT NOT NULL,
+        repo TEXT NOT NULL,
+        source TEXT NOT NULL,
+        started TEXT NOT NULL,
+        restored TEXT,
+        cause_pr INTEGER,
+        cause_deployment TEXT,
+        PRIMARY KEY (host, repo, source)
+    )"
+);
+table!(
+    data::SentAlert,
+    sent_alert,
+    [series, week, kind, sink],
+    "CREATE TABLE sent_alert (
+        series TEXT NOT NULL,
+        week TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        sink TEXT NOT NULL,
+        PRIMARY KEY (series, week, kind, sink)
     )"
 );
 
+impl ToSql for data::Issue {
+    fn to_sql(&self) -> rusqlite::Result<types::ToSqlOutput> {
+        self.id.to_sql()
+    }
+}
+
 impl ToSql for data::PullRequest {
     fn to_sql(&self) -> rusqlite::Result<types::ToSqlOutput> {
         self.id.to_sql()
@@ -237,39 +2226,63 @@ impl FromSql for Status {
 struct PrReader<'conn> {
     stmt: Statement<'conn>,
     stmt_samples: Statement<'conn>,
+    stmt_labels: Statement<'conn>,
 }
 
 impl<'conn> PrReader<'conn> {
     fn init(conn: &'conn Connection) -> Result<Self> {
-        let stmt = conn.prepare(
-            "SELECT pr.id, pr.number, pr.title, pr.body, user.username, user.url AS user_url, pr.created, pr.url
+        let stmt = conn.prepare(concat!(
+            "SELECT pr.id, pr.host, pr.repo, pr.number, pr.title, pr.body, user.username, user.url AS user_url, user.bot, pr.created, pr.url
                 FROM pr, user
-                WHERE pr.author = user.id
-                ORDER BY pr.number"
-        )?;
+                WHERE pr.author = user.id AND pr.host = user.host
+                    AND datetime(pr.created) < datetime(?2)
+                    AND NOT EXISTS (
+                        SELECT * FROM sample
+                            WHERE ",
+            latest_sample!(),
+            " AND sample.status != 'Open'
+                                AND datetime(substr(sample.status, 8)) < datetime(?1)
+                    )
+                ORDER BY pr.host, pr.number"
+        ))?;
         let stmt_samples = conn.prepare(
-            "SELECT time, status, commits, additions, changed_files, review_comments, first_commit
+            "SELECT time, status, commits, additions, deletions, changed_files, review_comments, first_commit, head, merge_commit, draft, milestone
                 FROM sample
-                WHERE sample.pr = ?1",
+                WHERE sample.pr = ?1 AND sample.host = ?2
+                ORDER BY time",
+        )?;
+        let stmt_labels = conn.prepare(
+            "SELECT label FROM pr_label
+                WHERE pr = ?1 AND host = ?2 AND removed IS NULL
+                ORDER BY label",
         )?;
 
-        Ok(PrReader { stmt, stmt_samples })
+        Ok(PrReader {
+            stmt,
+            stmt_samples,
+            stmt_labels,
+        })
     }
 
-    // TODO use range
-    fn read(self, _times: Range<Date>) -> Result<Vec<PullRequest>> {
+    fn read(self, times: Range<Date>) -> Result<Vec<PullRequest>> {
         let PrReader {
             mut stmt,
             mut stmt_samples,
+            mut stmt_labels,
         } = self;
 
-        let result = collect_query(&mut stmt, NO_PARAMS, |row| {
+        let result = collect_query(&mut stmt, params![times.start, times.end], |row| {
             let mut pr = PullRequest::from_query(row)?;
             pr.samples = collect_query(
                 &mut stmt_samples,
-                params![row.get::<_, u32>(0)?],
+                params![row.get::<_, u32>(0)?, row.get::<_, String>(1)?],
                 Sample::from_query,
             )?;
+            pr.labels = collect_query(
+                &mut stmt_labels,
+                params![row.get::<_, u32>(0)?, row.get::<_, String>(1)?],
+                |row| row.get(0),
+            )?;
             pr.author = User::from_query(row)?;
             pr.author.url = row.get("user_url")?;
             Ok(pr)
@@ -303,19 +2316,25 @@ macro_rules! from_query {
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct PullRequest {
+    pub host: String,
+    pub id: u32,
+    pub repo: String,
     pub number: u32,
     pub title: String,
     pub body: String,
     pub author: User,
     pub created: Date,
     pub url: String,
+    /// Labels which haven't been removed.
+    pub labels: Vec<String>,
     pub samples: Vec<Sample>,
 }
 
 from_query!(
     PullRequest,
-    [number, title, body, created, url],
+    [host, id, repo, number, title, body, created, url],
     author: User::default(),
+    labels: vec![],
     samples: vec![]
 );
 
@@ -323,9 +2342,10 @@ from_query!(
 pub struct User {
     pub username: String,
     pub url: String,
+    pub bot: bool,
 }
 
-from_query!(User, [username], url: String::new());
+from_query!(User, [username, bot], url: String::new());
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Sample {
@@ -337,6 +2357,10 @@ pub struct Sample {
     pub changed_files: u32,
     pub review_comments: u32,
     pub first_commit: Sha,
+    pub head: Sha,
+    pub merge_commit: Option<Sha>,
+    pub draft: bool,
+    pub milestone: Option<String>,
 }
 
 from_query!(
@@ -349,10 +2373,44 @@ from_query!(
         deletions,
         changed_files,
         review_comments,
-        first_commit
+        first_commit,
+        head,
+        merge_commit,
+        draft,
+        milestone
     ],
 );
 
+#[derive(Debug, Eq, PartialEq)]
+pub struct MergedPr {
+    pub id: u32,
+    pub merged: Date,
+    pub merge_commit: Option<Sha>,
+}
+
+from_query!(MergedPr, [id, merged, merge_commit],);
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Deployment {
+    pub name: String,
+    pub sha: Sha,
+    pub time: Date,
+}
+
+from_query!(Deployment, [name, sha, time],);
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Incident {
+    pub number: u32,
+    pub label: String,
+    pub title: String,
+    pub body: String,
+    pub opened: Date,
+    pub closed: Option<Date>,
+}
+
+from_query!(Incident, [number, label, title, body, opened, closed],);
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -375,20 +2433,28 @@ mod test {
 
     macro_rules! pr {
         ($name: ident, $id: expr, $title: expr, $created: expr, $url: expr) => {
+            pr!($name, "https://api.github.com", $id, $title, $created, $url);
+        };
+        ($name: ident, $host: expr, $id: expr, $title: expr, $created: expr, $url: expr) => {
             impl data::PullRequest {
                 fn $name() -> data::PullRequest {
                     data::PullRequest {
+                        host: $host.to_owned(),
                         id: $id,
+                        repo: "nrc/gh-velocity".to_owned(),
                         number: 100 + $id,
                         title: $title.to_owned(),
                         body: format!("Body of {}", $title),
                         author: data::User {
+                            host: $host.to_owned(),
                             id: 42,
                             username: "bob".to_owned(),
                             url: "https://bob".to_owned(),
+                            bot: false,
                         },
                         created: $created,
                         url: $url.to_owned(),
+                        labels: vec![],
                     }
                 }
             }
@@ -396,15 +2462,20 @@ mod test {
             impl PullRequest {
                 fn $name() -> PullRequest {
                     PullRequest {
+                        host: $host.to_owned(),
+                        id: $id,
+                        repo: "nrc/gh-velocity".to_owned(),
                         number: 100 + $id,
                         title: $title.to_owned(),
                         body: format!("Body of {}", $title),
                         author: User {
                             username: "bob".to_owned(),
                             url: "https://bob".to_owned(),
+                            bot: false,
                         },
                         created: $created,
                         url: $url.to_owned(),
+                        labels: vec![],
                         samples: vec![],
                     }
                 }
@@ -416,6 +2487,14 @@ mod test {
     date!(date2, "2019-05-14 09:15:13");
     pr!(pr0, 1, "PR number 0", date1(), "https://pr0");
     pr!(pr1, 2, "PR number 1", date2(), "https://pr1");
+    pr!(
+        pr_ghe,
+        "https://github.example.com/api/v3",
+        1,
+        "PR number 0 on GHE",
+        date2(),
+        "https://pr_ghe"
+    );
 
     #[test]
     fn insert_and_read() -> Result<()> {
@@ -435,11 +2514,834 @@ mod test {
             1
         );
 
-        let prs = read_prs(&conn, date1()..date1())?;
+        let prs = read_prs(&conn, all_time())?;
         assert_eq!(prs.len(), 2);
 
         assert_eq!(prs[0], PullRequest::pr0());
         assert_eq!(prs[1], PullRequest::pr1());
+
+        // pr0 was opened at the end of the range.
+        let prs = read_prs(&conn, date2()..date1())?;
+        assert_eq!(prs, vec![PullRequest::pr1()]);
+        Ok(())
+    }
+
+    #[test]
+    fn ids_per_host() -> Result<()> {
+        let conn = init_connection()?;
+        let prs = &[data::PullRequest::pr0(), data::PullRequest::pr_ghe()];
+        for pr in prs {
+            pr.insert_into(&conn)?;
+            pr.author.insert_into(&conn)?;
+        }
+        assert_eq!(
+            conn.query_row("SELECT COUNT(*) FROM user", NO_PARAMS, |r| r
+                .get::<_, u32>(0))?,
+            2
+        );
+
+        let prs = read_prs(&conn, all_time())?;
+        assert_eq!(prs.len(), 2);
+
+        assert_eq!(prs[0], PullRequest::pr0());
+        assert_eq!(prs[1], PullRequest::pr_ghe());
+        Ok(())
+    }
+
+    #[test]
+    fn read_prs_in_range() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-16 09:25:34";
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            sample(data::PullRequest::pr1(), merged, Status::Open),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+        let ids = |start: &str, end: &str| -> Result<Vec<u32>> {
+            let range = Date::new(start.to_owned())..Date::new(end.to_owned());
+            Ok(read_prs(&conn, range)?
+                .into_iter()
+                .map(|pr| pr.id)
+                .collect())
+        };
+
+        assert_eq!(ids("2019-05-01", "2019-05-15")?, vec![2]);
+        assert_eq!(ids("2019-05-16", "2019-05-17")?, vec![1, 2]);
+        // pr0 was merged before the range started, but pr1 is still open.
+        assert_eq!(ids("2019-05-17", "2019-06-01")?, vec![2]);
+
+        assert_eq!(
+            open_pr_numbers(&conn, "https://api.github.com", "nrc/gh-velocity")?,
+            vec![102]
+        );
+        Ok(())
+    }
+
+    fn sample(pr: data::PullRequest, time: &str, status: Status) -> data::Sample {
+        data::Sample {
+            host: pr.host.clone(),
+            time: Date::new(time.to_owned()),
+            pr,
+            status,
+            commits: 1,
+            additions: 10,
+            deletions: 5,
+            changed_files: 2,
+            review_comments: 3,
+            first_commit: Sha("abc".to_owned()),
+            head: Sha("def".to_owned()),
+            merge_commit: None,
+            draft: false,
+            milestone: None,
+            files: vec![],
+        }
+    }
+
+    #[test]
+    fn test_label_history() -> Result<()> {
+        let conn = init_connection()?;
+        let label = |name: &str| data::Label {
+            host: "https://api.github.com".to_owned(),
+            repo: "nrc/gh-velocity".to_owned(),
+            name: name.to_owned(),
+            color: "ee0701".to_owned(),
+        };
+        let merged = "2019-05-16 09:25:34";
+        let mut samples = vec![
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-15 10:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-15 11:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+        ];
+        samples[0].pr.labels = vec![label("bug"), label("p1")];
+        samples[1].pr.labels = vec![label("bug")];
+        samples[2].pr.labels = vec![label("bug"), label("p1")];
+        for s in &samples {
+            crate::source::record_sample(&conn, s)?;
+        }
+
+        let history: Vec<(String, String, Option<String>)> = collect_query(
+            &mut conn
+                .prepare("SELECT label, added, removed FROM pr_label ORDER BY label, added")?,
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(
+            history,
+            vec![
+                ("bug".to_owned(), "2019-05-15 10:00:00".to_owned(), None),
+                (
+                    "p1".to_owned(),
+                    "2019-05-15 10:00:00".to_owned(),
+                    Some("2019-05-15 11:00:00".to_owned())
+                ),
+                ("p1".to_owned(), merged.to_owned(), None),
+            ]
+        );
+        assert_eq!(labels(&conn)?, vec!["bug".to_owned(), "p1".to_owned()]);
+
+        let prs = read_prs(&conn, all_time())?;
+        assert_eq!(prs[0].labels, vec!["bug".to_owned(), "p1".to_owned()]);
+
+        let bugs = PrFilter {
+            label: Some("bug".to_owned()),
+            ..PrFilter::default()
+        };
+        let weeks = weekly_stats(&conn, &bugs)?;
+        assert_eq!(weeks.len(), 1);
+        assert_eq!(weeks[0].merged_prs, 1);
+
+        // Labels count as of the time being aggregated: `p1` was removed on the
+        // 15th and added back when merged, `shipped` only after merging.
+        let mut shipped = sample(
+            data::PullRequest::pr0(),
+            "2019-05-17 10:00:00",
+            Status::Merged(Date::new(merged.to_owned())),
+        );
+        shipped.pr.labels = vec![label("bug"), label("p1"), label("shipped")];
+        crate::source::record_sample(&conn, &shipped)?;
+        let filter = |label: &str| PrFilter {
+            label: Some(label.to_owned()),
+            ..PrFilter::default()
+        };
+        assert_eq!(weekly_stats(&conn, &filter("p1"))?[0].merged_prs, 1);
+        assert!(weekly_stats(&conn, &filter("shipped"))?.is_empty());
+        let open: Vec<_> = open_prs_per_day(&conn, &filter("p1"))?
+            .into_iter()
+            .map(|d| (d.date, d.open_prs))
+            .collect();
+        assert_eq!(
+            open,
+            vec![
+                ("2019-05-15".to_owned(), 0),
+                ("2019-05-16".to_owned(), 0),
+                ("2019-05-17".to_owned(), 0),
+            ]
+        );
+        let open: Vec<_> = open_prs_per_day(&conn, &bugs)?
+            .into_iter()
+            .map(|d| d.open_prs)
+            .collect();
+        assert_eq!(open, vec![1, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_prs_per_day() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-16 09:25:34";
+        let mut draft = sample(
+            data::PullRequest::pr1(),
+            "2019-05-14 09:15:13",
+            Status::Open,
+        );
+        draft.draft = true;
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-15 10:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            draft,
+            sample(
+                data::PullRequest::pr1(),
+                "2019-05-16 00:00:00",
+                Status::Open,
+            ),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+
+        let days: Vec<_> = open_prs_per_day(&conn, &PrFilter::default())?
+            .into_iter()
+            .map(|d| (d.date, d.open_prs, d.draft_prs))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("2019-05-14".to_owned(), 0, 1),
+                ("2019-05-15".to_owned(), 1, 1),
+                ("2019-05-16".to_owned(), 1, 0),
+            ]
+        );
+
+        // The 16th is the last day of its week which we have a sample from.
+        let weeks: Vec<_> = open_prs_per_bucket(&conn, &PrFilter::default(), Granularity::Week)?
+            .into_iter()
+            .map(|d| (d.date, d.open_prs, d.draft_prs))
+            .collect();
+        assert_eq!(weeks, vec![("2019-05-16".to_owned(), 1, 0)]);
+
+        // An issue we only sampled after it was closed, twice.
+        let issue = data::IssueSample {
+            host: "https://api.github.com".to_owned(),
+            time: Date::new("2019-05-16T08:00:00Z".to_owned()),
+            issue: data::Issue {
+                host: "https://api.github.com".to_owned(),
+                id: 7,
+                repo: "nrc/gh-velocity".to_owned(),
+                number: 3,
+                title: "Broken".to_owned(),
+                body: String::new(),
+                created: Date::new("2019-05-14T08:00:00Z".to_owned()),
+                url: String::new(),
+            },
+            closed: Some(Date::new("2019-05-15T12:00:00Z".to_owned())),
+        };
+        for _ in 0..2 {
+            issue.issue.insert_into(&conn)?;
+            issue.insert_into(&conn)?;
+        }
+        let issues: Vec<_> = open_prs_per_day(&conn, &PrFilter::default())?
+            .into_iter()
+            .map(|d| d.open_issues)
+            .collect();
+        assert_eq!(issues, vec![1, 0, 0]);
+        assert_eq!(
+            last_issue_sample(&conn, "https://api.github.com", "nrc/gh-velocity")?,
+            Some("2019-05-16T08:00:01Z".to_owned())
+        );
+        let samples: u32 =
+            conn.query_row("SELECT COUNT(*) FROM issue_sample", NO_PARAMS, |row| {
+                row.get(0)
+            })?;
+        assert_eq!(samples, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> Result<()> {
+        let conn = init_connection()?;
+        // Already up to date.
+        migrate(&conn)?;
+
+        conn.execute_batch(
+            "DROP TABLE issue_sample;
+            CREATE TABLE issue_sample (
+                id INTEGER PRIMARY KEY,
+                host TEXT NOT NULL,
+                issue INTEGER NOT NULL,
+                time TEXT NOT NULL,
+                closed TEXT
+            );
+            INSERT INTO issue_sample (host, issue, time) VALUES ('h', 1, 't'), ('h', 1, 't');
+            PRAGMA user_version = 0;",
+        )?;
+        migrate(&conn)?;
+        let samples: u32 =
+            conn.query_row("SELECT COUNT(*) FROM issue_sample", NO_PARAMS, |row| {
+                row.get(0)
+            })?;
+        assert_eq!(samples, 1);
+        assert!(conn
+            .execute(
+                "INSERT INTO issue_sample (host, issue, time) VALUES ('h', 1, 't')",
+                NO_PARAMS
+            )
+            .is_err());
+
+        // From before labels had a history or sent alerts had a sink.
+        data::PullRequest::pr0().insert_into(&conn)?;
+        conn.execute_batch(
+            "DROP TABLE pr_label;
+            CREATE TABLE pr_label (
+                host TEXT NOT NULL,
+                pr INTEGER NOT NULL,
+                label TEXT NOT NULL,
+                PRIMARY KEY (host, pr, label)
+            );
+            INSERT INTO pr_label VALUES ('https://api.github.com', 1, 'bug');
+            DROP TABLE sent_alert;
+            CREATE TABLE sent_alert (
+                series TEXT NOT NULL,
+                week TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                PRIMARY KEY (series, week, kind)
+            );
+            DROP TABLE failure;
+            PRAGMA user_version = 0;",
+        )?;
+        migrate(&conn)?;
+        let label: (String, String, Option<String>) = conn.query_row(
+            "SELECT label, added, removed FROM pr_label",
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(label, ("bug".to_owned(), date1().date, None));
+        assert!(has_column(&conn, "sent_alert", "sink")?);
+        assert!(has_column(&conn, "failure", "repo")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_baseline() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE pr (
+                id INTEGER PRIMARY KEY,
+                number INTEGER,
+                title TEXT NOT NULL,
+                body TEXT NOT NULL,
+                author INTEGER,
+                created TEXT NOT NULL,
+                url TEXT NOT NULL
+            );
+            CREATE TABLE user (
+                id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL,
+                url TEXT NOT NULL
+            );
+            CREATE TABLE sample (
+                id INTEGER PRIMARY KEY,
+                pr INTEGER,
+                status TEXT NOT NULL,
+                time TEXT NOT NULL,
+                commits INTEGER,
+                additions INTEGER,
+                deletions INTEGER,
+                changed_files INTEGER,
+                review_comments INTEGER,
+                first_commit TEXT NOT NULL
+            );
+            INSERT INTO pr VALUES (1, 101, 'Fix', '', 42, '2019-05-14 09:15:13',
+                'https://github.com/nrc/gh-velocity/pull/101');
+            INSERT INTO user VALUES (42, 'bob', 'https://bob'), (43, 'dependabot[bot]', '');
+            INSERT INTO sample VALUES
+                (1, 1, 'Open', '2019-05-15 09:00:00', 1, 10, 2, 1, 0, 'abc'),
+                (2, 1, 'Merged 2019-05-16 09:15:13', '2019-05-16 10:00:00', 1, 10, 2, 1, 3, 'abc');",
+        )?;
+        migrate(&conn)?;
+
+        let prs = read_prs(&conn, all_time())?;
+        assert_eq!(prs.len(), 1);
+        assert_eq!(
+            (
+                &*prs[0].host,
+                prs[0].id,
+                &*prs[0].repo,
+                &*prs[0].author.username
+            ),
+            (GITHUB_API, 1, "nrc/gh-velocity", "bob")
+        );
+        assert_eq!(prs[0].samples.len(), 2);
+        let weeks = weekly_stats(&conn, &PrFilter::default())?;
+        assert_eq!((weeks.len(), weeks[0].merged_prs), (1, 1));
+        assert_eq!(weeks[0].time_to_merge.mean, 2880);
+        let bots: Vec<(String, bool)> = collect_query(
+            &mut conn.prepare("SELECT username, bot FROM user ORDER BY id")?,
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!(
+            bots,
+            vec![
+                ("bob".to_owned(), false),
+                ("dependabot[bot]".to_owned(), true)
+            ]
+        );
+        // Tables added since are created.
+        assert!(has_column(&conn, "pr_label", "added")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cumulative_flow() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-17 09:25:34";
+        let closed = "2019-05-16 12:00:00";
+        let mut draft = sample(
+            data::PullRequest::pr1(),
+            "2019-05-14 09:15:13",
+            Status::Open,
+        );
+        draft.draft = true;
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-14 10:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            draft,
+            sample(
+                data::PullRequest::pr1(),
+                "2019-05-15 00:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr1(),
+                closed,
+                Status::Closed(Date::new(closed.to_owned())),
+            ),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+        let review = |reviewer: &str, approved, submitted: &str| data::Review {
+            host: "https://api.github.com".to_owned(),
+            pr: 1,
+            reviewer: reviewer.to_owned(),
+            approved,
+            submitted: Date::new(submitted.to_owned()),
+        };
+        // The author's own review doesn't count.
+        review("bob", false, "2019-05-14 11:00:00").insert_into(&conn)?;
+        review("alice", false, "2019-05-15 10:00:00").insert_into(&conn)?;
+        review("alice", true, "2019-05-16 08:00:00").insert_into(&conn)?;
+
+        let days: Vec<_> = cumulative_flow(&conn, &PrFilter::default())?
+            .into_iter()
+            .map(|d| {
+                (
+                    d.date,
+                    [
+                        d.draft,
+                        d.awaiting_review,
+                        d.in_review,
+                        d.approved,
+                        d.merged,
+                        d.closed,
+                    ],
+                )
+            })
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("2019-05-14".to_owned(), [1, 1, 0, 0, 0, 0]),
+                ("2019-05-15".to_owned(), [0, 1, 1, 0, 0, 0]),
+                ("2019-05-16".to_owned(), [0, 0, 0, 1, 0, 1]),
+                ("2019-05-17".to_owned(), [0, 0, 0, 0, 1, 1]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cohorts() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-16 09:00:00";
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            sample(data::PullRequest::pr1(), merged, Status::Open),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+
+        // Both were opened in the week of the 13th, which ended on the 20th, so
+        // as of the 25th we can't know how many will be merged within a week.
+        let cohorts = cohorts(&conn, &PrFilter::default(), "2019-05-25 00:00:00")?;
+        assert_eq!(cohorts.len(), 1);
+        assert_eq!(cohorts[0].start_date, "2019-05-13");
+        assert_eq!(cohorts[0].opened_prs, 2);
+        let merged_within: Vec<_> = cohorts[0]
+            .merged_within
+            .iter()
+            .map(|m| (m.days, m.merged_prs, m.fraction))
+            .collect();
+        assert_eq!(
+            merged_within,
+            vec![
+                (1, 1, Some(0.5)),
+                (3, 1, Some(0.5)),
+                (7, 1, None),
+                (14, 1, None),
+            ]
+        );
+
+        // The open PR is only labelled after the 25th.
+        conn.execute(
+            "INSERT INTO pr_label VALUES (?1, ?2, 'bug', '2019-05-27 00:00:00', NULL)",
+            params![data::PullRequest::pr1().host, data::PullRequest::pr1().id],
+        )?;
+        let bugs = PrFilter {
+            label: Some("bug".to_owned()),
+            ..PrFilter::default()
+        };
+        assert!(super::cohorts(&conn, &bugs, "2019-05-25 00:00:00")?.is_empty());
+        assert_eq!(
+            super::cohorts(&conn, &bugs, "2019-05-28 00:00:00")?.len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_prs() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-16 09:25:34";
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-15 10:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            sample(
+                data::PullRequest::pr1(),
+                "2019-05-20 10:00:00",
+                Status::Open,
+            ),
+            // Pushed to or commented on after the review.
+            sample(
+                data::PullRequest::pr1(),
+                "2019-05-20 18:00:00",
+                Status::Open,
+            ),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+        data::Review {
+            host: "https://api.github.com".to_owned(),
+            pr: 2,
+            reviewer: "alice".to_owned(),
+            approved: false,
+            submitted: Date::new("2019-05-20 12:00:00".to_owned()),
+        }
+        .insert_into(&conn)?;
+
+        assert_eq!(times_to_merge(&conn)?, vec![1440]);
+        let prs = open_prs(&conn, "2019-05-21 00:00:00")?;
+        assert_eq!(prs.len(), 1);
+        let pr = &prs[0];
+        assert_eq!(pr.url, "https://pr1");
+        assert!(!pr.draft);
+        assert!(pr.reviewed);
+        assert!(!pr.approved);
+        // From 2019-05-14 09:15:13.
+        assert_eq!(WallClock.minutes(pr.created, pr.now), 6 * 1440 + 885);
+        assert_eq!(WallClock.minutes(pr.last_active, pr.now), 6 * 60);
+
+        let open = open_pr_times(&conn, "2019-05-21 00:00:00")?;
+        assert_eq!(open.len(), 1);
+        assert_eq!(
+            (&*open[0].url, &*open[0].week),
+            ("https://pr1", "2019-05-20")
+        );
+        assert_eq!(
+            (open[0].waiting_for_review, open[0].time_to_merge),
+            (6 * 1440 + 165, 6 * 1440 + 885)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_weekly_stats() -> Result<()> {
+        let conn = init_connection()?;
+        let merged = "2019-05-16 09:25:34";
+        let closed = "2019-05-29 00:00:00";
+        let samples = &[
+            sample(
+                data::PullRequest::pr0(),
+                "2019-05-15 10:00:00",
+                Status::Open,
+            ),
+            sample(
+                data::PullRequest::pr0(),
+                merged,
+                Status::Merged(Date::new(merged.to_owned())),
+            ),
+            sample(
+                data::PullRequest::pr1(),
+                closed,
+                Status::Closed(Date::new(closed.to_owned())),
+            ),
+        ];
+        for s in samples {
+            s.pr.author.insert_into(&conn)?;
+            s.pr.insert_into(&conn)?;
+            s.insert_into(&conn)?;
+        }
+        data::LeadTime {
+            host: "https://api.github.com".to_owned(),
+            pr: 1,
+            first_authored: Date::new("2019-05-15 08:25:34".to_owned()),
+            merged: Date::new(merged.to_owned()),
+            rebases: 0,
+            force_pushes: 0,
+        }
+        .insert_into(&conn)?;
+        let review = |reviewer: &str, approved, submitted: &str| data::Review {
+            host: "https://api.github.com".to_owned(),
+            pr: 1,
+            reviewer: reviewer.to_owned(),
+            approved,
+            submitted: Date::new(submitted.to_owned()),
+        };
+        review("bob", false, "2019-05-15 10:25:34").insert_into(&conn)?;
+        review("alice", false, "2019-05-15 11:25:34").insert_into(&conn)?;
+        review("alice", true, "2019-05-15 21:25:34").insert_into(&conn)?;
+        data::DraftEvent {
+            host: "https://api.github.com".to_owned(),
+            pr: 1,
+            draft: false,
+            time: Date::new("2019-05-15 10:25:34".to_owned()),
+        }
+        .insert_into(&conn)?;
+        let check = |started: &str, finished: &str, conclusion: &str| data::CheckRun {
+            host: "https://api.github.com".to_owned(),
+            pr: 1,
+            sha: Sha("def".to_owned()),
+            name: "test".to_owned(),
+            started: Date::new(started.to_owned()),
+            finished: Some(Date::new(finished.to_owned())),
+            conclusion: Some(conclusion.to_owned()),
+        };
+        check("2019-05-15 10:30:34", "2019-05-15 10:40:34", "failure").insert_into(&conn)?;
+        check("2019-05-15 10:40:34", "2019-05-15 10:55:34", "success").insert_into(&conn)?;
+        check("2019-05-15 11:00:34", "2019-05-15 11:01:34", "other").insert_into(&conn)?;
+        // Concluded without a completion time: ignored rather than an error.
+        data::CheckRun {
+            finished: None,
+            ..check("2019-05-15 11:10:34", "2019-05-15 11:10:34", "success")
+        }
+        .insert_into(&conn)?;
+        data::Issue {
+            host: "https://api.github.com".to_owned(),
+            id: 99,
+            repo: "nrc/gh-velocity".to_owned(),
+            number: 7,
+            title: "Broken".to_owned(),
+            body: String::new(),
+            created: Date::new("2019-05-15 08:25:34".to_owned()),
+            url: String::new(),
+        }
+        .insert_into(&conn)?;
+        replace_fixed_issues(
+            &conn,
+            "https://api.github.com",
+            1,
+            "nrc/gh-velocity",
+            &[7, 8],
+        )?;
+
+        let weeks = weekly_stats(&conn, &PrFilter::default())?;
+        assert_eq!(weeks.len(), 3);
+        assert_eq!(weeks[0].start_date, "2019-05-13");
+        assert_eq!(weeks[0].merged_prs, 1);
+        assert_eq!(weeks[0].closed_prs, 0);
+        assert_eq!(weeks[0].time_to_merge.mean, 1380);
+        assert_eq!(weeks[0].review_comments.max, 3);
+        assert_eq!(weeks[0].lead_time.mean, 1500);
+        assert_eq!(weeks[0].coding_time.mean, 60);
+        assert_eq!(weeks[0].review_time.mean, 1380);
+        assert_eq!(weeks[0].draft_time.mean, 60);
+        assert_eq!(weeks[0].waiting_for_review.mean, 60);
+        assert_eq!(weeks[0].in_review.mean, 600);
+        assert_eq!(weeks[0].approval_to_merge.mean, 720);
+        assert_eq!(weeks[0].issue_to_merge.mean, 1500);
+        assert_eq!(weeks[0].issue_to_merge.max, 1500);
+        assert_eq!(weeks[0].ci_wait.mean, 25);
+        assert_eq!(weeks[0].blocked_on_ci.mean, 15);
+        assert_eq!(weeks[0].ci_runs, 2);
+        assert_eq!(weeks[0].ci_failure_rate, 0.5);
+        assert_eq!((weeks[0].ci_reruns, weeks[0].flaky_checks), (1, 1));
+        // Opened on Wednesday morning and merged on Thursday morning, with
+        // `config::BUSINESS_HOURS` of 9 to 5.
+        let business = &weeks[0].business;
+        assert_eq!(business.time_to_merge.mean, 420);
+        assert_eq!(business.draft_time.mean, 60);
+        assert_eq!(business.waiting_for_review.mean, 60);
+        assert_eq!(business.in_review.mean, 334);
+        assert_eq!(business.approval_to_merge.mean, 26);
+        assert_eq!(business.issue_to_merge.mean, 506);
+
+        // Merged on the 16th and closed on the 29th.
+        let months = stats(&conn, &PrFilter::default(), Granularity::Month)?;
+        assert_eq!(months.len(), 1);
+        assert_eq!(months[0].start_date, "2019-05-01");
+        assert_eq!((months[0].merged_prs, months[0].closed_prs), (1, 1));
+        assert_eq!(months[0].time_to_merge.mean, 1380);
+        let days = stats(&conn, &PrFilter::default(), Granularity::Day)?;
+        assert_eq!(days.len(), 14);
+        assert_eq!(days[13].start_date, "2019-05-29");
+
+        // Quiet weeks after the last merge or close are included.
+        let through = weekly_stats_through(&conn, &PrFilter::default(), "2019-06-10")?;
+        assert_eq!(through.len(), 5);
+        assert_eq!(through[4].start_date, "2019-06-10");
+        assert_eq!((through[4].merged_prs, through[4].closed_prs), (0, 0));
+        let through = weekly_stats_through(&conn, &PrFilter::default(), "2019-05-20")?;
+        assert_eq!(through.len(), 3);
+
+        let merged = pr_times(&conn)?;
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].week, "2019-05-13");
+        assert_eq!(
+            (merged[0].waiting_for_review, merged[0].time_to_merge),
+            (60, 1380)
+        );
+        let sizes: Vec<_> = weeks[0]
+            .sizes
+            .iter()
+            .map(|s| (s.size, s.merged_prs, s.time_to_merge.mean))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                (Size::XS, 0, 0),
+                (Size::S, 1, 1380),
+                (Size::M, 0, 0),
+                (Size::L, 0, 0),
+                (Size::XL, 0, 0),
+            ]
+        );
+        assert_eq!(weeks[1].start_date, "2019-05-20");
+        assert_eq!((weeks[1].merged_prs, weeks[1].closed_prs), (0, 0));
+        assert_eq!(weeks[2].start_date, "2019-05-27");
+        assert_eq!((weeks[2].merged_prs, weeks[2].closed_prs), (0, 1));
+
+        let bugs = PrFilter {
+            label: Some("bug".to_owned()),
+            ..PrFilter::default()
+        };
+        assert!(weekly_stats(&conn, &bugs)?.is_empty());
+
+        let humans = PrFilter {
+            bots: Bots::Exclude,
+            ..PrFilter::default()
+        };
+        let bots = PrFilter {
+            bots: Bots::Only,
+            ..PrFilter::default()
+        };
+        assert_eq!(weekly_stats(&conn, &humans)?.len(), 3);
+        assert!(weekly_stats(&conn, &bots)?.is_empty());
+        data::User {
+            bot: true,
+            ..data::PullRequest::pr0().author
+        }
+        .insert_into(&conn)?;
+        assert!(weekly_stats(&conn, &humans)?.is_empty());
+        assert_eq!(weekly_stats(&conn, &bots)?[0].merged_prs, 1);
+
+        replace_files(
+            &conn,
+            "https://api.github.com",
+            1,
+            &["docs/index.md".to_owned()],
+        )?;
+        set_area(
+            &conn,
+            "https://api.github.com",
+            "nrc/gh-velocity",
+            "docs/index.md",
+            Some("@docs"),
+        )?;
+        assert_eq!(areas(&conn)?, vec!["@docs".to_owned()]);
+        let docs = PrFilter {
+            area: Some("@docs".to_owned()),
+            ..PrFilter::default()
+        };
+        let weeks = weekly_stats(&conn, &docs)?;
+        assert_eq!((weeks.len(), weeks[0].merged_prs), (1, 1));
         Ok(())
     }
 }
@@ -0,0 +1,526 @@
+//! DORA metrics: how often we deploy, how long it takes for a change to be
+//! deployed, how often deployments fail, and how long it takes to restore
+//! service. Deployments are releases or deployments recorded from GitHub (see
+//! `github`), or tags in a repo's mirror (recorded here). Failures are found
+//! using `config::FAILURE_RULES`.
+
+use crate::config::{Deploys, FailureRule, Repo, FAILURE_RULES, REPOS};
+use crate::data::{self, Date, Status};
+use crate::git::Mirror;
+use crate::{db, Result};
+
+use rusqlite::Connection;
+
+/// Marks a commit reverted by `git revert`.
+const REVERTED_COMMIT: &str = "This reverts commit ";
+
+/// Record tags as deployments, link merged PRs to deployments, and find
+/// failures, for every repo. Should be run after `git::analyse_all` so that
+/// mirrors are up to date. A repo which fails doesn't stop the others being
+/// updated, the first error is returned afterwards.
+pub fn update_all() -> Result<()> {
+    let conn = db::connection()?;
+    let mut result = Ok(());
+    for repo in REPOS {
+        result = result.and(update_repo(&conn, repo));
+    }
+    result
+}
+
+fn update_repo(conn: &Connection, repo: &Repo) -> Result<()> {
+    if repo.deploys != Deploys::None {
+        let mirror = repo.clone_url.map(|_| Mirror::open(repo));
+        if let (Deploys::Tags { pattern }, Some(mirror)) = (&repo.deploys, &mirror) {
+            record_tags(conn, repo, mirror, pattern)?;
+        }
+        link_deployments(conn, repo, mirror.as_ref())?;
+    }
+    let failures = find_failures(conn, repo, FAILURE_RULES)?;
+    db::replace_failures(conn, repo.host, &repo.full_name(), &failures)
+}
+
+/// Labels of issues which sources should record as incidents.
+pub fn incident_labels() -> impl Iterator<Item = &'static str> {
+    FAILURE_RULES.iter().filter_map(|rule| match rule {
+        FailureRule::IssueLabel(label) => Some(*label),
+        _ => None,
+    })
+}
+
+fn record_tags(conn: &Connection, repo: &Repo, mirror: &Mirror, pattern: &str) -> Result<()> {
+    for (name, sha, time) in mirror.tags(pattern)? {
+        data::Deployment {
+            host: repo.host.to_owned(),
+            repo: repo.full_name(),
+            name,
+            sha,
+            time: Date::new(time),
+        }
+        .insert_into(conn)?;
+    }
+    Ok(())
+}
+
+/// Link each merged PR to the first deployment after it was merged which
+/// contains its merge commit. Without a mirror we can't tell which deployments
+/// contain a PR, so we assume the first one after it was merged does.
+fn link_deployments(conn: &Connection, repo: &Repo, mirror: Option<&Mirror>) -> Result<()> {
+    let full_name = repo.full_name();
+    for pr in db::undeployed_prs(conn, repo.host, &full_name)? {
+        for deployment in db::deployments_since(conn, repo.host, &full_name, &pr.merged)? {
+            if contains(mirror, &deployment, &pr)? != Some(false) {
+                data::PrDeployment {
+                    host: repo.host.to_owned(),
+                    pr: pr.id,
+                    repo: full_name.clone(),
+                    deployment: deployment.name,
+                }
+                .insert_into(conn)?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `None` if we can't tell.
+fn contains(
+    mirror: Option<&Mirror>,
+    deployment: &db::Deployment,
+    pr: &db::MergedPr,
+) -> Result<Option<bool>> {
+    let (mirror, merge_commit) = match (mirror, &pr.merge_commit) {
+        (Some(mirror), Some(merge_commit)) => (mirror, merge_commit),
+        _ => return Ok(None),
+    };
+    let deployed = if deployment.sha.0.is_empty() {
+        match mirror.resolve_tag(&deployment.name)? {
+            Some(sha) => sha,
+            None => return Ok(None),
+        }
+    } else {
+        deployment.sha.clone()
+    };
+    mirror.is_ancestor(merge_commit, &deployed)
+}
+
+/// Failures in `repo` according to `rules`. A failure starts when the PR which
+/// fixes it is opened (or the issue which reports it), and is restored when
+/// that PR is merged (or the issue is closed).
+fn find_failures(
+    conn: &Connection,
+    repo: &Repo,
+    rules: &[FailureRule],
+) -> Result<Vec<data::Failure>> {
+    let full_name = repo.full_name();
+    let prs: Vec<db::PullRequest> = db::read_prs(conn, db::all_time())?
+        .into_iter()
+        .filter(|pr| pr.host == repo.host && pr.repo == full_name)
+        .collect();
+
+    let mut failures = vec![];
+    for pr in &prs {
+        let restored = match pr.samples.last().map(|s| &s.status) {
+            Some(Status::Open) => None,
+            Some(Status::Merged(d)) => Some(Date::new(d.date.clone())),
+            // An abandoned fix doesn't tell us anything.
+            Some(Status::Closed(_)) | None => continue,
+        };
+        if !rules.iter().any(|rule| matches_pr(rule, pr)) {
+            continue;
+        }
+
+        let text = format!("{}\n{}", pr.title, pr.body);
+        let started = Date::new(pr.created.date.clone());
+        let (cause_pr, cause_deployment) =
+            cause(conn, repo, &prs, Some(pr.number), &text, &started)?;
+        failures.push(data::Failure {
+            host: repo.host.to_owned(),
+            repo: full_name.clone(),
+            source: format!("pr {}", pr.number),
+            started,
+            restored,
+            cause_pr,
+            cause_deployment,
+        });
+    }
+
+    for incident in db::read_incidents(conn, repo.host, &full_name)? {
+        let matches = rules.iter().any(|rule| match rule {
+            FailureRule::IssueLabel(label) => *label == incident.label,
+            _ => false,
+        });
+        if !matches {
+            continue;
+        }
+
+        let text = format!("{}\n{}", incident.title, incident.body);
+        let (cause_pr, cause_deployment) = cause(conn, repo, &prs, None, &text, &incident.opened)?;
+        failures.push(data::Failure {
+            host: repo.host.to_owned(),
+            repo: full_name.clone(),
+            source: format!("issue {}", incident.number),
+            started: incident.opened,
+            restored: incident.closed,
+            cause_pr,
+            cause_deployment,
+        });
+    }
+
+    Ok(failures)
+}
+
+fn matches_pr(rule: &FailureRule, pr: &db::PullRequest) -> bool {
+    match rule {
+        // `Revert "..."` is the title used by GitHub's revert button and `git revert`.
+        FailureRule::Revert => {
+            pr.title.starts_with("Revert \"") || pr.body.contains(REVERTED_COMMIT)
+        }
+        FailureRule::PrLabel(label) => pr.labels.iter().any(|l| l == label),
+        FailureRule::IssueLabel(_) => false,
+    }
+}
+
+/// The PR (and the deployment which included it) referenced by `text`, or if
+/// there is no such PR, the last deployment before `started`.
+fn cause(
+    conn: &Connection,
+    repo: &Repo,
+    prs: &[db::PullRequest],
+    own_number: Option<u32>,
+    text: &str,
+    started: &Date,
+) -> Result<(Option<u32>, Option<String>)> {
+    match referenced_pr(prs, own_number, text) {
+        Some(pr) => Ok((Some(pr.id), db::pr_deployment(conn, repo.host, pr.id)?)),
+        None => Ok((
+            None,
+            db::last_deployment_before(conn, repo.host, &repo.full_name(), started)?,
+        )),
+    }
+}
+
+/// A PR referenced by number (e.g., `Reverts owner/repo#12` or `caused by
+/// #12`, but not a PR in another repo) or by its merge commit (e.g., `This
+/// reverts commit 1a2b3c...`).
+fn referenced_pr<'a>(
+    prs: &'a [db::PullRequest],
+    own_number: Option<u32>,
+    text: &str,
+) -> Option<&'a db::PullRequest> {
+    for (i, _) in text.match_indices('#') {
+        let digits: String = text[i + 1..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        let number = match digits.parse() {
+            Ok(n) if Some(n) != own_number => n,
+            _ => continue,
+        };
+        let before = &text[..i];
+        let full_name = &before[before.trim_end_matches(is_repo_name_char).len()..];
+        if let Some(pr) = prs.iter().find(|pr| {
+            pr.number == number
+                && (full_name.is_empty() || full_name.eq_ignore_ascii_case(&pr.repo))
+        }) {
+            return Some(pr);
+        }
+    }
+
+    for (i, _) in text.match_indices(REVERTED_COMMIT) {
+        let sha: String = text[i + REVERTED_COMMIT.len()..]
+            .chars()
+            .take_while(char::is_ascii_hexdigit)
+            .collect();
+        if sha.is_empty() {
+            continue;
+        }
+        let pr = prs.iter().find(|pr| {
+            pr.samples
+                .last()
+                .and_then(|s| s.merge_commit.as_ref())
+                .map(|c| c.0.starts_with(&sha))
+                .unwrap_or(false)
+        });
+        if pr.is_some() {
+            return pr;
+        }
+    }
+
+    None
+}
+
+fn is_repo_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '/'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Areas, Forge};
+    use crate::data::{Sha, Status};
+    use crate::source::record_sample;
+
+    const REPO: Repo = Repo {
+        forge: Forge::GitHub,
+        host: "https://api.github.com",
+        ca_cert: None,
+        clone_url: None,
+        owner: "nrc",
+        name: "gh-velocity",
+        deploys: Deploys::Releases,
+        areas: Areas::None,
+    };
+
+    fn merged_sample(id: u32, created: &str, merged: &str) -> data::Sample {
+        pr_sample(
+            id,
+            "",
+            "",
+            &[],
+            created,
+            Status::Merged(Date::new(merged.to_owned())),
+        )
+    }
+
+    fn pr_sample(
+        id: u32,
+        title: &str,
+        body: &str,
+        labels: &[&str],
+        created: &str,
+        status: Status,
+    ) -> data::Sample {
+        let author = data::User {
+            host: REPO.host.to_owned(),
+            id: 42,
+            username: "bob".to_owned(),
+            url: "https://bob".to_owned(),
+            bot: false,
+        };
+        data::Sample {
+            host: REPO.host.to_owned(),
+            time: Date::new(created.to_owned()),
+            pr: data::PullRequest {
+                host: REPO.host.to_owned(),
+                id,
+                repo: REPO.full_name(),
+                number: 100 + id,
+                title: title.to_owned(),
+                body: body.to_owned(),
+                author,
+                created: Date::new(created.to_owned()),
+                url: String::new(),
+                labels: labels
+                    .iter()
+                    .map(|l| data::Label {
+                        host: REPO.host.to_owned(),
+                        repo: REPO.full_name(),
+                        name: l.to_string(),
+                        color: String::new(),
+                    })
+                    .collect(),
+            },
+            status,
+            commits: 1,
+            additions: 0,
+            deletions: 0,
+            changed_files: 0,
+            review_comments: 0,
+            first_commit: Sha("abc".to_owned()),
+            head: Sha("def".to_owned()),
+            merge_commit: None,
+            draft: false,
+            milestone: None,
+            files: vec![],
+        }
+    }
+
+    fn release(name: &str, time: &str) -> data::Deployment {
+        data::Deployment {
+            host: REPO.host.to_owned(),
+            repo: REPO.full_name(),
+            name: name.to_owned(),
+            sha: Sha(String::new()),
+            time: Date::new(time.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_link_deployments() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+        record_sample(
+            &conn,
+            &merged_sample(1, "2019-05-13T00:00:00Z", "2019-05-14T00:00:00Z"),
+        )?;
+        record_sample(
+            &conn,
+            &merged_sample(2, "2019-05-14T00:00:00Z", "2019-05-15T12:00:00Z"),
+        )?;
+        release("v1.0", "2019-05-14T12:00:00Z").insert_into(&conn)?;
+        release("v1.1", "2019-05-16T00:00:00Z").insert_into(&conn)?;
+        release("v1.2", "2019-05-28T00:00:00Z").insert_into(&conn)?;
+
+        link_deployments(&conn, &REPO, None)?;
+        assert!(db::undeployed_prs(&conn, REPO.host, &REPO.full_name())?.is_empty());
+        let linked: Vec<(u32, String)> = conn
+            .prepare("SELECT pr, deployment FROM pr_deployment ORDER BY pr")?
+            .query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(linked, vec![(1, "v1.0".to_owned()), (2, "v1.1".to_owned())]);
+
+        let weeks = db::weekly_deploy_stats(&conn, &db::PrFilter::default())?;
+        assert_eq!(weeks.len(), 3);
+        assert_eq!(weeks[0].start_date, "2019-05-13");
+        assert_eq!(weeks[0].deployments, 2);
+        assert_eq!(weeks[0].lead_time_for_changes.min, 36 * 60);
+        assert_eq!(weeks[0].lead_time_for_changes.max, 48 * 60);
+        assert_eq!(weeks[1].deployments, 0);
+        assert_eq!(weeks[2].start_date, "2019-05-27");
+        assert_eq!(weeks[2].deployments, 1);
+        assert_eq!(weeks[2].lead_time_for_changes.max, 0);
+
+        // Only v1.0 and v1.1 include a PR, and neither is by a bot.
+        let humans = db::PrFilter {
+            bots: db::Bots::Exclude,
+            ..db::PrFilter::default()
+        };
+        let weeks = db::weekly_deploy_stats(&conn, &humans)?;
+        assert_eq!(weeks.len(), 1);
+        assert_eq!(weeks[0].deployments, 2);
+        let bots = db::PrFilter {
+            bots: db::Bots::Only,
+            ..db::PrFilter::default()
+        };
+        assert!(db::weekly_deploy_stats(&conn, &bots)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_failures() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+        let mut original = merged_sample(1, "2019-05-13T00:00:00Z", "2019-05-14T00:00:00Z");
+        original.merge_commit = Some(Sha("1a2b3c4d".to_owned()));
+        record_sample(&conn, &original)?;
+        record_sample(
+            &conn,
+            &merged_sample(2, "2019-05-14T00:00:00Z", "2019-05-14T06:00:00Z"),
+        )?;
+        release("v1.0", "2019-05-14T12:00:00Z").insert_into(&conn)?;
+        release("v1.1", "2019-05-16T00:00:00Z").insert_into(&conn)?;
+        link_deployments(&conn, &REPO, None)?;
+
+        let fixes = &[
+            // Revert by number (from GitHub's revert button).
+            pr_sample(
+                3,
+                "Revert \"Fix the thing\"",
+                "Reverts nrc/gh-velocity#101",
+                &[],
+                "2019-05-15T00:00:00Z",
+                Status::Merged(Date::new("2019-05-15T01:00:00Z".to_owned())),
+            ),
+            // Revert by commit (from `git revert`), still open.
+            pr_sample(
+                4,
+                "Undo",
+                "This reverts commit 1a2b3c4.",
+                &[],
+                "2019-05-15T00:00:00Z",
+                Status::Open,
+            ),
+            // Hotfix with no reference to a PR in this repo, caused by the
+            // last deployment.
+            pr_sample(
+                5,
+                "Quick fix for #105 and other/repo#102",
+                "",
+                &["hotfix"],
+                "2019-05-16T12:00:00Z",
+                Status::Merged(Date::new("2019-05-16T12:30:00Z".to_owned())),
+            ),
+            // Abandoned hotfix.
+            pr_sample(
+                6,
+                "Another fix",
+                "",
+                &["hotfix"],
+                "2019-05-16T12:00:00Z",
+                Status::Closed(Date::new("2019-05-16T12:30:00Z".to_owned())),
+            ),
+            // Neither a revert nor a hotfix.
+            pr_sample(
+                7,
+                "Refer to #101",
+                "",
+                &["bug"],
+                "2019-05-16T12:00:00Z",
+                Status::Open,
+            ),
+        ];
+        for fix in fixes {
+            record_sample(&conn, fix)?;
+        }
+        data::Incident {
+            host: REPO.host.to_owned(),
+            repo: REPO.full_name(),
+            number: 200,
+            label: "incident".to_owned(),
+            title: "Site down".to_owned(),
+            body: "Probably caused by #102".to_owned(),
+            opened: Date::new("2019-05-15T00:00:00Z".to_owned()),
+            closed: Some(Date::new("2019-05-15T02:00:00Z".to_owned())),
+        }
+        .insert_into(&conn)?;
+
+        let failures = find_failures(&conn, &REPO, FAILURE_RULES)?;
+        let summary: Vec<_> = failures
+            .iter()
+            .map(|f| {
+                (
+                    f.source.as_str(),
+                    f.restored.as_ref().map(|d| d.date.as_str()),
+                    f.cause_pr,
+                    f.cause_deployment.as_ref().map(|d| d.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "pr 103",
+                    Some("2019-05-15T01:00:00Z"),
+                    Some(1),
+                    Some("v1.0")
+                ),
+                ("pr 104", None, Some(1), Some("v1.0")),
+                ("pr 105", Some("2019-05-16T12:30:00Z"), None, Some("v1.1")),
+                (
+                    "issue 200",
+                    Some("2019-05-15T02:00:00Z"),
+                    Some(2),
+                    Some("v1.0")
+                ),
+            ]
+        );
+
+        // Only reverts.
+        assert_eq!(
+            find_failures(&conn, &REPO, &[FailureRule::Revert])?.len(),
+            2
+        );
+
+        db::replace_failures(&conn, REPO.host, &REPO.full_name(), &failures)?;
+        let weeks = db::weekly_deploy_stats(&conn, &db::PrFilter::default())?;
+        assert_eq!(weeks.len(), 1);
+        assert_eq!(weeks[0].deployments, 2);
+        assert_eq!(weeks[0].failed_deployments, 2);
+        assert_eq!(weeks[0].change_failure_rate, 1.0);
+        assert_eq!(weeks[0].time_to_restore.min, 30);
+        assert_eq!(weeks[0].time_to_restore.max, 120);
+        Ok(())
+    }
+}
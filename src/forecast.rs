@@ -0,0 +1,209 @@
+//! Forecasting with Monte Carlo simulation: we simulate many possible futures,
+//! where each week's throughput (merged PRs) is a randomly chosen week from the
+//! recent past, and report how the simulations turned out.
+
+use crate::config::{FORECAST_HISTORY_WEEKS, FORECAST_TRIALS};
+use crate::{db, Result};
+
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng};
+use rusqlite::{Connection, NO_PARAMS};
+use serde_derive::Serialize;
+use std::fmt;
+
+/// The most weeks and PRs we'll forecast for, about ten years and more PRs
+/// than anyone has open, to bound the work (and the sums) of a simulation.
+pub const MAX_WEEKS: u32 = 520;
+pub const MAX_PRS: u32 = 10_000;
+
+#[derive(Clone, Serialize, Debug)]
+pub struct Forecast {
+    /// Merged PRs in each week which we resampled, oldest first.
+    pub history: Vec<u32>,
+    pub throughput: Throughput,
+    pub completion: Completion,
+}
+
+/// How many PRs we'll merge in the next `weeks` weeks. With 85% confidence,
+/// we'll merge at least `p85` PRs, etc.
+#[derive(Clone, Serialize, Debug, Eq, PartialEq)]
+pub struct Throughput {
+    pub weeks: u32,
+    pub p50: u32,
+    pub p85: u32,
+    pub p95: u32,
+}
+
+/// How many weeks it will take to merge `prs` PRs. With 85% confidence, we'll
+/// be done within `p85` weeks, etc. `None` if we haven't merged any PRs
+/// recently, so we'll never be done.
+#[derive(Clone, Serialize, Debug, Eq, PartialEq)]
+pub struct Completion {
+    pub prs: u32,
+    pub p50: Option<u32>,
+    pub p85: Option<u32>,
+    pub p95: Option<u32>,
+}
+
+/// Forecast throughput for the next `weeks` weeks, and when `prs` PRs will be
+/// merged. If `prs` is `None`, we use the number of PRs open now (excluding
+/// drafts). `weeks` and `prs` should be at most `MAX_WEEKS` and `MAX_PRS`.
+pub fn forecast(conn: &Connection, weeks: u32, prs: Option<u32>) -> Result<Forecast> {
+    let history = history(conn)?;
+    let prs = match prs {
+        Some(prs) => prs,
+        None => db::open_prs_per_day(conn, &db::PrFilter::default())?
+            .last()
+            .map(|d| d.open_prs)
+            .unwrap_or(0),
+    };
+    let mut rng = StdRng::from_entropy();
+    Ok(Forecast {
+        throughput: throughput(&history, weeks, FORECAST_TRIALS, &mut rng),
+        completion: completion(&history, prs, FORECAST_TRIALS, &mut rng),
+        history,
+    })
+}
+
+impl fmt::Display for Forecast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Merged PRs in the last {} weeks: {:?}",
+            self.history.len(),
+            self.history
+        )?;
+        let t = &self.throughput;
+        writeln!(f, "PRs merged in the next {} weeks:", t.weeks)?;
+        for (confidence, prs) in &[(50, t.p50), (85, t.p85), (95, t.p95)] {
+            writeln!(f, "  {}%: at least {}", confidence, prs)?;
+        }
+        let c = &self.completion;
+        writeln!(f, "Weeks to merge {} PRs:", c.prs)?;
+        for (confidence, weeks) in &[(50, c.p50), (85, c.p85), (95, c.p95)] {
+            match weeks {
+                Some(weeks) => writeln!(f, "  {}%: at most {}", confidence, weeks)?,
+                None => writeln!(f, "  {}%: never", confidence)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Merged PRs in each of the most recent complete weeks, including weeks
+/// without any right up to this week.
+fn history(conn: &Connection) -> Result<Vec<u32>> {
+    let this_week: String = conn.query_row(
+        "SELECT date('now', 'weekday 0', '-6 days')",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let weeks: Vec<u32> = db::weekly_stats_through(conn, &db::PrFilter::default(), &this_week)?
+        .into_iter()
+        .filter(|w| w.start_date < this_week)
+        .map(|w| w.merged_prs)
+        .collect();
+    let start = weeks.len().saturating_sub(FORECAST_HISTORY_WEEKS);
+    Ok(weeks[start..].to_vec())
+}
+
+fn throughput(history: &[u32], weeks: u32, trials: usize, rng: &mut impl Rng) -> Throughput {
+    let mut totals: Vec<u32> = (0..trials)
+        .map(|_| (0..weeks).map(|_| sample(history, rng)).sum())
+        .collect();
+    totals.sort();
+    // Confident that we'll merge at least this many, so count from the bottom.
+    let at_least = |confidence: f64| totals[((1.0 - confidence) * totals.len() as f64) as usize];
+    Throughput {
+        weeks,
+        p50: at_least(0.5),
+        p85: at_least(0.85),
+        p95: at_least(0.95),
+    }
+}
+
+fn completion(history: &[u32], prs: u32, trials: usize, rng: &mut impl Rng) -> Completion {
+    if history.iter().all(|&merged| merged == 0) {
+        return Completion {
+            prs,
+            p50: None,
+            p85: None,
+            p95: None,
+        };
+    }
+    let mut durations: Vec<u32> = (0..trials)
+        .map(|_| {
+            let (mut weeks, mut merged) = (0, 0);
+            while merged < prs {
+                merged += sample(history, rng);
+                weeks += 1;
+            }
+            weeks
+        })
+        .collect();
+    durations.sort();
+    let within = |confidence: f64| {
+        let rank = (confidence * durations.len() as f64).ceil() as usize;
+        Some(durations[rank.max(1) - 1])
+    };
+    Completion {
+        prs,
+        p50: within(0.5),
+        p85: within(0.85),
+        p95: within(0.95),
+    }
+}
+
+/// A random week's throughput, zero if there is no history.
+fn sample(history: &[u32], rng: &mut impl Rng) -> u32 {
+    if history.is_empty() {
+        return 0;
+    }
+    history[rng.gen_range(0, history.len())]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_throughput() {
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(
+            throughput(&[5, 5, 5], 4, 100, &mut rng),
+            Throughput {
+                weeks: 4,
+                p50: 20,
+                p85: 20,
+                p95: 20,
+            }
+        );
+        assert_eq!(throughput(&[], 4, 100, &mut rng).p50, 0);
+
+        let t = throughput(&[0, 2, 4, 6, 8, 10], 4, 1000, &mut rng);
+        assert!(t.p95 <= t.p85 && t.p85 <= t.p50);
+        assert!(t.p50 >= 16 && t.p50 <= 24, "{:?}", t);
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(
+            completion(&[5, 5, 5], 12, 100, &mut rng),
+            Completion {
+                prs: 12,
+                p50: Some(3),
+                p85: Some(3),
+                p95: Some(3),
+            }
+        );
+        assert_eq!(completion(&[0, 0], 12, 100, &mut rng).p50, None);
+        assert_eq!(completion(&[3], 0, 100, &mut rng).p95, Some(0));
+
+        let c = completion(&[0, 2, 4, 6, 8, 10], 20, 1000, &mut rng);
+        let (p50, p85, p95) = (c.p50.unwrap(), c.p85.unwrap(), c.p95.unwrap());
+        assert!(p50 <= p85 && p85 <= p95);
+        assert!(p50 >= 3 && p50 <= 5, "{:?}", c);
+    }
+}
@@ -1,13 +1,14 @@
 use serde_derive::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use crate::size::Size;
 use crate::{db, Result};
 
-// TODO
-// Use Tide to send it as json via an http endpoint (per block = n weeks, just do everything for now)
-// Provide the web frontend (and static files) as another endpoint
+// TODO send it in blocks of n weeks, rather than everything at once.
 
 pub(crate) type Blob = BlobOuter<db::DeployConnProvider>;
 
@@ -27,60 +28,279 @@ impl<T: db::ConnectionProvider> BlobOuter<T> {
 
     // TODO tests
     pub(crate) fn update(&self) -> Result<()> {
-        let mut this = self.inner.lock()?;
-        // No point in updating more than once per day.
-        if this.last_update.elapsed() < Duration::from_secs(60 * 60 * 24) {
-            return Ok(());
+        // No point in updating more than once per day. The lock is only held
+        // for this check and to swap in the new blob, so requests for the old
+        // one aren't blocked while it's being built.
+        let last_update = self.inner.lock()?.last_update;
+        if let Some(last_update) = last_update {
+            if last_update.elapsed() < Duration::from_secs(60 * 60 * 24) {
+                return Ok(());
+            }
         }
 
         let conn = T::connection()?;
         let mut new_blob = BlobInner::default();
-        new_blob.days = db::open_prs_per_day(&conn)?;
-        new_blob.weeks = db::weekly_stats(&conn)?;
+        new_blob.last_update = Some(Instant::now());
+        new_blob.days = db::open_prs_per_day(&conn, &db::PrFilter::default())?;
+        new_blob.weeks = db::weekly_stats(&conn, &db::PrFilter::default())?;
+        let humans = db::PrFilter {
+            bots: db::Bots::Exclude,
+            ..db::PrFilter::default()
+        };
+        new_blob.human_weeks = db::weekly_stats(&conn, &humans)?;
+        let bots = db::PrFilter {
+            bots: db::Bots::Only,
+            ..db::PrFilter::default()
+        };
+        new_blob.bot_weeks = db::weekly_stats(&conn, &bots)?;
+        new_blob.bot_days = db::open_prs_per_day(&conn, &bots)?;
+        new_blob.flow = db::cumulative_flow(&conn, &db::PrFilter::default())?;
+        new_blob.cohorts = db::cohorts(&conn, &db::PrFilter::default(), "now")?;
+        for label in db::labels(&conn)? {
+            let filter = db::PrFilter {
+                label: Some(label.clone()),
+                ..db::PrFilter::default()
+            };
+            new_blob
+                .label_weeks
+                .insert(label, db::weekly_stats(&conn, &filter)?);
+        }
+        for area in db::areas(&conn)? {
+            let filter = db::PrFilter {
+                area: Some(area.clone()),
+                ..db::PrFilter::default()
+            };
+            new_blob
+                .area_weeks
+                .insert(area, db::weekly_stats(&conn, &filter)?);
+        }
+        new_blob.deploy_weeks = db::weekly_deploy_stats(&conn, &db::PrFilter::default())?;
 
-        *this = new_blob;
+        *self.inner.lock()? = new_blob;
         Ok(())
     }
+
+    /// The blob as served to the dashboard.
+    pub(crate) fn json(&self) -> Result<String> {
+        let this = self.inner.lock()?;
+        Ok(serde_json::to_string(&*this)?)
+    }
 }
 
 #[derive(Clone, Serialize, Debug)]
 struct BlobInner {
     #[serde(skip_serializing)]
-    last_update: Instant,
+    last_update: Option<Instant>,
     weeks: Vec<Week>,
+    // Weekly stats for PRs by people and by bots (e.g., Dependabot).
+    human_weeks: Vec<Week>,
+    bot_weeks: Vec<Week>,
+    // Weekly stats for PRs with each label.
+    label_weeks: BTreeMap<String, Vec<Week>>,
+    // Weekly stats for PRs which change each area (see `area`).
+    area_weeks: BTreeMap<String, Vec<Week>>,
+    deploy_weeks: Vec<DeployWeek>,
     days: Vec<Day>,
+    bot_days: Vec<Day>,
+    // PRs in each state per day, for the cumulative flow diagram.
+    flow: Vec<FlowDay>,
+    // PRs by the week they were opened, for the cohort heatmap.
+    cohorts: Vec<Cohort>,
 }
 
 impl Default for BlobInner {
     fn default() -> BlobInner {
         BlobInner {
-            last_update: Instant::now(),
+            last_update: None,
             weeks: vec![],
+            human_weeks: vec![],
+            bot_weeks: vec![],
+            label_weeks: BTreeMap::new(),
+            area_weeks: BTreeMap::new(),
+            deploy_weeks: vec![],
             days: vec![],
+            bot_days: vec![],
+            flow: vec![],
+            cohorts: vec![],
         }
     }
 }
 
 #[derive(Clone, Serialize, Debug)]
 pub struct Week {
-    start_date: String,
+    pub start_date: String,
     pub merged_prs: u32,
     pub closed_prs: u32,
-    // In minutes
+    // In minutes, excluding time as a draft.
     pub time_to_merge: Distribution,
     pub review_comments: Distribution,
+    // The below are only for PRs in repos we keep a mirror of, in minutes.
+    // From first commit authored to merge.
+    pub lead_time: Distribution,
+    // From first commit authored to PR opened.
+    pub coding_time: Distribution,
+    // From PR opened to merge, excluding time as a draft.
+    pub review_time: Distribution,
+    // Time to merge split into phases, in minutes (see `cycle::Phases`).
+    pub draft_time: Distribution,
+    pub waiting_for_review: Distribution,
+    pub in_review: Distribution,
+    pub approval_to_merge: Distribution,
+    // Merged PRs of each size, smallest first.
+    pub sizes: Vec<SizeWeek>,
+    // From an issue being opened to the PR which fixes it merging, in minutes.
+    pub issue_to_merge: Distribution,
+    // CI runs on merged PRs (see `cycle::Ci`), durations in minutes.
+    pub ci_wait: Distribution,
+    pub blocked_on_ci: Distribution,
+    pub ci_runs: u32,
+    // Failed runs / `ci_runs`.
+    pub ci_failure_rate: f64,
+    pub ci_reruns: u32,
+    pub flaky_checks: u32,
+    // The durations above in business time, see `business`.
+    pub business: BusinessTime,
+}
+
+// Minutes of business time, each field is as in `Week`.
+#[derive(Clone, Serialize, Debug)]
+pub struct BusinessTime {
+    pub time_to_merge: Distribution,
+    pub lead_time: Distribution,
+    pub coding_time: Distribution,
+    pub review_time: Distribution,
+    pub draft_time: Distribution,
+    pub waiting_for_review: Distribution,
+    pub in_review: Distribution,
+    pub approval_to_merge: Distribution,
+    pub issue_to_merge: Distribution,
+    pub ci_wait: Distribution,
+    pub blocked_on_ci: Distribution,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SizeWeek {
+    pub size: Size,
+    pub merged_prs: u32,
+    // In minutes, excluding time as a draft.
+    pub time_to_merge: Distribution,
+    pub business_time_to_merge: Distribution,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct DeployWeek {
+    pub start_date: String,
+    pub deployments: u32,
+    // In minutes, for PRs first deployed this week. From first commit authored
+    // (or PR opened, if we don't keep a mirror) to deployment.
+    pub lead_time_for_changes: Distribution,
+    pub business_lead_time_for_changes: Distribution,
+    // Deployments this week which caused a failure, see `config::FAILURE_RULES`.
+    pub failed_deployments: u32,
+    // `failed_deployments / deployments`.
+    pub change_failure_rate: f64,
+    // In minutes, for failures restored this week.
+    pub time_to_restore: Distribution,
+    pub business_time_to_restore: Distribution,
 }
 
 #[derive(Clone, Serialize, Debug)]
 pub struct Day {
     pub date: String,
+    // Excluding drafts.
     pub open_prs: u32,
+    pub draft_prs: u32,
+    pub open_issues: u32,
+}
+
+// PRs opened in a week, for a cohort table (see `db::cohorts`).
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct Cohort {
+    pub start_date: String,
+    pub opened_prs: u32,
+    // One for each of `config::COHORT_DAYS`.
+    pub merged_within: Vec<MergedWithin>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct MergedWithin {
+    pub days: u32,
+    pub merged_prs: u32,
+    // `merged_prs / opened_prs`, or `None` if not all the cohort's PRs have
+    // been open for `days` yet.
+    pub fraction: Option<f64>,
+}
+
+// The number of PRs in each state on a day, for a cumulative flow diagram.
+#[derive(Clone, Serialize, Debug, Eq, PartialEq)]
+pub struct FlowDay {
+    pub date: String,
+    pub draft: u32,
+    pub awaiting_review: u32,
+    pub in_review: u32,
+    pub approved: u32,
+    pub merged: u32,
+    pub closed: u32,
 }
 
 #[derive(Clone, Serialize, Debug)]
 pub struct Distribution {
     pub mean: u32,
+    /// The lower median, if there are an even number of values.
+    pub median: u32,
     pub mode: u32,
     pub min: u32,
     pub max: u32,
 }
+
+impl Distribution {
+    /// All fields are zero if there are no values.
+    pub fn new(values: &[u32]) -> Distribution {
+        if values.is_empty() {
+            return Distribution {
+                mean: 0,
+                median: 0,
+                mode: 0,
+                min: 0,
+                max: 0,
+            };
+        }
+
+        let mut counts = HashMap::new();
+        for v in values {
+            *counts.entry(*v).or_insert(0) += 1;
+        }
+        // Break ties using the smaller value, so the result is deterministic.
+        let mode = counts
+            .into_iter()
+            .max_by_key(|&(v, count)| (count, Reverse(v)))
+            .unwrap()
+            .0;
+
+        let mut sorted = values.to_vec();
+        sorted.sort();
+
+        Distribution {
+            mean: (values.iter().map(|v| u64::from(*v)).sum::<u64>() / values.len() as u64) as u32,
+            median: sorted[(sorted.len() - 1) / 2],
+            mode,
+            min: *values.iter().min().unwrap(),
+            max: *values.iter().max().unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_distribution() {
+        let d = Distribution::new(&[]);
+        assert_eq!((d.mean, d.median, d.mode, d.min, d.max), (0, 0, 0, 0, 0));
+        let d = Distribution::new(&[5, 1, 3, 3, 1, 11]);
+        assert_eq!((d.mean, d.median, d.mode, d.min, d.max), (4, 3, 1, 1, 11));
+        let d = Distribution::new(&[5, 1, 3]);
+        assert_eq!(d.median, 3);
+    }
+}
@@ -0,0 +1,434 @@
+//! Analysis of PRs using a bare mirror of their repo. This tells us things the
+//! forges' APIs don't, such as when a PR's first commit was authored and
+//! whether its branch has been rebased.
+
+use crate::config::{Repo, MIRROR_DIR, REPOS};
+use crate::data::{self, Date, Sha, Status};
+use crate::{db, GhvError, Result};
+
+use rusqlite::Connection;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Update the mirror of every repo with a `clone_url` and analyse any merged
+/// PRs we haven't already analysed. A repo which fails doesn't stop the others
+/// being analysed, the first error is returned afterwards.
+pub fn analyse_all() -> Result<()> {
+    let conn = db::connection()?;
+    let mut result = Ok(());
+    for repo in REPOS {
+        if let Some(url) = repo.clone_url {
+            let analysed = Mirror::update(url, &mirror_path(repo))
+                .and_then(|mirror| analyse_repo(&conn, repo, &mirror));
+            result = result.and(analysed);
+        }
+    }
+    result
+}
+
+fn analyse_repo(conn: &Connection, repo: &Repo, mirror: &Mirror) -> Result<()> {
+    for pr in db::read_prs(conn, db::all_time())? {
+        if pr.host != repo.host
+            || pr.repo != repo.full_name()
+            || db::has_lead_time(conn, &pr.host, pr.id)?
+        {
+            continue;
+        }
+        if let Some(lead_time) = analyse(mirror, &pr)? {
+            lead_time.insert_into(conn)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns `None` if the PR is not merged or we can't find its commits.
+///
+/// A force-push is any change of the PR's head which is not a fast-forward. A
+/// rebase is a force-push which also changes the PR's first commit (so every
+/// rebase is also counted as a force-push).
+fn analyse(mirror: &Mirror, pr: &db::PullRequest) -> Result<Option<data::LeadTime>> {
+    let last = match pr.samples.last() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let merged = match &last.status {
+        Status::Merged(d) => d,
+        _ => return Ok(None),
+    };
+
+    let mut first_commits: Vec<&Sha> = vec![];
+    for sample in &pr.samples {
+        if !sample.first_commit.0.is_empty() && !first_commits.contains(&&sample.first_commit) {
+            first_commits.push(&sample.first_commit);
+        }
+    }
+
+    // Rebasing changes the first commit but not when it was authored, so any
+    // of them will do, but some may have been garbage collected.
+    let mut first_authored: Option<String> = None;
+    for sha in &first_commits {
+        if let Some(time) = mirror.time(sha, "%ad")? {
+            if first_authored.as_ref().map(|f| time < *f).unwrap_or(true) {
+                first_authored = Some(time);
+            }
+        }
+    }
+    let first_authored = match first_authored {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+
+    // Prefer the time from the merge commit so that both times have the same
+    // format and come from the same clock.
+    let merged = match &last.merge_commit {
+        Some(sha) => mirror.time(sha, "%cd")?,
+        None => None,
+    }
+    .unwrap_or_else(|| merged.date.clone());
+
+    let mut force_pushes = 0;
+    for pair in pr.samples.windows(2) {
+        let (before, after) = (&pair[0].head, &pair[1].head);
+        if before != after && mirror.is_ancestor(before, after)? == Some(false) {
+            force_pushes += 1;
+        }
+    }
+
+    Ok(Some(data::LeadTime {
+        host: pr.host.clone(),
+        pr: pr.id,
+        first_authored: Date::new(first_authored),
+        merged: Date::new(merged),
+        rebases: first_commits.len().saturating_sub(1) as u32,
+        force_pushes,
+    }))
+}
+
+fn mirror_path(repo: &Repo) -> PathBuf {
+    let host: String = repo
+        .host
+        .splitn(2, "://")
+        .last()
+        .unwrap()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Path::new(MIRROR_DIR)
+        .join(host)
+        .join(repo.owner)
+        .join(format!("{}.git", repo.name))
+}
+
+/// A bare mirror of a repo.
+pub(crate) struct Mirror {
+    path: PathBuf,
+}
+
+impl Mirror {
+    /// The mirror of `repo`, which should already have been updated by
+    /// `analyse_all`.
+    pub(crate) fn open(repo: &Repo) -> Mirror {
+        Mirror {
+            path: mirror_path(repo),
+        }
+    }
+
+    /// Clone a mirror of the repo at `url` into `path`, or update it if it
+    /// already exists.
+    fn update(url: &str, path: &Path) -> Result<Mirror> {
+        let mirror = Mirror {
+            path: path.to_owned(),
+        };
+        if path.exists() {
+            check(mirror.git(&["remote", "update", "--prune"])?)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            check(
+                Command::new("git")
+                    .args(&["clone", "--mirror", "--quiet", url])
+                    .arg(path)
+                    .output()?,
+            )?;
+        }
+        Ok(mirror)
+    }
+
+    /// A timestamp of commit `sha` in UTC, `format` should be `%ad` (authored)
+    /// or `%cd` (committed). `None` if the commit is not in the repo.
+    pub(crate) fn time(&self, sha: &Sha, format: &str) -> Result<Option<String>> {
+        let output = self.git(&[
+            "show",
+            "--no-patch",
+            "--date=iso-strict-local",
+            &format!("--format={}", format),
+            &format!("{}^{{commit}}", sha.0),
+        ])?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(
+            String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        ))
+    }
+
+    /// `None` if either commit is not in the repo.
+    pub(crate) fn is_ancestor(&self, ancestor: &Sha, descendant: &Sha) -> Result<Option<bool>> {
+        let output = self.git(&["merge-base", "--is-ancestor", &ancestor.0, &descendant.0])?;
+        Ok(match output.status.code() {
+            Some(0) => Some(true),
+            Some(1) => Some(false),
+            _ => None,
+        })
+    }
+
+    /// Every tag matching `pattern`, with the commit it points at and when it
+    /// was created (in UTC).
+    pub(crate) fn tags(&self, pattern: &str) -> Result<Vec<(String, Sha, String)>> {
+        let output = self.git(&[
+            "for-each-ref",
+            "--format=%(refname:short) %(objectname) %(*objectname) %(creatordate:iso-strict-local)",
+            &format!("refs/tags/{}", pattern),
+        ])?;
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        check(output)?;
+
+        Ok(stdout
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                match &fields[..] {
+                    // Annotated tags point at a tag object, which points at the commit.
+                    [name, _, commit, time] if !commit.is_empty() => {
+                        Some((name.to_string(), Sha(commit.to_string()), time.to_string()))
+                    }
+                    [name, commit, _, time] => {
+                        Some((name.to_string(), Sha(commit.to_string()), time.to_string()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    /// The commit tag `name` points at, `None` if there is no such tag.
+    pub(crate) fn resolve_tag(&self, name: &str) -> Result<Option<Sha>> {
+        let output = self.git(&[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/tags/{}^{{commit}}", name),
+        ])?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(Sha(String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .to_owned())))
+    }
+
+    /// The contents of the file at `path` in the default branch, `None` if
+    /// there is no such file.
+    pub(crate) fn file(&self, path: &str) -> Result<Option<String>> {
+        let output = self.git(&["show", &format!("HEAD:{}", path)])?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+
+    fn git(&self, args: &[&str]) -> Result<Output> {
+        Ok(Command::new("git")
+            .arg("-C")
+            .arg(&self.path)
+            .args(args)
+            .env("TZ", "UTC")
+            .output()?)
+    }
+}
+
+fn check(output: Output) -> Result<()> {
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(GhvError::GitError(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Run git in `dir` with fixed dates and identity.
+    fn git(dir: &Path, args: &[&str], date: &str) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(&["-c", "user.name=Bob", "-c", "user.email=bob@example.com"])
+            .args(args)
+            .env("GIT_AUTHOR_DATE", date)
+            .env("GIT_COMMITTER_DATE", date)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    fn sample(head: &str, first_commit: &str, status: Status) -> db::Sample {
+        db::Sample {
+            time: Date::new(String::new()),
+            status,
+            commits: 1,
+            additions: 0,
+            deletions: 0,
+            changed_files: 0,
+            review_comments: 0,
+            first_commit: Sha(first_commit.to_owned()),
+            head: Sha(head.to_owned()),
+            merge_commit: None,
+            draft: false,
+            milestone: None,
+        }
+    }
+
+    #[test]
+    fn test_analyse() -> Result<()> {
+        let dir = env::temp_dir().join(format!("ghv-git-test-{}", process::id()));
+        let origin = dir.join("origin");
+        fs::create_dir_all(&origin)?;
+        git(&origin, &["init", "--quiet"], "");
+        git(
+            &origin,
+            &["commit", "--allow-empty", "-m", "base"],
+            "2019-05-01T00:00:00Z",
+        );
+        git(&origin, &["branch", "-M", "master"], "");
+
+        // A PR branch which is force-pushed (amended), then rebased.
+        git(&origin, &["checkout", "--quiet", "-b", "pr"], "");
+        git(
+            &origin,
+            &["commit", "--allow-empty", "-m", "one"],
+            "2019-05-10T10:00:00+02:00",
+        );
+        let first = git(&origin, &["rev-parse", "HEAD"], "");
+        git(
+            &origin,
+            &["commit", "--allow-empty", "-m", "two"],
+            "2019-05-11T00:00:00Z",
+        );
+        let head1 = git(&origin, &["rev-parse", "HEAD"], "");
+        git(
+            &origin,
+            &["commit", "--amend", "--allow-empty", "-m", "two!"],
+            "2019-05-12T00:00:00Z",
+        );
+        let head2 = git(&origin, &["rev-parse", "HEAD"], "");
+        // Pretend the mirror fetched the old heads before they were replaced.
+        git(&origin, &["tag", "old1", head1.as_str()], "");
+        git(&origin, &["tag", "old2", head2.as_str()], "");
+        git(&origin, &["checkout", "--quiet", "master"], "");
+        git(
+            &origin,
+            &["commit", "--allow-empty", "-m", "other"],
+            "2019-05-13T00:00:00Z",
+        );
+        git(&origin, &["checkout", "--quiet", "pr"], "");
+        git(
+            &origin,
+            &["rebase", "--quiet", "master"],
+            "2019-05-14T00:00:00Z",
+        );
+        let first_rebased = git(&origin, &["rev-parse", "HEAD~1"], "");
+        let head3 = git(&origin, &["rev-parse", "HEAD"], "");
+        git(&origin, &["checkout", "--quiet", "master"], "");
+        git(
+            &origin,
+            &["merge", "--no-ff", "--quiet", "pr", "-m", "merge"],
+            "2019-05-15T12:00:00Z",
+        );
+        let merge = git(&origin, &["rev-parse", "HEAD"], "");
+        fs::write(origin.join("CODEOWNERS"), "* @nrc\n")?;
+        git(&origin, &["add", "CODEOWNERS"], "");
+        git(
+            &origin,
+            &["commit", "--quiet", "-m", "owners"],
+            "2019-05-16T00:00:00Z",
+        );
+
+        let mirror = Mirror::update(origin.to_str().unwrap(), &dir.join("mirror.git"))?;
+        let merged = Status::Merged(Date::new("2019-05-15T12:00:01Z".to_owned()));
+        let mut samples = vec![
+            sample(&head1, &first, Status::Open),
+            sample(&head2, &first, Status::Open),
+            sample(&head3, &first_rebased, Status::Open),
+            sample(&head3, &first_rebased, merged),
+        ];
+        samples[3].merge_commit = Some(Sha(merge));
+        let pr = db::PullRequest {
+            host: "https://api.github.com".to_owned(),
+            id: 1,
+            repo: "nrc/gh-velocity".to_owned(),
+            number: 101,
+            title: String::new(),
+            body: String::new(),
+            author: db::User::default(),
+            created: Date::new("2019-05-12T00:00:00Z".to_owned()),
+            url: String::new(),
+            labels: vec![],
+            samples,
+        };
+
+        let lead_time = analyse(&mirror, &pr)?.unwrap();
+        assert_eq!(lead_time.first_authored.date, "2019-05-10T08:00:00+00:00");
+        assert_eq!(lead_time.merged.date, "2019-05-15T12:00:00+00:00");
+        assert_eq!(lead_time.rebases, 1);
+        assert_eq!(lead_time.force_pushes, 2);
+
+        let tags = mirror.tags("old*")?;
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].0, "old1");
+        assert_eq!(tags[0].1, Sha(head1.clone()));
+        assert_eq!(tags[0].2, "2019-05-11T00:00:00+00:00");
+        assert_eq!(mirror.resolve_tag("old2")?, Some(Sha(head2.clone())));
+        assert_eq!(mirror.resolve_tag("missing")?, None);
+        assert_eq!(mirror.file("CODEOWNERS")?, Some("* @nrc\n".to_owned()));
+        assert_eq!(mirror.file("missing")?, None);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let repo = Repo {
+            forge: crate::config::Forge::GitHub,
+            host: "https://github.example.com/api/v3",
+            ca_cert: None,
+            clone_url: None,
+            owner: "nrc",
+            name: "gh-velocity",
+            deploys: crate::config::Deploys::None,
+            areas: crate::config::Areas::None,
+        };
+        assert_eq!(
+            mirror_path(&repo),
+            Path::new(MIRROR_DIR)
+                .join("github.example.com_api_v3")
+                .join("nrc")
+                .join("gh-velocity.git")
+        );
+    }
+}
@@ -0,0 +1,596 @@
+//! Pull requests from Gitea or Forgejo, whose API is close to GitHub's.
+
+use crate::auth::http_client;
+use crate::config::Repo;
+use crate::data::{self, Date, Sha, Status};
+use crate::source::{
+    closed_since_sampled, is_bot, record_activity, record_issue_sample, record_sample,
+    saturating_from, saturating_from_opt, statuses_to_checks, Source,
+};
+use crate::{db, dora, Result};
+
+use reqwest::Client;
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use url::form_urlencoded;
+
+/// Items per page to ask for when listing; Gitea may return fewer, up to its
+/// `MAX_RESPONSE_ITEMS`.
+const PAGE_SIZE: usize = 50;
+/// Gitea's default `WORK_IN_PROGRESS_PREFIXES`, which mark a PR as a draft.
+const WIP_PREFIXES: &[&str] = &["WIP:", "[WIP]"];
+
+pub struct GiteaSource {
+    repo: &'static Repo,
+    token: &'static str,
+}
+
+impl GiteaSource {
+    pub fn new(repo: &'static Repo, token: &'static str) -> GiteaSource {
+        GiteaSource { repo, token }
+    }
+
+    fn record_all(&self, client: &Client, conn: &Connection) -> Result<()> {
+        let pulls: Vec<Pull> = self.get_all(client, "pulls?state=open")?;
+        let open: Vec<u32> = pulls.iter().map(|p| saturating_from(p.number)).collect();
+        for pull in pulls {
+            // TODO handle any errors
+            let _ = self.record_data(client, conn, pull);
+        }
+        for number in closed_since_sampled(conn, self.repo, &open)? {
+            // TODO handle any errors
+            let _ = self
+                .get(client, &format!("pulls/{}", number))
+                .and_then(|pull| self.record_data(client, conn, pull));
+        }
+        Ok(())
+    }
+
+    /// Record issues labelled as incidents, see `FailureRule::IssueLabel`.
+    fn record_incidents(&self, client: &Client, conn: &Connection) -> Result<()> {
+        for label in dora::incident_labels() {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .append_pair("labels", label)
+                .finish();
+            let issues: Vec<Issue> =
+                self.get_all(client, &format!("issues?state=all&type=issues&{}", query))?;
+            for issue in issues {
+                data::Incident {
+                    host: self.repo.host.to_owned(),
+                    repo: self.repo.full_name(),
+                    number: saturating_from(issue.number),
+                    label: label.to_owned(),
+                    title: issue.title,
+                    body: issue.body,
+                    opened: Date::new(issue.created_at),
+                    closed: issue.closed_at.map(Date::new),
+                }
+                .insert_into(conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_data(&self, client: &Client, conn: &Connection, pull: Pull) -> Result<()> {
+        let commits = self.get_all(client, &format!("pulls/{}/commits", pull.number))?;
+        let reviews = self.get_all(client, &format!("pulls/{}/reviews", pull.number))?;
+        let files = self.get_all(client, &format!("pulls/{}/files", pull.number))?;
+        let head = Sha(pull.head.sha.clone());
+        // Listed newest first.
+        let mut statuses: Vec<CommitStatus> =
+            self.get_all(client, &format!("commits/{}/statuses", head.0))?;
+        statuses.reverse();
+
+        let activity = to_reviews(self.repo, saturating_from(pull.id), &reviews);
+        let checks = statuses_to_checks(
+            self.repo.host,
+            saturating_from(pull.id),
+            &head,
+            statuses
+                .into_iter()
+                .map(|s| (s.context, s.status, s.created_at))
+                .collect(),
+        );
+        let sample = to_sample(self.repo, pull, commits, reviews, files);
+
+        // Gitea doesn't record when a PR becomes (or stops being) a draft, so
+        // we infer it from consecutive samples.
+        let mut draft_events = vec![];
+        let was_draft = db::last_sample_draft(conn, &sample.host, sample.pr.id)?;
+        if was_draft.is_some() && was_draft != Some(sample.draft) {
+            draft_events.push(data::DraftEvent {
+                host: sample.host.clone(),
+                pr: sample.pr.id,
+                draft: sample.draft,
+                time: Date::new(sample.time.date.clone()),
+            });
+        }
+
+        record_sample(conn, &sample)?;
+        record_activity(conn, &activity, &draft_events, &checks)
+    }
+
+    /// Record a sample for every issue updated since we last sampled issues.
+    fn record_issues(&self, client: &Client, conn: &Connection) -> Result<()> {
+        let mut path = "issues?state=all&type=issues".to_owned();
+        if let Some(since) = db::last_issue_sample(conn, self.repo.host, &self.repo.full_name())? {
+            path.push_str(&format!("&since={}", since.replace('+', "%2B")));
+        }
+        let issues: Vec<Issue> = self.get_all(client, &path)?;
+        for issue in issues {
+            record_issue_sample(
+                conn,
+                &data::IssueSample {
+                    host: self.repo.host.to_owned(),
+                    time: Date::new(issue.updated_at),
+                    issue: data::Issue {
+                        host: self.repo.host.to_owned(),
+                        id: saturating_from(issue.id),
+                        repo: self.repo.full_name(),
+                        number: saturating_from(issue.number),
+                        title: issue.title,
+                        body: issue.body,
+                        created: Date::new(issue.created_at),
+                        url: issue.html_url,
+                    },
+                    closed: issue.closed_at.map(Date::new),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get<T: DeserializeOwned>(&self, client: &Client, path: &str) -> Result<T> {
+        Ok(client
+            .get(&format!("{}/{}", self.repo.api_url(), path))
+            .header("Authorization", format!("token {}", self.token))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    /// Get every page of a list, using `X-Total-Count` to know when we're done
+    /// since a short page doesn't mean it's the last.
+    fn get_all<T: DeserializeOwned>(&self, client: &Client, path: &str) -> Result<Vec<T>> {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let mut result = vec![];
+        for page in 1.. {
+            let mut response = client
+                .get(&format!(
+                    "{}/{}{}limit={}&page={}",
+                    self.repo.api_url(),
+                    path,
+                    separator,
+                    PAGE_SIZE,
+                    page
+                ))
+                .header("Authorization", format!("token {}", self.token))
+                .send()?
+                .error_for_status()?;
+            let total: Option<usize> = response
+                .headers()
+                .get("X-Total-Count")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            let mut items: Vec<T> = response.json()?;
+            let done = items.is_empty() || total.map_or(false, |t| result.len() + items.len() >= t);
+            result.append(&mut items);
+            if done {
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Source for GiteaSource {
+    fn update(&self) -> Result<()> {
+        let client = http_client(self.repo)?;
+        let conn = db::connection()?;
+        self.record_all(&client, &conn)?;
+        self.record_incidents(&client, &conn)?;
+        self.record_issues(&client, &conn)
+    }
+}
+
+fn to_sample(
+    repo: &Repo,
+    pull: Pull,
+    commits: Vec<Commit>,
+    reviews: Vec<Review>,
+    files: Vec<ChangedFile>,
+) -> data::Sample {
+    let author = data::User {
+        host: repo.host.to_owned(),
+        id: saturating_from(pull.user.id),
+        bot: is_bot(&pull.user.login, false),
+        username: pull.user.login,
+        url: pull.user.html_url,
+    };
+
+    let pr = data::PullRequest {
+        host: repo.host.to_owned(),
+        id: saturating_from(pull.id),
+        repo: repo.full_name(),
+        number: saturating_from(pull.number),
+        title: pull.title,
+        body: pull.body,
+        author,
+        created: Date::new(pull.created_at),
+        url: pull.html_url,
+        labels: pull
+            .labels
+            .into_iter()
+            .map(|l| data::Label {
+                host: repo.host.to_owned(),
+                repo: repo.full_name(),
+                name: l.name,
+                color: l.color,
+            })
+            .collect(),
+    };
+
+    // Only reported by Gitea 1.22 and later.
+    let draft = pull.draft.unwrap_or(false)
+        || WIP_PREFIXES
+            .iter()
+            .any(|p| pr.title.to_uppercase().starts_with(p));
+
+    // Unlike GitHub, closing a PR by merging it also sets `closed_at`.
+    let closed_at = if pull.merged_at.is_some() {
+        None
+    } else {
+        pull.closed_at
+    };
+
+    data::Sample {
+        host: repo.host.to_owned(),
+        time: Date::new(pull.updated_at),
+        pr,
+        status: Status::from_opts(closed_at, pull.merged_at),
+        commits: saturating_from(commits.len()),
+        additions: saturating_from_opt(pull.additions),
+        deletions: saturating_from_opt(pull.deletions),
+        changed_files: saturating_from_opt(pull.changed_files),
+        review_comments: reviews.iter().map(|r| r.comments_count).sum(),
+        // Commits are listed newest first.
+        first_commit: Sha(commits.last().map(|c| c.sha.clone()).unwrap_or_default()),
+        head: Sha(pull.head.sha),
+        merge_commit: pull.merge_commit_sha.map(Sha),
+        draft,
+        milestone: pull.milestone.map(|m| m.title),
+        files: files.into_iter().map(|f| f.filename).collect(),
+    }
+}
+
+fn to_reviews(repo: &Repo, pr: u32, reviews: &[Review]) -> Vec<data::Review> {
+    reviews
+        .iter()
+        .filter(|r| r.state != "PENDING")
+        .map(|r| data::Review {
+            host: repo.host.to_owned(),
+            pr,
+            reviewer: r.user.login.clone(),
+            approved: r.state == "APPROVED",
+            submitted: Date::new(r.submitted_at.clone()),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct Pull {
+    id: u64,
+    number: u64,
+    title: String,
+    body: String,
+    user: User,
+    created_at: String,
+    updated_at: String,
+    closed_at: Option<String>,
+    merged_at: Option<String>,
+    html_url: String,
+    head: Branch,
+    merge_commit_sha: Option<String>,
+    labels: Vec<Label>,
+    milestone: Option<Milestone>,
+    draft: Option<bool>,
+    // Only reported by Gitea 1.18 and later.
+    additions: Option<u64>,
+    deletions: Option<u64>,
+    changed_files: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: u64,
+    login: String,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    id: u64,
+    number: u64,
+    title: String,
+    body: String,
+    created_at: String,
+    updated_at: String,
+    closed_at: Option<String>,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    name: String,
+    color: String,
+}
+
+#[derive(Deserialize)]
+struct Milestone {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct Branch {
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct CommitStatus {
+    context: String,
+    status: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+struct ChangedFile {
+    filename: String,
+}
+
+#[derive(Deserialize)]
+struct Review {
+    user: User,
+    state: String,
+    submitted_at: String,
+    comments_count: u32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Areas, Deploys, Forge};
+    use std::thread;
+    use tiny_http::{Header, Response, Server};
+
+    /// Serve canned responses for a repo with a single open PR, #3, and a merged
+    /// PR, #5.
+    fn fake_gitea() -> &'static Repo {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let host = format!("http://{}/api/v1", server.server_addr());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let url = request.url().to_owned();
+                let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+                if path == "/api/v1/repos/owner/repo/labels" {
+                    // One item per page, as if `limit` had been clamped.
+                    let page = query.split("page=").nth(1).unwrap_or("");
+                    let body = if page == "1" || page == "2" {
+                        format!("[{}]", page)
+                    } else {
+                        "[]".to_owned()
+                    };
+                    let header = Header::from_bytes("X-Total-Count", "2").unwrap();
+                    let _ = request.respond(Response::from_string(body).with_header(header));
+                    continue;
+                }
+                let body = match path {
+                    "/api/v1/repos/owner/repo/pulls/5" => MERGED_PULL,
+                    _ if !query.contains("page=1") => "[]",
+                    "/api/v1/repos/owner/repo/pulls" => PULLS,
+                    "/api/v1/repos/owner/repo/pulls/3/commits" => COMMITS,
+                    "/api/v1/repos/owner/repo/pulls/3/reviews" => REVIEWS,
+                    "/api/v1/repos/owner/repo/pulls/3/files" => FILES,
+                    "/api/v1/repos/owner/repo/commits/def/statuses" => STATUSES,
+                    "/api/v1/repos/owner/repo/issues" => ISSUES,
+                    "/api/v1/repos/owner/repo/pulls/5/commits"
+                    | "/api/v1/repos/owner/repo/pulls/5/reviews"
+                    | "/api/v1/repos/owner/repo/pulls/5/files"
+                    | "/api/v1/repos/owner/repo/commits/ghi/statuses" => "[]",
+                    _ => {
+                        let _ = request.respond(Response::empty(404));
+                        continue;
+                    }
+                };
+                let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+                let _ = request.respond(Response::from_string(body).with_header(header));
+            }
+        });
+
+        Box::leak(Box::new(Repo {
+            forge: Forge::Gitea { token: "" },
+            host: Box::leak(host.into_boxed_str()),
+            ca_cert: None,
+            clone_url: None,
+            owner: "owner",
+            name: "repo",
+            deploys: Deploys::None,
+            areas: Areas::None,
+        }))
+    }
+
+    const PULLS: &str = r#"[{
+        "id": 1234,
+        "number": 3,
+        "title": "WIP: Fix the thing",
+        "body": "It was broken, fixes #7",
+        "state": "open",
+        "user": {"id": 42, "login": "bob", "html_url": "https://gitea.example.com/bob"},
+        "created_at": "2019-05-14T09:15:13Z",
+        "updated_at": "2019-05-15T09:25:34Z",
+        "closed_at": null,
+        "merged_at": null,
+        "html_url": "https://gitea.example.com/owner/repo/pulls/3",
+        "head": {"ref": "fix", "sha": "def"},
+        "merge_commit_sha": null,
+        "labels": [{"id": 7, "name": "hotfix", "color": "ee0701"}],
+        "milestone": null,
+        "additions": 10,
+        "deletions": 4,
+        "changed_files": 2
+    }]"#;
+    /// #5 as it is now, and as it was when we last sampled it.
+    const MERGED_PULL: &str = r#"{
+        "id": 5678,
+        "number": 5,
+        "title": "Another fix",
+        "body": "",
+        "state": "closed",
+        "user": {"id": 42, "login": "bob", "html_url": "https://gitea.example.com/bob"},
+        "created_at": "2019-05-13T09:00:00Z",
+        "updated_at": "2019-05-15T10:00:00Z",
+        "closed_at": "2019-05-15T10:00:00Z",
+        "merged_at": "2019-05-15T10:00:00Z",
+        "html_url": "https://gitea.example.com/owner/repo/pulls/5",
+        "head": {"ref": "other", "sha": "ghi"},
+        "merge_commit_sha": "jkl",
+        "labels": [],
+        "milestone": null
+    }"#;
+    const OPEN_PULL: &str = r#"{
+        "id": 5678,
+        "number": 5,
+        "title": "Another fix",
+        "body": "",
+        "state": "open",
+        "user": {"id": 42, "login": "bob", "html_url": "https://gitea.example.com/bob"},
+        "created_at": "2019-05-13T09:00:00Z",
+        "updated_at": "2019-05-14T09:00:00Z",
+        "closed_at": null,
+        "merged_at": null,
+        "html_url": "https://gitea.example.com/owner/repo/pulls/5",
+        "head": {"ref": "other", "sha": "ghi"},
+        "merge_commit_sha": null,
+        "labels": [],
+        "milestone": null
+    }"#;
+    const COMMITS: &str = r#"[{"sha": "def"}, {"sha": "abc"}]"#;
+    const REVIEWS: &str = r#"[
+        {"user": {"id": 43, "login": "alice", "html_url": ""}, "state": "REQUEST_CHANGES", "submitted_at": "2019-05-14T10:00:00Z", "comments_count": 2},
+        {"user": {"id": 43, "login": "alice", "html_url": ""}, "state": "APPROVED", "submitted_at": "2019-05-15T09:00:00Z", "comments_count": 1}
+    ]"#;
+
+    const STATUSES: &str = r#"[
+        {"id": 2, "context": "ci/test", "status": "success", "created_at": "2019-05-15T09:10:00Z"},
+        {"id": 1, "context": "ci/test", "status": "pending", "created_at": "2019-05-15T09:00:00Z"}
+    ]"#;
+
+    const ISSUES: &str = r#"[{
+        "id": 99,
+        "number": 7,
+        "title": "The thing is broken",
+        "body": "",
+        "html_url": "https://gitea.example.com/owner/repo/issues/7",
+        "created_at": "2019-05-10T09:00:00+02:00",
+        "updated_at": "2019-05-14T09:00:00+02:00",
+        "closed_at": null
+    }]"#;
+
+    const FILES: &str = r#"[
+        {"filename": "src/lib.rs", "status": "changed", "additions": 8, "deletions": 4, "changes": 12},
+        {"filename": "README.md", "status": "added", "additions": 2, "deletions": 0, "changes": 2}
+    ]"#;
+
+    #[test]
+    fn test_get_all() -> Result<()> {
+        let repo = fake_gitea();
+        let source = GiteaSource::new(repo, "");
+        let labels: Vec<u32> = source.get_all(&http_client(repo)?, "labels")?;
+        assert_eq!(labels, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_all() -> Result<()> {
+        let repo = fake_gitea();
+        let source = GiteaSource::new(repo, "");
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+
+        // We last saw #5 open, but it has been merged since, so isn't listed.
+        let pull: Pull = serde_json::from_str(OPEN_PULL)?;
+        record_sample(&conn, &to_sample(repo, pull, vec![], vec![], vec![]))?;
+
+        source.record_all(&http_client(repo)?, &conn)?;
+
+        let prs = db::read_prs(&conn, db::all_time())?;
+        assert_eq!(prs.len(), 2);
+        assert_eq!(prs[1].number, 5);
+        assert_eq!(
+            prs[1].samples.last().unwrap().status,
+            Status::Merged(Date::new("2019-05-15T10:00:00Z".to_owned()))
+        );
+        let prs: Vec<_> = prs.into_iter().filter(|pr| pr.number == 3).collect();
+        assert_eq!(prs[0].host, repo.host);
+        assert_eq!(prs[0].repo, "owner/repo");
+        assert_eq!(prs[0].number, 3);
+        assert_eq!(prs[0].author.username, "bob");
+        assert_eq!(prs[0].labels, vec!["hotfix".to_owned()]);
+        assert_eq!(prs[0].samples.len(), 1);
+
+        let sample = &prs[0].samples[0];
+        assert_eq!(sample.status, Status::Open);
+        assert_eq!(sample.commits, 2);
+        assert_eq!(sample.additions, 10);
+        assert_eq!(sample.deletions, 4);
+        assert_eq!(sample.changed_files, 2);
+        assert_eq!(sample.review_comments, 3);
+        assert_eq!(sample.first_commit, Sha("abc".to_owned()));
+        assert_eq!(sample.head, Sha("def".to_owned()));
+        assert!(sample.draft);
+
+        let paths = db::changed_paths(&conn, repo.host, "owner/repo")?;
+        assert_eq!(paths, vec!["README.md", "src/lib.rs"]);
+
+        let approvals: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM review WHERE pr = 1234 AND approved",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(approvals, 1);
+
+        source.record_issues(&http_client(repo)?, &conn)?;
+        assert_eq!(
+            db::last_issue_sample(&conn, repo.host, "owner/repo")?,
+            Some("2019-05-14T07:00:01Z".to_owned())
+        );
+        let fixed: u32 = conn.query_row(
+            "SELECT issue FROM pr_issue WHERE pr = 1234",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(fixed, 7);
+        let days = db::open_prs_per_day(&conn, &db::PrFilter::default())?;
+        assert_eq!(days.last().unwrap().open_issues, 1);
+
+        let check: (String, String, String) = conn.query_row(
+            "SELECT started, finished, conclusion FROM check_run WHERE pr = 1234 AND sha = 'def'",
+            rusqlite::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(
+            check,
+            (
+                "2019-05-15T09:00:00Z".to_owned(),
+                "2019-05-15T09:10:00Z".to_owned(),
+                "success".to_owned()
+            )
+        );
+        Ok(())
+    }
+}
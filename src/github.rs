@@ -1,48 +1,287 @@
-use crate::config::{ACCESS_TOKEN, OWNER, REPO, USER_AGENT};
+use crate::auth::{http_client, Auth};
+use crate::config::{Deploys, Repo, USER_AGENT};
 use crate::data::{self, Date, Sha, Status};
-use crate::{db, Result};
+use crate::source::{
+    self, is_bot, record_activity, saturating_from, saturating_from_opt, statuses_to_checks, Source,
+};
+use crate::{db, dora, Result};
 
 use futures::compat::Compat01As03;
+use futures::future;
 use futures::prelude::*;
+use futures::stream;
 use hubcaps::{
     self,
-    issues::State,
+    issues::{IssueListOptions, State},
     pulls::{Pull, PullListOptions},
-    Credentials, Github,
+    Github,
 };
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, TlsConnector};
+use reqwest::header;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
 use std::convert::TryFrom;
+use std::fs;
+
+type Client = Github<HttpsConnector<HttpConnector>>;
 
-pub fn update_from_repo() {
-    // TODO handle any errors
-    futures::executor::block_on(open_pull_requests().then(record_data).collect::<Vec<_>>());
-    // TODO for any PR which we think is still open, but isn't in the above result, query GitHub to get a sample for when it was closed/merged
+pub struct GitHubSource {
+    auth: Auth,
+    repo: &'static Repo,
 }
 
-fn open_pull_requests() -> impl Stream<Item = hubcaps::Result<Pull>> {
-    let github = Github::new(
-        USER_AGENT.to_owned(),
-        Credentials::Token(ACCESS_TOKEN.to_owned()),
-    );
-    let opts = PullListOptions::builder().state(State::Open).build();
-    Compat01As03::new(
+impl GitHubSource {
+    pub fn new(auth: Auth, repo: &'static Repo) -> GitHubSource {
+        GitHubSource { auth, repo }
+    }
+}
+
+impl Source for GitHubSource {
+    fn update(&self) -> Result<()> {
+        let github = client(&self.auth, self.repo)?;
+        let repo = self.repo;
+        let pulls: Vec<_> =
+            futures::executor::block_on(open_pull_requests(&github, repo).collect());
+        let open: Option<Vec<u32>> = pulls
+            .iter()
+            .map(|p| p.as_ref().ok().map(|p| saturating_from(p.number)))
+            .collect();
+        // TODO handle any errors
+        futures::executor::block_on(
+            stream::iter(pulls)
+                .then(|p| record_data(self.auth.clone(), github.clone(), repo, p))
+                .collect::<Vec<_>>(),
+        );
+        // We can only tell which PRs have closed if we saw every open PR.
+        if let Some(open) = open {
+            let conn = db::connection()?;
+            for number in source::closed_since_sampled(&conn, repo, &open)? {
+                // TODO handle any errors
+                let _ = update_pull(&self.auth, repo, u64::from(number));
+            }
+        }
+        // Record everything we can, even if one kind of data fails.
+        let deployments = record_deployments(&self.auth, repo);
+        let incidents = futures::executor::block_on(record_incidents(&github, repo));
+        let issues = futures::executor::block_on(record_issues(&github, repo));
+        deployments.and(incidents).and(issues)
+    }
+}
+
+/// Record releases or successful deployments, depending on `repo.deploys`.
+fn record_deployments(auth: &Auth, repo: &Repo) -> Result<()> {
+    let mut deployments = vec![];
+    match repo.deploys {
+        Deploys::Releases => {
+            let releases: Vec<Release> = get_all(auth, repo, "releases", &[])?;
+            for release in releases {
+                let published_at = match release.published_at {
+                    Some(p) if !release.draft => p,
+                    _ => continue,
+                };
+                deployments.push(data::Deployment {
+                    host: repo.host.to_owned(),
+                    repo: repo.full_name(),
+                    name: release.tag_name,
+                    // Looked up in the mirror, if we have one.
+                    sha: Sha(String::new()),
+                    time: Date::new(published_at),
+                });
+            }
+        }
+        Deploys::Deployments { environment } => {
+            let list: Vec<Deployment> =
+                get_all(auth, repo, "deployments", &[("environment", environment)])?;
+            for deployment in list {
+                let statuses: Vec<DeploymentStatus> = get_all(
+                    auth,
+                    repo,
+                    &format!("deployments/{}/statuses", deployment.id),
+                    &[],
+                )?;
+                if let Some(time) = deployed_at(statuses) {
+                    deployments.push(data::Deployment {
+                        host: repo.host.to_owned(),
+                        repo: repo.full_name(),
+                        name: deployment.id.to_string(),
+                        sha: Sha(deployment.sha),
+                        time: Date::new(time),
+                    });
+                }
+            }
+        }
+        Deploys::None | Deploys::Tags { .. } => {}
+    }
+
+    let conn = db::connection()?;
+    for deployment in deployments {
+        deployment.insert_into(&conn)?;
+    }
+    Ok(())
+}
+
+/// A deployment is made when it first succeeds. Other states (including ones
+/// we don't know about) are ignored.
+fn deployed_at(statuses: Vec<DeploymentStatus>) -> Option<String> {
+    statuses
+        .into_iter()
+        .filter(|s| s.state == "success")
+        .map(|s| s.created_at)
+        .min()
+}
+
+/// Record issues labelled as incidents, see `FailureRule::IssueLabel`.
+async fn record_incidents(github: &Client, repo: &Repo) -> Result<()> {
+    let conn = db::connection()?;
+    for label in dora::incident_labels() {
+        let opts = IssueListOptions::builder()
+            .state(State::All)
+            .labels(vec![label])
+            .build();
+        let issues = Compat01As03::new(github.repo(repo.owner, repo.name).issues().iter(&opts))
+            .collect::<Vec<_>>()
+            .await;
+        for issue in issues {
+            let issue = issue?;
+            // GitHub lists PRs as issues too.
+            if issue.pull_request.is_some() {
+                continue;
+            }
+            data::Incident {
+                host: repo.host.to_owned(),
+                repo: repo.full_name(),
+                number: saturating_from(issue.number),
+                label: label.to_owned(),
+                title: issue.title,
+                body: issue.body.unwrap_or_else(String::new),
+                opened: Date::new(issue.created_at),
+                closed: issue.closed_at.map(Date::new),
+            }
+            .insert_into(&conn)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record a sample for every issue updated since we last sampled issues.
+async fn record_issues(github: &Client, repo: &Repo) -> Result<()> {
+    let conn = db::connection()?;
+    let mut opts = IssueListOptions::builder();
+    opts.state(State::All);
+    if let Some(since) = db::last_issue_sample(&conn, repo.host, &repo.full_name())? {
+        opts.since(since);
+    }
+    let issues = Compat01As03::new(
         github
-            .repo(OWNER.to_owned(), REPO.to_owned())
-            .pulls()
-            .iter(&opts),
+            .repo(repo.owner, repo.name)
+            .issues()
+            .iter(&opts.build()),
     )
+    .collect::<Vec<_>>()
+    .await;
+    for issue in issues {
+        let issue = issue?;
+        // GitHub lists PRs as issues too.
+        if issue.pull_request.is_some() {
+            continue;
+        }
+        source::record_issue_sample(
+            &conn,
+            &data::IssueSample {
+                host: repo.host.to_owned(),
+                time: Date::new(issue.updated_at),
+                issue: data::Issue {
+                    host: repo.host.to_owned(),
+                    id: saturating_from(issue.id),
+                    repo: repo.full_name(),
+                    number: saturating_from(issue.number),
+                    title: issue.title,
+                    body: issue.body.unwrap_or_else(String::new),
+                    created: Date::new(issue.created_at),
+                    url: issue.html_url,
+                },
+                closed: issue.closed_at.map(Date::new),
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Record a sample for a single PR, e.g., in response to a webhook delivery.
+pub fn update_pull(auth: &Auth, repo: &'static Repo, number: u64) -> Result<()> {
+    let github = client(auth, repo)?;
+    let pull = Compat01As03::new(github.repo(repo.owner, repo.name).pulls().get(number).get());
+    futures::executor::block_on(pull.then(|p| record_data(auth.clone(), github.clone(), repo, p)))
+}
+
+/// Record a sample for every open PR whose head is `branch`, e.g., after a push.
+pub fn update_branch(auth: &Auth, repo: &'static Repo, branch: &str) -> Result<()> {
+    let github = client(auth, repo)?;
+    let pulls = open_pull_requests(&github, repo).filter(|p| {
+        future::ready(match p {
+            Ok(p) => p.head.commit_ref == branch,
+            Err(_) => true,
+        })
+    });
+    futures::executor::block_on(
+        pulls
+            .then(|p| record_data(auth.clone(), github.clone(), repo, p))
+            .collect::<Vec<_>>(),
+    )
+    .into_iter()
+    .collect()
+}
+
+fn client(auth: &Auth, repo: &Repo) -> Result<Client> {
+    let credentials = auth.credentials(repo)?;
+    let path = match repo.ca_cert {
+        Some(path) => path,
+        None => return Ok(Github::host(repo.host, USER_AGENT, credentials)),
+    };
+
+    let cert = Certificate::from_pem(&fs::read(path)?)?;
+    let tls = TlsConnector::builder().add_root_certificate(cert).build()?;
+    let mut http = HttpConnector::new(4);
+    http.enforce_http(false);
+    let http = hyper::Client::builder()
+        .keep_alive(true)
+        .build(HttpsConnector::from((http, tls)));
+    Ok(Github::custom(repo.host, USER_AGENT, credentials, http))
+}
+
+fn open_pull_requests(github: &Client, repo: &Repo) -> impl Stream<Item = hubcaps::Result<Pull>> {
+    let opts = PullListOptions::builder().state(State::Open).build();
+    Compat01As03::new(github.repo(repo.owner, repo.name).pulls().iter(&opts))
 }
 
-async fn record_data(p: hubcaps::Result<Pull>) -> Result<()> {
+async fn record_data(
+    auth: Auth,
+    github: Client,
+    repo: &'static Repo,
+    p: hubcaps::Result<Pull>,
+) -> Result<()> {
     let p = p?;
+    let reviews: Vec<Review> = get_all(&auth, repo, &format!("pulls/{}/reviews", p.number), &[])?;
+    let events: Vec<Event> = get_all(&auth, repo, &format!("issues/{}/events", p.number), &[])?;
+    let head = Sha(p.head.sha.clone());
+    let runs = get_pages(
+        &auth,
+        repo,
+        &format!("commits/{}/check-runs", head.0),
+        &[("filter", "all")],
+        |page: CheckRuns| page.check_runs,
+    )?;
+    let statuses: Vec<CommitStatus> =
+        get_all(&auth, repo, &format!("commits/{}/statuses", head.0), &[])?;
+    let (reviews, draft_events) = to_activity(repo, saturating_from(p.id), reviews, events);
+    let checks = to_checks(repo, saturating_from(p.id), &head, runs, statuses);
+    record_activity(&db::connection()?, &reviews, &draft_events, &checks)?;
+    let extra: PullExtra = get(&auth, repo, &format!("pulls/{}", p.number))?;
+    let files: Vec<File> = get_all(&auth, repo, &format!("pulls/{}/files", p.number), &[])?;
 
-    let github = Github::new(
-        USER_AGENT.to_owned(),
-        Credentials::Token(ACCESS_TOKEN.to_owned()),
-    );
-    let pull = github
-        .repo(OWNER.to_owned(), REPO.to_owned())
-        .pulls()
-        .get(p.number);
+    let pull = github.repo(repo.owner, repo.name).pulls().get(p.number);
     let mut commits = Compat01As03::new(pull.commits().iter());
     let first_commit = commits.next().await;
     let first_sha = first_commit
@@ -51,35 +290,287 @@ async fn record_data(p: hubcaps::Result<Pull>) -> Result<()> {
     let review_comments = Compat01As03::new(pull.review_comments().list())
         .await?
         .len();
-    record_sample::<db::DeployConnProvider>(p, first_sha, review_comments)
+    record_sample::<db::DeployConnProvider>(repo, p, first_sha, review_comments, extra, files)
+}
+
+fn to_activity(
+    repo: &Repo,
+    pr: u32,
+    reviews: Vec<Review>,
+    events: Vec<Event>,
+) -> (Vec<data::Review>, Vec<data::DraftEvent>) {
+    let reviews = reviews
+        .into_iter()
+        .filter_map(|r| {
+            // Pending reviews haven't been submitted yet.
+            Some(data::Review {
+                host: repo.host.to_owned(),
+                pr,
+                reviewer: r.user.map(|u| u.login).unwrap_or_default(),
+                approved: r.state == "APPROVED",
+                submitted: Date::new(r.submitted_at?),
+            })
+        })
+        .collect();
+    let draft_events = events
+        .into_iter()
+        .filter_map(|e| {
+            let draft = match &*e.event {
+                "convert_to_draft" => true,
+                "ready_for_review" => false,
+                _ => return None,
+            };
+            Some(data::DraftEvent {
+                host: repo.host.to_owned(),
+                pr,
+                draft,
+                time: Date::new(e.created_at),
+            })
+        })
+        .collect();
+    (reviews, draft_events)
+}
+
+/// CI runs of the PR's head commit `sha`, from both the Checks API and commit
+/// statuses.
+fn to_checks(
+    repo: &Repo,
+    pr: u32,
+    sha: &Sha,
+    runs: Vec<CheckRun>,
+    mut statuses: Vec<CommitStatus>,
+) -> Vec<data::CheckRun> {
+    let mut checks: Vec<_> = runs
+        .into_iter()
+        .filter_map(|r| {
+            // Queued runs haven't started.
+            let started = r.started_at?;
+            let conclusion = r.conclusion.map(|c| {
+                match &*c {
+                    "success" => "success",
+                    "failure" | "timed_out" => "failure",
+                    _ => "other",
+                }
+                .to_owned()
+            });
+            Some(data::CheckRun {
+                host: repo.host.to_owned(),
+                pr,
+                sha: sha.clone(),
+                name: r.name,
+                started: Date::new(started),
+                finished: r.completed_at.map(Date::new),
+                conclusion,
+            })
+        })
+        .collect();
+    // Statuses are listed newest first.
+    statuses.reverse();
+    checks.extend(statuses_to_checks(
+        repo.host,
+        pr,
+        sha,
+        statuses
+            .into_iter()
+            .map(|s| (s.context, s.state, s.created_at))
+            .collect(),
+    ));
+    checks
+}
+
+/// Get an item directly from the REST API, for fields which hubcaps doesn't
+/// support.
+fn get<T: DeserializeOwned>(auth: &Auth, repo: &Repo, path: &str) -> Result<T> {
+    Ok(send(auth, repo, &format!("{}/{}", repo.api_url(), path))?.json()?)
+}
+
+/// Get every page of a list directly from the REST API, for endpoints which
+/// hubcaps doesn't support (or doesn't paginate).
+fn get_all<T: DeserializeOwned>(
+    auth: &Auth,
+    repo: &Repo,
+    path: &str,
+    query: &[(&str, &str)],
+) -> Result<Vec<T>> {
+    get_pages(auth, repo, path, query, |items: Vec<T>| items)
+}
+
+/// As `get_all`, for endpoints which wrap each page's items in an object.
+fn get_pages<P: DeserializeOwned, T>(
+    auth: &Auth,
+    repo: &Repo,
+    path: &str,
+    query: &[(&str, &str)],
+    items: impl Fn(P) -> Vec<T>,
+) -> Result<Vec<T>> {
+    let mut url = reqwest::Url::parse(&format!("{}/{}", repo.api_url(), path))?;
+    url.query_pairs_mut()
+        .extend_pairs(query)
+        .append_pair("per_page", "100");
+    let mut url = url.into_string();
+    let mut result = vec![];
+    loop {
+        let mut response = send(auth, repo, &url)?;
+        result.extend(items(response.json()?));
+
+        match response
+            .headers()
+            .get(header::LINK)
+            .and_then(|l| l.to_str().ok())
+            .and_then(next_link)
+        {
+            Some(next) => url = next,
+            None => return Ok(result),
+        }
+    }
+}
+
+fn send(auth: &Auth, repo: &Repo, url: &str) -> Result<reqwest::Response> {
+    Ok(http_client(repo)?
+        .get(url)
+        .header(
+            header::AUTHORIZATION,
+            format!("token {}", auth.token(repo)?),
+        )
+        .header(header::USER_AGENT, USER_AGENT)
+        .send()?
+        .error_for_status()?)
+}
+
+/// The URL of the next page from a `Link` header.
+fn next_link(link: &str) -> Option<String> {
+    let next = link.split(',').find(|l| l.contains("rel=\"next\""))?;
+    let start = next.find('<')? + 1;
+    let end = next.find('>')?;
+    Some(next[start..end].to_owned())
+}
+
+/// Fields of a PR which hubcaps doesn't support.
+#[derive(Deserialize)]
+struct PullExtra {
+    user: PullUser,
+    draft: bool,
+    milestone: Option<Milestone>,
+}
+
+#[derive(Deserialize)]
+struct PullUser {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct CheckRuns {
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Deserialize)]
+struct CheckRun {
+    name: String,
+    started_at: Option<String>,
+    completed_at: Option<String>,
+    conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CommitStatus {
+    context: String,
+    state: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+struct File {
+    filename: String,
+}
+
+#[derive(Deserialize)]
+struct Milestone {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct Review {
+    // `None` for deleted users.
+    user: Option<ReviewUser>,
+    state: String,
+    submitted_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReviewUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    event: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    draft: bool,
+    // `None` for drafts.
+    published_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Deployment {
+    id: u64,
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct DeploymentStatus {
+    // E.g., `success`, `inactive` or `queued`.
+    state: String,
+    created_at: String,
 }
 
 fn record_sample<T: db::ConnectionProvider>(
+    repo: &Repo,
     pull: Pull,
     first_sha: String,
     review_comments: usize,
+    extra: PullExtra,
+    files: Vec<File>,
 ) -> Result<()> {
     let conn = T::connection()?;
 
     let author = data::User {
+        host: repo.host.to_owned(),
         id: saturating_from(pull.user.id),
+        bot: is_bot(&pull.user.login, extra.user.kind == "Bot"),
         username: pull.user.login,
         url: pull.user.url,
     };
-    author.insert_into(&conn)?;
 
     let pr = data::PullRequest {
+        host: repo.host.to_owned(),
         id: u32::try_from(pull.id).unwrap_or_else(|_| u32::max_value()),
+        repo: repo.full_name(),
         number: u32::try_from(pull.number).unwrap_or_else(|_| u32::max_value()),
         title: pull.title,
         body: pull.body.unwrap_or_else(String::new),
         author,
         created: Date::new(pull.created_at),
         url: pull.url,
+        labels: pull
+            .labels
+            .into_iter()
+            .map(|l| data::Label {
+                host: repo.host.to_owned(),
+                repo: repo.full_name(),
+                name: l.name,
+                color: l.color,
+            })
+            .collect(),
     };
-    pr.insert_into(&conn)?;
 
     let sample = data::Sample {
+        host: repo.host.to_owned(),
         time: Date::new(pull.updated_at),
         pr,
         status: Status::from_opts(pull.closed_at, pull.merged_at),
@@ -89,27 +580,13 @@ fn record_sample<T: db::ConnectionProvider>(
         changed_files: saturating_from_opt(pull.changed_files),
         review_comments: saturating_from(review_comments),
         first_commit: Sha(first_sha),
+        head: Sha(pull.head.sha),
+        merge_commit: pull.merge_commit_sha.map(Sha),
+        draft: extra.draft,
+        milestone: extra.milestone.map(|m| m.title),
+        files: files.into_iter().map(|f| f.filename).collect(),
     };
-    sample.insert_into(&conn)?;
-
-    Ok(())
-}
-
-#[inline]
-fn saturating_from<T>(v: T) -> u32
-where
-    u32: TryFrom<T>,
-{
-    u32::try_from(v).unwrap_or_else(|_| u32::max_value())
-}
-
-#[inline]
-fn saturating_from_opt<T>(v: Option<T>) -> u32
-where
-    u32: TryFrom<T>,
-{
-    v.map(|v| u32::try_from(v).unwrap_or_else(|_| u32::max_value()))
-        .unwrap_or(0)
+    source::record_sample(&conn, &sample)
 }
 
 #[cfg(test)]
@@ -117,12 +594,41 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_sat_from() {
-        assert_eq!(42, saturating_from::<u64>(42));
-        assert_eq!(u32::max_value(), saturating_from::<u64>(u32::max_value() as u64 + 500));
-        assert_eq!(42, saturating_from_opt::<u64>(Some(42)));
-        assert_eq!(u32::max_value(), saturating_from_opt::<u64>(Some(u32::max_value() as u64 + 500)));
-        assert_eq!(0, saturating_from_opt::<u64>(None));
+    fn test_next_link() {
+        assert_eq!(
+            next_link(
+                r#"<https://api.github.com/repositories/1/pulls/2/reviews?per_page=100&page=2>; rel="next", <https://api.github.com/repositories/1/pulls/2/reviews?per_page=100&page=3>; rel="last""#
+            ),
+            Some(
+                "https://api.github.com/repositories/1/pulls/2/reviews?per_page=100&page=2"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            next_link(
+                r#"<https://api.github.com/repositories/1/pulls/2/reviews?per_page=100&page=1>; rel="first""#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_deployed_at() -> Result<()> {
+        let statuses: Vec<DeploymentStatus> = serde_json::from_str(
+            r#"[
+                {"state": "inactive", "created_at": "2019-05-16T09:00:00Z"},
+                {"state": "success", "created_at": "2019-05-15T10:00:00Z"},
+                {"state": "success", "created_at": "2019-05-15T11:00:00Z"},
+                {"state": "in_progress", "created_at": "2019-05-15T09:30:00Z"},
+                {"state": "queued", "created_at": "2019-05-15T09:00:00Z"}
+            ]"#,
+        )?;
+        assert_eq!(
+            deployed_at(statuses),
+            Some("2019-05-15T10:00:00Z".to_owned())
+        );
+        assert_eq!(deployed_at(vec![]), None);
+        Ok(())
     }
 
     #[test]
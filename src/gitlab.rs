@@ -0,0 +1,525 @@
+//! Merge requests from GitLab, mapped onto the same data as GitHub PRs.
+
+use crate::auth::http_client;
+use crate::config::Repo;
+use crate::data::{self, Date, Sha, Status};
+use crate::source::{
+    closed_since_sampled, is_bot, record_activity, record_issue_sample, record_sample,
+    saturating_from, Source,
+};
+use crate::{db, dora, Result};
+
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use url::form_urlencoded;
+
+/// The body of the system note GitLab adds when someone approves an MR.
+const APPROVAL_NOTE: &str = "approved this merge request";
+
+pub struct GitLabSource {
+    repo: &'static Repo,
+    token: &'static str,
+}
+
+impl GitLabSource {
+    pub fn new(repo: &'static Repo, token: &'static str) -> GitLabSource {
+        GitLabSource { repo, token }
+    }
+
+    fn record_data(&self, client: &Client, mr: MergeRequest) -> Result<()> {
+        let changes = self.get(client, &format!("merge_requests/{}/changes", mr.iid))?;
+        let commits = self.get_all(client, &format!("merge_requests/{}/commits", mr.iid))?;
+        let notes = self.get_all(client, &format!("merge_requests/{}/notes", mr.iid))?;
+        let pipelines: Vec<Pipeline> =
+            self.get_all(client, &format!("merge_requests/{}/pipelines", mr.iid))?;
+        let mut jobs = vec![];
+        for pipeline in pipelines.iter().filter(|p| p.sha == mr.sha) {
+            jobs.extend(self.get_all(
+                client,
+                &format!("pipelines/{}/jobs?include_retried=true", pipeline.id),
+            )?);
+        }
+
+        let (reviews, draft_events) = to_activity(self.repo, saturating_from(mr.id), &notes);
+        let checks = to_checks(
+            self.repo,
+            saturating_from(mr.id),
+            &Sha(mr.sha.clone()),
+            jobs,
+        );
+        let sample = to_sample(self.repo, mr, changes, commits, notes);
+        let conn = db::connection()?;
+        record_sample(&conn, &sample)?;
+        record_activity(&conn, &reviews, &draft_events, &checks)
+    }
+
+    /// Record issues labelled as incidents, see `FailureRule::IssueLabel`.
+    fn record_incidents(&self, client: &Client) -> Result<()> {
+        let conn = db::connection()?;
+        for label in dora::incident_labels() {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .append_pair("labels", label)
+                .finish();
+            let issues: Vec<Issue> =
+                self.get_all(client, &format!("issues?state=all&{}", query))?;
+            for issue in issues {
+                data::Incident {
+                    host: self.repo.host.to_owned(),
+                    repo: self.repo.full_name(),
+                    number: saturating_from(issue.iid),
+                    label: label.to_owned(),
+                    title: issue.title,
+                    body: issue.description.unwrap_or_else(String::new),
+                    opened: Date::new(issue.created_at),
+                    closed: issue.closed_at.map(Date::new),
+                }
+                .insert_into(&conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a sample for every issue updated since we last sampled issues.
+    fn record_issues(&self, client: &Client) -> Result<()> {
+        let conn = db::connection()?;
+        let mut path = "issues?state=all".to_owned();
+        if let Some(since) = db::last_issue_sample(&conn, self.repo.host, &self.repo.full_name())? {
+            path.push_str(&format!("&updated_after={}", since.replace('+', "%2B")));
+        }
+        let issues: Vec<Issue> = self.get_all(client, &path)?;
+        for issue in issues {
+            record_issue_sample(
+                &conn,
+                &data::IssueSample {
+                    host: self.repo.host.to_owned(),
+                    time: Date::new(issue.updated_at),
+                    issue: data::Issue {
+                        host: self.repo.host.to_owned(),
+                        id: saturating_from(issue.id),
+                        repo: self.repo.full_name(),
+                        number: saturating_from(issue.iid),
+                        title: issue.title,
+                        body: issue.description.unwrap_or_else(String::new),
+                        created: Date::new(issue.created_at),
+                        url: issue.web_url,
+                    },
+                    closed: issue.closed_at.map(Date::new),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn url(&self, path: &str, page: &str) -> String {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        format!(
+            "{}/projects/{}/{}{}per_page=100&page={}",
+            self.repo.host,
+            self.repo.full_name().replace('/', "%2F"),
+            path,
+            separator,
+            page
+        )
+    }
+
+    fn get<T: DeserializeOwned>(&self, client: &Client, path: &str) -> Result<T> {
+        Ok(client
+            .get(&self.url(path, "1"))
+            .header("PRIVATE-TOKEN", self.token)
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    /// Get every page of a list.
+    fn get_all<T: DeserializeOwned>(&self, client: &Client, path: &str) -> Result<Vec<T>> {
+        let mut result = vec![];
+        let mut page = "1".to_owned();
+        loop {
+            let mut response = client
+                .get(&self.url(path, &page))
+                .header("PRIVATE-TOKEN", self.token)
+                .send()?
+                .error_for_status()?;
+            let mut items: Vec<T> = response.json()?;
+            result.append(&mut items);
+
+            match response
+                .headers()
+                .get("X-Next-Page")
+                .and_then(|p| p.to_str().ok())
+            {
+                Some(p) if !p.is_empty() => page = p.to_owned(),
+                _ => return Ok(result),
+            }
+        }
+    }
+}
+
+impl Source for GitLabSource {
+    fn update(&self) -> Result<()> {
+        let client = http_client(self.repo)?;
+        let mrs: Vec<MergeRequest> = self.get_all(&client, "merge_requests?state=opened")?;
+        let open: Vec<u32> = mrs.iter().map(|mr| saturating_from(mr.iid)).collect();
+        for mr in mrs {
+            // TODO handle any errors
+            let _ = self.record_data(&client, mr);
+        }
+        let closed = closed_since_sampled(&db::connection()?, self.repo, &open)?;
+        for iid in closed {
+            // TODO handle any errors
+            let _ = self
+                .get(&client, &format!("merge_requests/{}", iid))
+                .and_then(|mr| self.record_data(&client, mr));
+        }
+        self.record_incidents(&client)?;
+        self.record_issues(&client)
+    }
+}
+
+fn to_sample(
+    repo: &Repo,
+    mr: MergeRequest,
+    changes: Changes,
+    commits: Vec<Commit>,
+    notes: Vec<Note>,
+) -> data::Sample {
+    let author = data::User {
+        host: repo.host.to_owned(),
+        id: saturating_from(mr.author.id),
+        bot: is_bot(&mr.author.username, false),
+        username: mr.author.username,
+        url: mr.author.web_url,
+    };
+
+    let pr = data::PullRequest {
+        host: repo.host.to_owned(),
+        id: saturating_from(mr.id),
+        repo: repo.full_name(),
+        number: saturating_from(mr.iid),
+        title: mr.title,
+        body: mr.description.unwrap_or_else(String::new),
+        author,
+        created: Date::new(mr.created_at),
+        url: mr.web_url,
+        // GitLab only gives us label names.
+        labels: mr
+            .labels
+            .into_iter()
+            .map(|name| data::Label {
+                host: repo.host.to_owned(),
+                repo: repo.full_name(),
+                name,
+                color: String::new(),
+            })
+            .collect(),
+    };
+
+    // GitLab doesn't distinguish review comments from other comments, so we
+    // count every comment. Approvals are only recorded as system notes; we
+    // count those too, since on GitHub an approval is a review.
+    let review_comments = notes
+        .iter()
+        .filter(|n| !n.system || n.body == APPROVAL_NOTE)
+        .count();
+
+    let (mut additions, mut deletions) = (0, 0);
+    for change in &changes.changes {
+        for line in change.diff.lines() {
+            if line.starts_with('+') {
+                additions += 1;
+            } else if line.starts_with('-') {
+                deletions += 1;
+            }
+        }
+    }
+
+    data::Sample {
+        host: repo.host.to_owned(),
+        time: Date::new(mr.updated_at),
+        pr,
+        status: Status::from_opts(mr.closed_at, mr.merged_at),
+        commits: saturating_from(commits.len()),
+        additions,
+        deletions,
+        // Very large MRs are reported as, e.g., "1000+".
+        changed_files: changes
+            .changes_count
+            .and_then(|c| c.trim_end_matches('+').parse().ok())
+            .unwrap_or(0),
+        review_comments: saturating_from(review_comments),
+        // Commits are listed newest first.
+        first_commit: Sha(commits.last().map(|c| c.id.clone()).unwrap_or_default()),
+        head: Sha(mr.sha),
+        merge_commit: mr.merge_commit_sha.or(mr.squash_commit_sha).map(Sha),
+        // Older versions of GitLab only report `work_in_progress`, and newer
+        // ones only `draft`.
+        draft: mr.draft.or(mr.work_in_progress).unwrap_or(false),
+        milestone: mr.milestone.map(|m| m.title),
+        files: changes.changes.into_iter().map(|c| c.new_path).collect(),
+    }
+}
+
+/// Jobs of the pipelines for the MR's head commit `sha`, including retried jobs.
+fn to_checks(repo: &Repo, pr: u32, sha: &Sha, jobs: Vec<Job>) -> Vec<data::CheckRun> {
+    jobs.into_iter()
+        .filter_map(|job| {
+            // Jobs which haven't started yet, or never will (e.g., manual jobs).
+            let started = job.started_at?;
+            let conclusion = match &*job.status {
+                "created" | "pending" | "running" => None,
+                "success" => Some("success"),
+                "failed" => Some("failure"),
+                _ => Some("other"),
+            };
+            Some(data::CheckRun {
+                host: repo.host.to_owned(),
+                pr,
+                sha: sha.clone(),
+                name: job.name,
+                started: Date::new(started),
+                finished: job.finished_at.map(Date::new),
+                conclusion: conclusion.map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// GitLab doesn't have reviews, so we treat every comment as a review (see
+/// `to_sample`). Draft changes are only recorded as system notes.
+fn to_activity(repo: &Repo, pr: u32, notes: &[Note]) -> (Vec<data::Review>, Vec<data::DraftEvent>) {
+    let mut reviews = vec![];
+    let mut draft_events = vec![];
+    for note in notes {
+        if !note.system || note.body == APPROVAL_NOTE {
+            reviews.push(data::Review {
+                host: repo.host.to_owned(),
+                pr,
+                reviewer: note.author.username.clone(),
+                approved: note.system,
+                submitted: Date::new(note.created_at.clone()),
+            });
+            continue;
+        }
+
+        let draft = if note.body.contains("as **ready**")
+            || note.body.starts_with("unmarked as a **Work In Progress**")
+        {
+            false
+        } else if note.body.contains("as **draft**")
+            || note.body.starts_with("marked as a **Work In Progress**")
+        {
+            true
+        } else {
+            continue;
+        };
+        draft_events.push(data::DraftEvent {
+            host: repo.host.to_owned(),
+            pr,
+            draft,
+            time: Date::new(note.created_at.clone()),
+        });
+    }
+    (reviews, draft_events)
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    id: u64,
+    iid: u64,
+    title: String,
+    description: Option<String>,
+    author: Author,
+    created_at: String,
+    updated_at: String,
+    closed_at: Option<String>,
+    merged_at: Option<String>,
+    web_url: String,
+    sha: String,
+    merge_commit_sha: Option<String>,
+    squash_commit_sha: Option<String>,
+    labels: Vec<String>,
+    draft: Option<bool>,
+    work_in_progress: Option<bool>,
+    milestone: Option<Milestone>,
+}
+
+#[derive(Deserialize)]
+struct Pipeline {
+    id: u64,
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct Job {
+    name: String,
+    status: String,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Milestone {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    id: u64,
+    username: String,
+    web_url: String,
+}
+
+#[derive(Deserialize)]
+struct Changes {
+    changes_count: Option<String>,
+    changes: Vec<Change>,
+}
+
+#[derive(Deserialize)]
+struct Change {
+    new_path: String,
+    diff: String,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    id: u64,
+    iid: u64,
+    title: String,
+    description: Option<String>,
+    created_at: String,
+    updated_at: String,
+    closed_at: Option<String>,
+    web_url: String,
+}
+
+#[derive(Deserialize)]
+struct Note {
+    body: String,
+    system: bool,
+    author: Author,
+    created_at: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Areas, Deploys, Forge};
+    use rusqlite::Connection;
+
+    const REPO: Repo = Repo {
+        forge: Forge::GitLab { token: "" },
+        host: "https://gitlab.com/api/v4",
+        ca_cert: None,
+        clone_url: None,
+        owner: "group/subgroup",
+        name: "project",
+        deploys: Deploys::None,
+        areas: Areas::None,
+    };
+
+    #[test]
+    fn test_url() {
+        let source = GitLabSource::new(&REPO, "");
+        assert_eq!(
+            source.url("merge_requests?state=opened", "2"),
+            "https://gitlab.com/api/v4/projects/group%2Fsubgroup%2Fproject/merge_requests?state=opened&per_page=100&page=2"
+        );
+        assert_eq!(
+            source.url("merge_requests/3/notes", "1"),
+            "https://gitlab.com/api/v4/projects/group%2Fsubgroup%2Fproject/merge_requests/3/notes?per_page=100&page=1"
+        );
+    }
+
+    #[test]
+    fn test_to_sample() -> Result<()> {
+        let mr: MergeRequest = serde_json::from_str(
+            r#"{
+                "id": 1234,
+                "iid": 3,
+                "title": "Fix the thing",
+                "description": null,
+                "state": "merged",
+                "author": {"id": 42, "username": "bob", "web_url": "https://gitlab.com/bob"},
+                "created_at": "2019-05-14T09:15:13.000Z",
+                "updated_at": "2019-05-15T09:25:34.000Z",
+                "closed_at": null,
+                "merged_at": "2019-05-15T09:25:34.000Z",
+                "web_url": "https://gitlab.com/group/subgroup/project/merge_requests/3",
+                "sha": "def",
+                "merge_commit_sha": "123",
+                "squash_commit_sha": null,
+                "labels": ["hotfix"],
+                "draft": false,
+                "milestone": {"id": 7, "title": "1.0"}
+            }"#,
+        )?;
+        let changes: Changes = serde_json::from_str(
+            r#"{
+                "changes_count": "2",
+                "changes": [
+                    {"new_path": "src/foo.rs", "diff": "@@ -1,2 +1,2 @@\n-foo\n+bar\n baz\n"},
+                    {"new_path": "docs/new.md", "diff": "@@ -0,0 +1 @@\n+new\n"}
+                ]
+            }"#,
+        )?;
+        let commits: Vec<Commit> = serde_json::from_str(r#"[{"id": "def"}, {"id": "abc"}]"#)?;
+        let notes: Vec<Note> = serde_json::from_str(
+            r#"[
+                {"body": "marked this merge request as **ready**", "system": true, "author": {"id": 42, "username": "bob", "web_url": ""}, "created_at": "2019-05-14T10:00:00.000Z"},
+                {"body": "Looks good", "system": false, "author": {"id": 43, "username": "alice", "web_url": ""}, "created_at": "2019-05-14T11:00:00.000Z"},
+                {"body": "added 1 commit", "system": true, "author": {"id": 42, "username": "bob", "web_url": ""}, "created_at": "2019-05-14T12:00:00.000Z"},
+                {"body": "approved this merge request", "system": true, "author": {"id": 43, "username": "alice", "web_url": ""}, "created_at": "2019-05-15T09:00:00.000Z"}
+            ]"#,
+        )?;
+
+        let (reviews, draft_events) = to_activity(&REPO, 1234, &notes);
+        assert_eq!(reviews.len(), 2);
+        assert_eq!(reviews[0].reviewer, "alice");
+        assert!(!reviews[0].approved);
+        assert!(reviews[1].approved);
+        assert_eq!(reviews[1].submitted.date, "2019-05-15T09:00:00.000Z");
+        assert_eq!(draft_events.len(), 1);
+        assert!(!draft_events[0].draft);
+
+        let sample = to_sample(&REPO, mr, changes, commits, notes);
+        assert_eq!(sample.host, "https://gitlab.com/api/v4");
+        assert_eq!(sample.pr.id, 1234);
+        assert_eq!(sample.pr.number, 3);
+        assert_eq!(sample.pr.body, "");
+        assert_eq!(sample.pr.author.username, "bob");
+        assert_eq!(
+            sample.status,
+            Status::Merged(Date::new("2019-05-15T09:25:34.000Z".to_owned()))
+        );
+        assert_eq!(sample.commits, 2);
+        assert_eq!(sample.additions, 2);
+        assert_eq!(sample.deletions, 1);
+        assert_eq!(sample.changed_files, 2);
+        assert_eq!(sample.review_comments, 2);
+        assert_eq!(sample.first_commit, Sha("abc".to_owned()));
+        assert_eq!(sample.head, Sha("def".to_owned()));
+        assert_eq!(sample.merge_commit, Some(Sha("123".to_owned())));
+        assert!(!sample.draft);
+        assert_eq!(sample.milestone, Some("1.0".to_owned()));
+        assert_eq!(sample.files, vec!["src/foo.rs", "docs/new.md"]);
+
+        let conn = Connection::open_in_memory()?;
+        db::init(&conn)?;
+        record_sample(&conn, &sample)?;
+        let prs = db::read_prs(&conn, db::all_time())?;
+        assert_eq!(prs.len(), 1);
+        assert_eq!(prs[0].url, sample.pr.url);
+        assert_eq!(prs[0].labels, vec!["hotfix".to_owned()]);
+        assert_eq!(prs[0].samples.len(), 1);
+        assert_eq!(prs[0].samples[0].deletions, 1);
+        Ok(())
+    }
+}
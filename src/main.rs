@@ -4,18 +4,46 @@ use hubcaps;
 use rusqlite;
 use std::{env, thread, time::Duration};
 
+use crate::auth::Auth;
+use crate::data::Date;
 use crate::frontend::Blob;
 
+mod aging;
+mod anomaly;
+mod area;
+mod auth;
+mod bucket;
+mod business;
+mod compare;
 mod config;
+mod cycle;
 mod data;
 mod db;
+mod dora;
+mod forecast;
 mod frontend;
+mod git;
+mod gitea;
 mod github;
+mod gitlab;
+mod server;
+mod size;
+mod slo;
+mod source;
+mod webhook;
 
-/// Update from GitHub every `UPDATE_TIMEOUT`s.
-fn update_loop(blob: Blob) {
+/// Update from every source every `UPDATE_TIMEOUT`s.
+fn update_loop(blob: Blob, auth: Auth) {
+    let sources = source::sources(&auth);
     loop {
-        github::update_from_repo();
+        for source in &sources {
+            // TODO handle any errors
+            let _ = source.update();
+        }
+        let _ = git::analyse_all();
+        let _ = dora::update_all();
+        let _ = area::update_all();
+        let _ = anomaly::check();
         // TODO deal with errors?
         blob.update();
         thread::sleep(Duration::from_secs(config::UPDATE_TIMEOUT));
@@ -26,6 +54,14 @@ fn update_loop(blob: Blob) {
 pub enum GhvError {
     DbError(rusqlite::Error),
     GhError(hubcaps::Error),
+    JsonError(serde_json::Error),
+    HttpError(reqwest::Error),
+    UrlError(reqwest::UrlError),
+    JwtError(jsonwebtoken::errors::Error),
+    IoError(std::io::Error),
+    TlsError(native_tls::Error),
+    /// A git command failed, with its stderr.
+    GitError(String),
     Other,
 }
 
@@ -41,6 +77,42 @@ impl From<hubcaps::Error> for GhvError {
     }
 }
 
+impl From<serde_json::Error> for GhvError {
+    fn from(e: serde_json::Error) -> GhvError {
+        GhvError::JsonError(e)
+    }
+}
+
+impl From<reqwest::Error> for GhvError {
+    fn from(e: reqwest::Error) -> GhvError {
+        GhvError::HttpError(e)
+    }
+}
+
+impl From<reqwest::UrlError> for GhvError {
+    fn from(e: reqwest::UrlError) -> GhvError {
+        GhvError::UrlError(e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for GhvError {
+    fn from(e: jsonwebtoken::errors::Error) -> GhvError {
+        GhvError::JwtError(e)
+    }
+}
+
+impl From<std::io::Error> for GhvError {
+    fn from(e: std::io::Error) -> GhvError {
+        GhvError::IoError(e)
+    }
+}
+
+impl From<native_tls::Error> for GhvError {
+    fn from(e: native_tls::Error) -> GhvError {
+        GhvError::TlsError(e)
+    }
+}
+
 impl<T> From<std::sync::PoisonError<T>> for GhvError {
     fn from(_: std::sync::PoisonError<T>) -> GhvError {
         GhvError::Other
@@ -57,9 +129,73 @@ fn main() {
             let conn = db::connection().expect("Could not connect to db");
             db::init(&conn).expect("Could not initialise db");
         }
+
+        // `--aging`, see `aging::aging`.
+        if first_arg == "--aging" {
+            let conn = db::connection().expect("Could not connect to db");
+            let aging = aging::aging(&conn).expect("Could not report aging PRs");
+            print!("{}", aging);
+            return;
+        }
+        // `--stats [granularity]`, see `bucket::stats`. `granularity` is one of
+        // day, week (the default), month, quarter or sprint.
+        if first_arg == "--stats" {
+            let granularity = args
+                .next()
+                .map(|a| a.parse().expect("Unknown granularity"))
+                .unwrap_or(bucket::Granularity::Week);
+            let conn = db::connection().expect("Could not connect to db");
+            let stats = bucket::stats(&conn, &db::PrFilter::default(), granularity)
+                .expect("Could not compute stats");
+            print!("{}", stats);
+            return;
+        }
+        // `--compare from to [previous_from previous_to]`, see
+        // `compare::compare`. Ends are exclusive.
+        if first_arg == "--compare" {
+            let mut range = || {
+                let start = args.next()?;
+                let end = args.next().expect("A period needs an end");
+                Some(Date::new(start)..Date::new(end))
+            };
+            let current = range().expect("Expected a period to compare");
+            let previous = range();
+            let conn = db::connection().expect("Could not connect to db");
+            let comparison =
+                compare::compare(&conn, current, previous).expect("Could not compare periods");
+            print!("{}", comparison);
+            return;
+        }
+        // `--forecast [weeks] [prs]`, see `forecast::forecast`.
+        if first_arg == "--forecast" {
+            let weeks = args
+                .next()
+                .map(|a| a.parse().expect("weeks should be a number"))
+                .unwrap_or(4);
+            let prs = args
+                .next()
+                .map(|a| a.parse().expect("prs should be a number"));
+            assert!(
+                weeks <= forecast::MAX_WEEKS && prs.map_or(true, |p| p <= forecast::MAX_PRS),
+                "at most {} weeks and {} PRs",
+                forecast::MAX_WEEKS,
+                forecast::MAX_PRS
+            );
+            let conn = db::connection().expect("Could not connect to db");
+            let forecast = forecast::forecast(&conn, weeks, prs).expect("Could not forecast");
+            print!("{}", forecast);
+            return;
+        }
     }
 
+    let conn = db::connection().expect("Could not connect to db");
+    db::migrate(&conn).expect("Could not migrate db");
+
+    let auth = Auth::new();
     let blob = Blob::new();
-    update_loop(blob.clone());
-    // TODO frontend thread
+    let worker = webhook::spawn_worker(auth.clone());
+    let server_blob = blob.clone();
+    thread::spawn(move || server::serve(worker, server_blob).expect("Could not start server"));
+
+    update_loop(blob, auth);
 }
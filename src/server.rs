@@ -0,0 +1,193 @@
+//! The HTTP server, which receives webhook deliveries, serves reports as JSON
+//! and serves the dashboard.
+
+use crate::config::SERVER_ADDR;
+use crate::data::Date;
+use crate::frontend::Blob;
+use crate::webhook::{self, Action, Outcome};
+use crate::{aging, bucket, compare, db, forecast, slo, GhvError, Result};
+
+use serde::Serialize;
+use std::io::Read;
+use std::sync::mpsc::Sender;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server};
+
+/// Serve requests forever. Work for webhook deliveries is sent to `worker`,
+/// see `webhook::spawn_worker`.
+pub fn serve(worker: Sender<Action>, blob: Blob) -> Result<()> {
+    let server = Server::http(SERVER_ADDR).map_err(|_| GhvError::Other)?;
+    for mut request in server.incoming_requests() {
+        let url = request.url().to_owned();
+        let (path, query) = url.split_at(url.find('?').unwrap_or_else(|| url.len()));
+        let response = match (request.method(), path) {
+            (Method::Post, "/webhook") => {
+                Response::empty(handle_webhook(&worker, &mut request)).boxed()
+            }
+            (Method::Get, "/forecast") => handle_forecast(query),
+            (Method::Get, "/stats") => handle_stats(query),
+            (Method::Get, "/compare") => handle_compare(query),
+            (Method::Get, "/slos") => json(db::connection().and_then(|conn| slo::report(&conn))),
+            (Method::Get, "/aging") => json(db::connection().and_then(|conn| aging::aging(&conn))),
+            (Method::Get, "/data") => json_body(blob.json()),
+            (Method::Get, "/") => with_content_type(
+                "text/html; charset=utf-8",
+                include_str!("../static/index.html"),
+            ),
+            (Method::Get, "/dashboard.js") => with_content_type(
+                "application/javascript",
+                include_str!("../static/dashboard.js"),
+            ),
+            _ => Response::empty(404).boxed(),
+        };
+        // TODO log errors
+        let _ = request.respond(response);
+    }
+
+    Ok(())
+}
+
+/// `/forecast?weeks=N&prs=M`, see `forecast::forecast`. `weeks` defaults to 4
+/// and `prs` to the number of open PRs.
+fn handle_forecast(query: &str) -> ResponseBox {
+    match forecast_params(query) {
+        Some((weeks, prs)) => {
+            json(db::connection().and_then(|conn| forecast::forecast(&conn, weeks, prs)))
+        }
+        None => Response::empty(400).boxed(),
+    }
+}
+
+/// `weeks` (default 4) and `prs`, `None` if either is invalid or too big.
+fn forecast_params(query: &str) -> Option<(u32, Option<u32>)> {
+    let weeks = match param(query, "weeks").map(str::parse) {
+        None => 4,
+        Some(Ok(weeks)) if weeks <= forecast::MAX_WEEKS => weeks,
+        Some(_) => return None,
+    };
+    let prs = match param(query, "prs").map(str::parse) {
+        None => None,
+        Some(Ok(prs)) if prs <= forecast::MAX_PRS => Some(prs),
+        Some(_) => return None,
+    };
+    Some((weeks, prs))
+}
+
+/// `/stats?granularity=G&bots=B`, see `bucket::stats`. `granularity` defaults
+/// to week, and `bots` (include, exclude or only) to `config::BOT_PRS`.
+fn handle_stats(query: &str) -> ResponseBox {
+    let granularity = match param(query, "granularity").map(str::parse) {
+        None => bucket::Granularity::Week,
+        Some(Ok(granularity)) => granularity,
+        Some(Err(_)) => return Response::empty(400).boxed(),
+    };
+    let bots = match param(query, "bots").map(str::parse) {
+        None => db::Bots::default(),
+        Some(Ok(bots)) => bots,
+        Some(Err(_)) => return Response::empty(400).boxed(),
+    };
+    let filter = db::PrFilter {
+        bots,
+        ..db::PrFilter::default()
+    };
+    json(db::connection().and_then(|conn| bucket::stats(&conn, &filter, granularity)))
+}
+
+/// `/compare?from=A&to=B&previous_from=C&previous_to=D`, see
+/// `compare::compare`. The previous period is optional.
+fn handle_compare(query: &str) -> ResponseBox {
+    let range = |start, end| match (param(query, start), param(query, end)) {
+        (Some(start), Some(end)) => {
+            Ok(Some(Date::new(start.to_owned())..Date::new(end.to_owned())))
+        }
+        (None, None) => Ok(None),
+        _ => Err(()),
+    };
+    match (range("from", "to"), range("previous_from", "previous_to")) {
+        (Ok(Some(current)), Ok(previous)) => {
+            json(db::connection().and_then(|conn| compare::compare(&conn, current, previous)))
+        }
+        _ => Response::empty(400).boxed(),
+    }
+}
+
+fn json<T: Serialize>(value: Result<T>) -> ResponseBox {
+    json_body(value.and_then(|v| Ok(serde_json::to_string(&v)?)))
+}
+
+fn json_body(body: Result<String>) -> ResponseBox {
+    match body {
+        Ok(body) => with_content_type("application/json", &body),
+        Err(_) => Response::empty(500).boxed(),
+    }
+}
+
+fn with_content_type(content_type: &str, body: &str) -> ResponseBox {
+    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+    Response::from_string(body).with_header(header).boxed()
+}
+
+/// The value of parameter `name` in a URL's query string (including the `?`).
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            Some((parts.next()?, parts.next().unwrap_or("")))
+        })
+        .find(|&(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+fn handle_webhook(worker: &Sender<Action>, request: &mut Request) -> u16 {
+    let event = header(request, "X-GitHub-Event").unwrap_or_default();
+    let signature = header(request, "X-Hub-Signature-256");
+    let mut body = vec![];
+    // One byte more than we'll accept, to tell if there was too much.
+    let mut reader = request.as_reader().take(webhook::MAX_WEBHOOK_BYTES + 1);
+    if reader.read_to_end(&mut body).is_err() {
+        return 400;
+    }
+    if body.len() as u64 > webhook::MAX_WEBHOOK_BYTES {
+        return 413;
+    }
+
+    match webhook::handle(worker, &event, signature.as_ref().map(|s| &**s), &body) {
+        Ok(Outcome::Queued) => 202,
+        Ok(Outcome::Ignored) => 204,
+        Ok(Outcome::BadSignature) => 401,
+        Err(GhvError::JsonError(_)) => 400,
+        Err(_) => 500,
+    }
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_param() {
+        assert_eq!(param("?weeks=4&prs=10", "weeks"), Some("4"));
+        assert_eq!(param("?weeks=4&prs=10", "prs"), Some("10"));
+        assert_eq!(param("?weeks", "weeks"), Some(""));
+        assert_eq!(param("", "weeks"), None);
+    }
+
+    #[test]
+    fn test_forecast_params() {
+        assert_eq!(forecast_params(""), Some((4, None)));
+        assert_eq!(forecast_params("?weeks=520&prs=10"), Some((520, Some(10))));
+        assert_eq!(forecast_params("?weeks=521"), None);
+        assert_eq!(forecast_params("?weeks=4294967295"), None);
+        assert_eq!(forecast_params("?prs=10001"), None);
+        assert_eq!(forecast_params("?weeks=-1"), None);
+    }
+}
@@ -0,0 +1,49 @@
+//! Classifying PRs by size, to compare how quickly small and large PRs merge.
+
+use crate::config::SIZE_THRESHOLDS;
+
+use serde_derive::Serialize;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+pub enum Size {
+    XS,
+    S,
+    M,
+    L,
+    XL,
+}
+
+impl Size {
+    /// Smallest first.
+    pub const ALL: [Size; 5] = [Size::XS, Size::S, Size::M, Size::L, Size::XL];
+
+    /// The size of a PR which changes `lines` lines, see `SIZE_THRESHOLDS`.
+    pub fn of(lines: u32) -> Size {
+        Size::with_thresholds(lines, &SIZE_THRESHOLDS)
+    }
+
+    fn with_thresholds(lines: u32, thresholds: &[u32; 4]) -> Size {
+        Size::ALL
+            .iter()
+            .zip(thresholds)
+            .find(|&(_, &max)| lines <= max)
+            .map(|(&size, _)| size)
+            .unwrap_or(Size::XL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_size() {
+        let thresholds = [10, 50, 250, 1000];
+        assert_eq!(Size::with_thresholds(0, &thresholds), Size::XS);
+        assert_eq!(Size::with_thresholds(10, &thresholds), Size::XS);
+        assert_eq!(Size::with_thresholds(11, &thresholds), Size::S);
+        assert_eq!(Size::with_thresholds(250, &thresholds), Size::M);
+        assert_eq!(Size::with_thresholds(251, &thresholds), Size::L);
+        assert_eq!(Size::with_thresholds(1001, &thresholds), Size::XL);
+    }
+}
@@ -0,0 +1,243 @@
+//! Evaluating service-level objectives (`config::SLOS`) against merged PRs,
+//! e.g., "90% of PRs get a first review within a day". For each SLO we report
+//! compliance per week, how much of the error budget (the PRs which are allowed
+//! to miss the threshold) the current window has used, and which PRs missed.
+//! Open PRs which have already missed the threshold count as violations in the
+//! current week, so a PR waiting for a review doesn't go unnoticed until it's
+//! merged.
+
+use crate::config::{Slo, SloMetric, SLOS};
+use crate::{db, Result};
+
+use rusqlite::{params, Connection};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SloReport {
+    pub name: &'static str,
+    pub metric: &'static str,
+    /// In minutes, of business time if `business_time`.
+    pub threshold: u32,
+    pub business_time: bool,
+    pub target: f64,
+    pub window_weeks: u32,
+    /// Only weeks when PRs were merged (or open PRs missed the threshold),
+    /// oldest first.
+    pub weeks: Vec<SloWeek>,
+    /// The fraction of PRs in the window which met the threshold, `None` if no
+    /// PRs were merged in the window.
+    pub compliance: Option<f64>,
+    /// Violations in the window / violations allowed by the target, so above
+    /// 1.0 means the SLO is not being met. `None` if no violations are allowed
+    /// (no PRs were merged in the window, or the target is 100%).
+    pub error_budget_burned: Option<f64>,
+    /// PRs in the window which missed the threshold, in order of merging, then
+    /// open PRs in order of opening.
+    pub violations: Vec<Violation>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct SloWeek {
+    pub start_date: String,
+    pub merged_prs: u32,
+    pub met: u32,
+    pub compliance: f64,
+    pub met_target: bool,
+}
+
+#[derive(Clone, Serialize, Debug, Eq, PartialEq)]
+pub struct Violation {
+    pub repo: String,
+    pub number: u32,
+    pub title: String,
+    pub url: String,
+    pub week: String,
+    /// The PR's value of the SLO's metric, in minutes, so far if it's open.
+    pub value: u32,
+    pub open: bool,
+}
+
+/// Evaluate every SLO in `config::SLOS`.
+pub fn report(conn: &Connection) -> Result<Vec<SloReport>> {
+    let prs = db::pr_times(conn)?;
+    let open = db::open_pr_times(conn, "now")?;
+    SLOS.iter()
+        .map(|slo| {
+            let window_start: String = conn.query_row(
+                "SELECT date('now', 'weekday 0', '-6 days', ?1)",
+                params![format!("-{} days", 7 * (slo.window_weeks.max(1) - 1))],
+                |row| row.get(0),
+            )?;
+            Ok(evaluate(slo, &prs, &open, &window_start))
+        })
+        .collect()
+}
+
+/// The window is the weeks starting on or after `window_start`. `open` PRs only
+/// count once they've missed the threshold, as until then they might meet it.
+fn evaluate(slo: &Slo, prs: &[db::PrTimes], open: &[db::PrTimes], window_start: &str) -> SloReport {
+    let value = |pr: &db::PrTimes| match (&slo.metric, slo.business_time) {
+        (SloMetric::TimeToFirstReview, false) => pr.waiting_for_review,
+        (SloMetric::TimeToFirstReview, true) => pr.business_waiting_for_review,
+        (SloMetric::TimeToMerge, false) => pr.time_to_merge,
+        (SloMetric::TimeToMerge, true) => pr.business_time_to_merge,
+    };
+
+    let mut weeks = BTreeMap::new();
+    let mut violations = vec![];
+    let merged = prs.iter().map(|pr| (pr, false));
+    for (pr, is_open) in merged.chain(open.iter().map(|pr| (pr, true))) {
+        let met = value(pr) <= slo.threshold;
+        if met && is_open {
+            continue;
+        }
+        let week = weeks.entry(&*pr.week).or_insert((0, 0));
+        week.0 += 1;
+        if met {
+            week.1 += 1;
+        } else if &*pr.week >= window_start {
+            violations.push(Violation {
+                repo: pr.repo.clone(),
+                number: pr.number,
+                title: pr.title.clone(),
+                url: pr.url.clone(),
+                week: pr.week.clone(),
+                value: value(pr),
+                open: is_open,
+            });
+        }
+    }
+
+    let in_window: u32 = weeks
+        .range(window_start..)
+        .map(|(_, &(merged, _))| merged)
+        .sum();
+    let (compliance, error_budget_burned) = if in_window == 0 {
+        (None, None)
+    } else {
+        let missed = violations.len() as f64;
+        let allowed = (1.0 - slo.target) * f64::from(in_window);
+        (
+            Some(1.0 - missed / f64::from(in_window)),
+            if allowed > 0.0 {
+                Some(missed / allowed)
+            } else {
+                None
+            },
+        )
+    };
+
+    SloReport {
+        name: slo.name,
+        metric: match slo.metric {
+            SloMetric::TimeToFirstReview => "time_to_first_review",
+            SloMetric::TimeToMerge => "time_to_merge",
+        },
+        threshold: slo.threshold,
+        business_time: slo.business_time,
+        target: slo.target,
+        window_weeks: slo.window_weeks,
+        weeks: weeks
+            .into_iter()
+            .map(|(start_date, (merged_prs, met))| {
+                let compliance = f64::from(met) / f64::from(merged_prs);
+                SloWeek {
+                    start_date: start_date.to_owned(),
+                    merged_prs,
+                    met,
+                    compliance,
+                    met_target: compliance >= slo.target,
+                }
+            })
+            .collect(),
+        compliance,
+        error_budget_burned,
+        violations,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let slo = Slo {
+            name: "Review within a day",
+            metric: SloMetric::TimeToFirstReview,
+            threshold: 1440,
+            business_time: false,
+            target: 0.75,
+            window_weeks: 2,
+        };
+        let pr = |number, week: &str, waiting_for_review| db::PrTimes {
+            repo: "owner/repo".to_owned(),
+            number,
+            title: String::new(),
+            url: String::new(),
+            week: week.to_owned(),
+            waiting_for_review,
+            time_to_merge: 10_000,
+            business_waiting_for_review: 0,
+            business_time_to_merge: 0,
+        };
+        let prs = &[
+            pr(1, "2019-05-06", 3000),
+            pr(2, "2019-05-13", 60),
+            pr(3, "2019-05-13", 1440),
+            pr(4, "2019-05-20", 2000),
+            pr(5, "2019-05-20", 100),
+        ];
+
+        let report = evaluate(&slo, prs, &[], "2019-05-13");
+        assert_eq!(report.metric, "time_to_first_review");
+        let weeks: Vec<_> = report
+            .weeks
+            .iter()
+            .map(|w| (&*w.start_date, w.merged_prs, w.met, w.met_target))
+            .collect();
+        assert_eq!(
+            weeks,
+            vec![
+                ("2019-05-06", 1, 0, false),
+                ("2019-05-13", 2, 2, true),
+                ("2019-05-20", 2, 1, false),
+            ]
+        );
+        assert_eq!(report.compliance, Some(0.75));
+        assert_eq!(report.error_budget_burned, Some(1.0));
+        assert_eq!(report.violations.len(), 1);
+        assert_eq!(
+            (report.violations[0].number, report.violations[0].value),
+            (4, 2000)
+        );
+
+        let business = Slo {
+            metric: SloMetric::TimeToFirstReview,
+            business_time: true,
+            ..slo
+        };
+        let report = evaluate(&business, prs, &[], "2019-05-13");
+        assert_eq!(report.compliance, Some(1.0));
+
+        let report = evaluate(&slo, prs, &[], "2019-05-27");
+        assert_eq!(report.compliance, None);
+        assert_eq!(report.error_budget_burned, None);
+        assert!(report.violations.is_empty());
+
+        // An open PR which has waited too long already counts, but one which
+        // might still be reviewed in time doesn't.
+        let open = &[pr(6, "2019-05-27", 2000), pr(7, "2019-05-27", 100)];
+        let report = evaluate(&slo, prs, open, "2019-05-20");
+        assert_eq!(report.weeks.len(), 4);
+        assert_eq!((report.weeks[3].merged_prs, report.weeks[3].met), (1, 0));
+        assert_eq!(report.compliance, Some(1.0 - 2.0 / 3.0));
+        let violations: Vec<_> = report
+            .violations
+            .iter()
+            .map(|v| (v.number, v.open))
+            .collect();
+        assert_eq!(violations, vec![(4, false), (6, true)]);
+    }
+}
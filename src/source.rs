@@ -0,0 +1,286 @@
+//! Places we get PR data from.
+
+use crate::auth::Auth;
+use crate::config::{Forge, Repo, BOTS, REPOS};
+use crate::data::{self, Date, Sha};
+use crate::{area, db, gitea, github, gitlab, Result};
+
+use rusqlite::Connection;
+use std::convert::TryFrom;
+
+/// A source of PRs (or their equivalent) for a single repo.
+pub trait Source {
+    /// Record a sample for every open PR, and for every PR which was open when
+    /// we last sampled it (see `closed_since_sampled`).
+    fn update(&self) -> Result<()>;
+}
+
+/// A source for every tracked repo.
+pub fn sources(auth: &Auth) -> Vec<Box<dyn Source>> {
+    REPOS
+        .iter()
+        .map(|repo| -> Box<dyn Source> {
+            match repo.forge {
+                Forge::GitHub => Box::new(github::GitHubSource::new(auth.clone(), repo)),
+                Forge::GitLab { token } => Box::new(gitlab::GitLabSource::new(repo, token)),
+                Forge::Gitea { token } => Box::new(gitea::GiteaSource::new(repo, token)),
+            }
+        })
+        .collect()
+}
+
+/// Numbers of the PRs in `repo` which were open when we last sampled them, but
+/// aren't in `open` (the PRs which are open now). They have been merged or
+/// closed since, so should be sampled again, otherwise they would look open
+/// forever.
+pub fn closed_since_sampled(conn: &Connection, repo: &Repo, open: &[u32]) -> Result<Vec<u32>> {
+    Ok(db::open_pr_numbers(conn, repo.host, &repo.full_name())?
+        .into_iter()
+        .filter(|number| !open.contains(number))
+        .collect())
+}
+
+/// Whether `username` is a bot, i.e., the forge says so (`forge_bot`), it has
+/// the `[bot]` suffix which GitHub Apps get, or it is in `BOTS`.
+pub fn is_bot(username: &str, forge_bot: bool) -> bool {
+    forge_bot || username.ends_with("[bot]") || BOTS.contains(&username)
+}
+
+/// Insert a sample, its PR, the PR's author, labels, changed files and the
+/// issues it fixes.
+pub fn record_sample(conn: &Connection, sample: &data::Sample) -> Result<()> {
+    sample.pr.author.insert_into(conn)?;
+    sample.pr.insert_into(conn)?;
+    record_labels(conn, &sample.pr, &sample.time)?;
+    db::replace_files(conn, &sample.host, sample.pr.id, &sample.files)?;
+    area::update_paths(conn, &sample.host, &sample.pr.repo, &sample.files)?;
+    db::replace_fixed_issues(
+        conn,
+        &sample.host,
+        sample.pr.id,
+        &sample.pr.repo,
+        &fixed_issues(&sample.pr.body),
+    )?;
+    sample.insert_into(conn)
+}
+
+/// Insert an issue sample and its issue.
+pub fn record_issue_sample(conn: &Connection, sample: &data::IssueSample) -> Result<()> {
+    sample.issue.insert_into(conn)?;
+    sample.insert_into(conn)
+}
+
+/// Numbers of the issues in the same repo which a PR's `body` says it fixes,
+/// using the keywords which GitHub, GitLab and Gitea all recognise, e.g.,
+/// "Fixes #12" or "closes: #3".
+pub fn fixed_issues(body: &str) -> Vec<u32> {
+    const KEYWORDS: &[&str] = &[
+        "close", "closes", "closed", "fix", "fixes", "fixed", "resolve", "resolves", "resolved",
+    ];
+    let words: Vec<&str> = body.split_whitespace().collect();
+    let mut result = vec![];
+    for pair in words.windows(2) {
+        let keyword = pair[0].trim_end_matches(':').to_lowercase();
+        if !KEYWORDS.contains(&&*keyword) || !pair[1].starts_with('#') {
+            continue;
+        }
+        let number = pair[1][1..].trim_end_matches(|c: char| !c.is_ascii_digit());
+        if let Ok(number) = number.parse() {
+            if !result.contains(&number) {
+                result.push(number);
+            }
+        }
+    }
+    result
+}
+
+/// Record `pr`'s labels as of `time`, marking any which have been added or
+/// removed since the last time.
+pub fn record_labels(conn: &Connection, pr: &data::PullRequest, time: &Date) -> Result<()> {
+    let current = db::current_labels(conn, &pr.host, pr.id)?;
+    for label in &pr.labels {
+        label.insert_into(conn)?;
+        if !current.contains(&label.name) {
+            data::PrLabel {
+                host: pr.host.clone(),
+                pr: pr.id,
+                label: label.name.clone(),
+                added: Date::new(time.date.clone()),
+                removed: None,
+            }
+            .insert_into(conn)?;
+        }
+    }
+    for label in current {
+        if !pr.labels.iter().any(|l| l.name == label) {
+            db::remove_label(conn, &pr.host, pr.id, &label, time)?;
+        }
+    }
+    Ok(())
+}
+
+/// Insert a PR's reviews, draft events and CI runs.
+pub fn record_activity(
+    conn: &Connection,
+    reviews: &[data::Review],
+    draft_events: &[data::DraftEvent],
+    checks: &[data::CheckRun],
+) -> Result<()> {
+    for review in reviews {
+        review.insert_into(conn)?;
+    }
+    for event in draft_events {
+        event.insert_into(conn)?;
+    }
+    for check in checks {
+        check.insert_into(conn)?;
+    }
+    Ok(())
+}
+
+/// Turn the statuses of commit `sha` into CI runs. `statuses` are `(context,
+/// state, time)`, oldest first. A run of a context starts with a `pending`
+/// status and finishes with the next status which isn't.
+pub fn statuses_to_checks(
+    host: &str,
+    pr: u32,
+    sha: &Sha,
+    statuses: Vec<(String, String, String)>,
+) -> Vec<data::CheckRun> {
+    let mut checks: Vec<data::CheckRun> = vec![];
+    for (context, state, time) in statuses {
+        let running = checks
+            .iter_mut()
+            .find(|c| c.name == context && c.finished.is_none());
+        let conclusion = match &*state {
+            "pending" => {
+                if running.is_none() {
+                    checks.push(data::CheckRun {
+                        host: host.to_owned(),
+                        pr,
+                        sha: sha.clone(),
+                        name: context,
+                        started: Date::new(time),
+                        finished: None,
+                        conclusion: None,
+                    });
+                }
+                continue;
+            }
+            "success" | "warning" => "success",
+            "failure" | "error" => "failure",
+            _ => "other",
+        };
+        match running {
+            Some(check) => {
+                check.finished = Some(Date::new(time));
+                check.conclusion = Some(conclusion.to_owned());
+            }
+            // No pending status, so we don't know when it started.
+            None => checks.push(data::CheckRun {
+                host: host.to_owned(),
+                pr,
+                sha: sha.clone(),
+                name: context,
+                started: Date::new(time.clone()),
+                finished: Some(Date::new(time)),
+                conclusion: Some(conclusion.to_owned()),
+            }),
+        }
+    }
+    checks
+}
+
+#[inline]
+pub fn saturating_from<T>(v: T) -> u32
+where
+    u32: TryFrom<T>,
+{
+    u32::try_from(v).unwrap_or_else(|_| u32::max_value())
+}
+
+#[inline]
+pub fn saturating_from_opt<T>(v: Option<T>) -> u32
+where
+    u32: TryFrom<T>,
+{
+    v.map(|v| u32::try_from(v).unwrap_or_else(|_| u32::max_value()))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sat_from() {
+        assert_eq!(42, saturating_from::<u64>(42));
+        assert_eq!(
+            u32::max_value(),
+            saturating_from::<u64>(u32::max_value() as u64 + 500)
+        );
+        assert_eq!(42, saturating_from_opt::<u64>(Some(42)));
+        assert_eq!(
+            u32::max_value(),
+            saturating_from_opt::<u64>(Some(u32::max_value() as u64 + 500))
+        );
+        assert_eq!(0, saturating_from_opt::<u64>(None));
+    }
+
+    #[test]
+    fn test_is_bot() {
+        assert!(!is_bot("bob", false));
+        assert!(is_bot("bob", true));
+        assert!(is_bot("dependabot[bot]", false));
+        assert!(is_bot("renovate-bot", false));
+        assert!(!is_bot("robot", false));
+    }
+
+    #[test]
+    fn test_fixed_issues() {
+        assert_eq!(fixed_issues("Fixes #12"), vec![12]);
+        assert_eq!(
+            fixed_issues("This PR\n\ncloses: #3, and FIXES #4.\nFixes #3"),
+            vec![3, 4]
+        );
+        assert!(fixed_issues("See #5, fixes nrc/other#6, fix #x").is_empty());
+    }
+
+    #[test]
+    fn test_statuses_to_checks() {
+        let status = |context: &str, state: &str, time: &str| {
+            (context.to_owned(), state.to_owned(), time.to_owned())
+        };
+        let checks = statuses_to_checks(
+            "https://api.github.com",
+            1,
+            &Sha("abc".to_owned()),
+            vec![
+                status("ci/test", "pending", "10:00"),
+                status("ci/lint", "success", "10:01"),
+                status("ci/test", "failure", "10:05"),
+                status("ci/test", "pending", "10:06"),
+                status("ci/test", "pending", "10:07"),
+            ],
+        );
+        let checks: Vec<_> = checks
+            .iter()
+            .map(|c| {
+                (
+                    &*c.name,
+                    &*c.started.date,
+                    c.finished.as_ref().map(|d| &*d.date),
+                    c.conclusion.as_ref().map(|c| &**c),
+                )
+            })
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                ("ci/test", "10:00", Some("10:05"), Some("failure")),
+                ("ci/lint", "10:01", Some("10:01"), Some("success")),
+                ("ci/test", "10:06", None, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Handles GitHub webhook deliveries so that samples are recorded as soon as a
+//! PR changes, rather than at the next poll. Polling in `update_loop` is kept as
+//! a safety net for missed deliveries.
+//!
+//! Recording a sample takes several requests to GitHub, so the server only
+//! checks and parses a delivery, and leaves the work to a worker thread (see
+//! `spawn_worker`). That way GitHub gets a response straight away, and other
+//! requests don't wait.
+
+use crate::auth::Auth;
+use crate::config::{Forge, Repo, REPOS, WEBHOOK_SECRET};
+use crate::{github, GhvError, Result};
+
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::Sha256;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// The largest delivery we'll read, GitHub caps payloads at 25 MB.
+pub const MAX_WEBHOOK_BYTES: u64 = 25 * 1024 * 1024;
+
+/// What the server should tell GitHub about a delivery.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The work for the delivery has been handed to the worker.
+    Queued,
+    Ignored,
+    BadSignature,
+}
+
+/// Handle a single delivery by sending any work to `worker`. `event` is the
+/// `X-GitHub-Event` header and `signature` the `X-Hub-Signature-256` header.
+pub fn handle(
+    worker: &Sender<Action>,
+    event: &str,
+    signature: Option<&str>,
+    body: &[u8],
+) -> Result<Outcome> {
+    if !verify_signature(WEBHOOK_SECRET.as_bytes(), signature, body) {
+        return Ok(Outcome::BadSignature);
+    }
+
+    match parse(event, body)? {
+        Some(action) => {
+            // The worker only stops if it panicked.
+            worker.send(action).map_err(|_| GhvError::Other)?;
+            Ok(Outcome::Queued)
+        }
+        None => Ok(Outcome::Ignored),
+    }
+}
+
+/// Start a thread which carries out the work for deliveries, one at a time.
+pub fn spawn_worker(auth: Auth) -> Sender<Action> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for action in receiver {
+            // TODO handle any errors
+            let _ = match action {
+                Action::Pull(repo, number) => github::update_pull(&auth, repo, number),
+                Action::Branch(repo, branch) => github::update_branch(&auth, repo, &branch),
+            };
+        }
+    });
+    sender
+}
+
+/// Check the HMAC-SHA256 `signature` (formatted as `sha256=<hex>`) of `body`.
+fn verify_signature(secret: &[u8], signature: Option<&str>, body: &[u8]) -> bool {
+    let signature = match signature {
+        Some(s) if s.starts_with("sha256=") => &s[7..],
+        _ => return false,
+    };
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    mac.input(body);
+    mac.verify(&signature).is_ok()
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Re-sample the PR with this number.
+    Pull(&'static Repo, u64),
+    /// Re-sample any open PRs with this head branch.
+    Branch(&'static Repo, String),
+}
+
+fn parse(event: &str, body: &[u8]) -> Result<Option<Action>> {
+    match event {
+        // `pull_request_review` payloads have the same shape as far as we care.
+        "pull_request" | "pull_request_review" => {
+            let payload: PullRequestEvent = serde_json::from_slice(body)?;
+            Ok(tracked_repo(&payload.repository)
+                .map(|repo| Action::Pull(repo, payload.pull_request.number)))
+        }
+        "push" => {
+            let payload: PushEvent = serde_json::from_slice(body)?;
+            if !payload.git_ref.starts_with("refs/heads/") {
+                return Ok(None);
+            }
+            Ok(tracked_repo(&payload.repository)
+                .map(|repo| Action::Branch(repo, payload.git_ref[11..].to_owned())))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The repo a delivery is about, matched on its web URL since that is in every
+/// kind of payload and tells us the host as well as the name. GitHub treats
+/// names case-insensitively.
+fn tracked_repo(repository: &Repository) -> Option<&'static Repo> {
+    REPOS.iter().find(|r| {
+        r.forge == Forge::GitHub && r.html_url().eq_ignore_ascii_case(&repository.html_url)
+    })
+}
+
+#[derive(Deserialize)]
+struct PullRequestEvent {
+    pull_request: PullRequest,
+    repository: Repository,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    number: u64,
+}
+
+#[derive(Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    repository: Repository,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    /// E.g., `https://github.com/nrc/gh-velocity`. Note that `url` is the API
+    /// URL in most payloads, but the web URL in `push` payloads.
+    html_url: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Example from GitHub's docs on validating webhook deliveries.
+    const SECRET: &[u8] = b"It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    // A push delivery, abridged, in which `repository.url` is the web URL.
+    const PUSH: &str = r#"{
+        "ref": "refs/heads/my-branch",
+        "before": "6113728f27ae82c7b1a177c8d03f9e96e0adf246",
+        "after": "0000000000000000000000000000000000000000",
+        "created": false,
+        "deleted": false,
+        "forced": false,
+        "base_ref": null,
+        "compare": "https://github.com/nrc/gh-velocity/compare/6113728f27ae...000000000000",
+        "commits": [],
+        "head_commit": null,
+        "repository": {
+            "id": 186853002,
+            "node_id": "MDEwOlJlcG9zaXRvcnkxODY4NTMwMDI=",
+            "name": "gh-velocity",
+            "full_name": "nrc/gh-velocity",
+            "private": false,
+            "owner": {
+                "name": "nrc",
+                "login": "nrc",
+                "id": 762626,
+                "html_url": "https://github.com/nrc",
+                "type": "User"
+            },
+            "html_url": "https://github.com/nrc/gh-velocity",
+            "url": "https://github.com/nrc/gh-velocity",
+            "created_at": 1557968983,
+            "pushed_at": 1557968985,
+            "default_branch": "master"
+        },
+        "pusher": {"name": "nrc", "email": "nrc@example.com"},
+        "sender": {"login": "nrc", "id": 762626, "type": "User"}
+    }"#;
+
+    #[test]
+    fn test_verify_signature() {
+        assert!(verify_signature(SECRET, Some(SIGNATURE), BODY));
+        assert!(!verify_signature(SECRET, Some(SIGNATURE), b"Hello, World?"));
+        assert!(!verify_signature(b"wrong secret", Some(SIGNATURE), BODY));
+        assert!(!verify_signature(SECRET, Some(&SIGNATURE[7..]), BODY));
+        assert!(!verify_signature(SECRET, Some("sha256=not hex"), BODY));
+        assert!(!verify_signature(SECRET, None, BODY));
+    }
+
+    #[test]
+    fn test_handle() -> Result<()> {
+        let repo = &REPOS[0];
+        let body = format!(
+            r#"{{"action": "closed", "pull_request": {{"number": 42}}, "repository": {{"html_url": "{}"}}}}"#,
+            repo.html_url()
+        );
+        let mut mac = Hmac::<Sha256>::new_varkey(WEBHOOK_SECRET.as_bytes()).unwrap();
+        mac.input(body.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.result().code()));
+
+        let (sender, receiver) = mpsc::channel();
+        assert_eq!(
+            handle(&sender, "pull_request", Some(&signature), body.as_bytes())?,
+            Outcome::Queued
+        );
+        assert_eq!(receiver.try_recv().ok(), Some(Action::Pull(repo, 42)));
+        assert_eq!(
+            handle(&sender, "ping", Some(&signature), body.as_bytes())?,
+            Outcome::Ignored
+        );
+        assert_eq!(
+            handle(&sender, "pull_request", None, body.as_bytes())?,
+            Outcome::BadSignature
+        );
+        assert!(receiver.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let repo = &REPOS[0];
+        let pr = format!(
+            r#"{{"action": "closed", "pull_request": {{"number": 42}}, "repository": {{"html_url": "{}"}}}}"#,
+            repo.html_url()
+        );
+        assert_eq!(
+            parse("pull_request", pr.as_bytes())?,
+            Some(Action::Pull(repo, 42))
+        );
+        assert_eq!(
+            parse("pull_request_review", pr.as_bytes())?,
+            Some(Action::Pull(repo, 42))
+        );
+
+        assert_eq!(
+            parse("push", PUSH.as_bytes())?,
+            Some(Action::Branch(repo, "my-branch".to_owned()))
+        );
+
+        let tag = format!(
+            r#"{{"ref": "refs/tags/v1.0", "repository": {{"html_url": "{}"}}}}"#,
+            repo.html_url()
+        );
+        assert_eq!(parse("push", tag.as_bytes())?, None);
+
+        let other_repo = r#"{"pull_request": {"number": 42}, "repository": {"html_url": "https://github.com/foo/bar"}}"#;
+        assert_eq!(parse("pull_request", other_repo.as_bytes())?, None);
+        let other_host = format!(
+            r#"{{"pull_request": {{"number": 42}}, "repository": {{"html_url": "https://github.example.com/{}"}}}}"#,
+            repo.full_name()
+        );
+        assert_eq!(parse("pull_request", other_host.as_bytes())?, None);
+        assert_eq!(parse("ping", b"{}")?, None);
+        assert!(parse("pull_request", b"{}").is_err());
+        Ok(())
+    }
+}
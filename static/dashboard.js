@@ -0,0 +1,164 @@
+// Renders the dashboard from the blob served at `/data` (see `frontend.rs`).
+
+"use strict";
+
+const SVG = "http://www.w3.org/2000/svg";
+
+// Bottom to top, so finished PRs accumulate at the bottom of the diagram.
+const FLOW_STATES = [
+  ["closed", "Closed", "#9e9e9e"],
+  ["merged", "Merged", "#6f42c1"],
+  ["approved", "Approved", "#2ea44f"],
+  ["in_review", "In review", "#f2c94c"],
+  ["awaiting_review", "Awaiting review", "#f2994a"],
+  ["draft", "Draft", "#bdbdbd"],
+];
+
+function element(name, attributes, parent) {
+  const el = document.createElementNS(SVG, name);
+  for (const [key, value] of Object.entries(attributes)) {
+    el.setAttribute(key, value);
+  }
+  parent.appendChild(el);
+  return el;
+}
+
+// A stacked area chart of the number of PRs in each state per day.
+function renderFlow(section, days) {
+  const legend = section.querySelector(".legend");
+  for (const [, name, colour] of FLOW_STATES.slice().reverse()) {
+    const item = document.createElement("span");
+    item.textContent = name;
+    item.style.setProperty("--colour", colour);
+    legend.appendChild(item);
+  }
+
+  const svg = section.querySelector("svg");
+  if (days.length === 0) {
+    element("text", { x: 10, y: 20 }, svg).textContent = "No data yet.";
+    return;
+  }
+
+  const margin = { top: 10, right: 10, bottom: 30, left: 40 };
+  const width = svg.getAttribute("width") - margin.left - margin.right;
+  const height = svg.getAttribute("height") - margin.top - margin.bottom;
+  const totals = days.map(day => FLOW_STATES.reduce((sum, [key]) => sum + day[key], 0));
+  const max = Math.max(1, ...totals);
+  const x = i => margin.left + (days.length === 1 ? 0 : (i * width) / (days.length - 1));
+  const y = count => margin.top + height - (count * height) / max;
+
+  const below = days.map(() => 0);
+  for (const [key, name, colour] of FLOW_STATES) {
+    const above = days.map((day, i) => below[i] + day[key]);
+    const top = above.map((count, i) => `${x(i)},${y(count)}`);
+    const bottom = below.map((count, i) => `${x(i)},${y(count)}`).reverse();
+    const area = element("polygon", { points: top.concat(bottom).join(" "), fill: colour }, svg);
+    element("title", {}, area).textContent = name;
+    above.forEach((count, i) => (below[i] = count));
+  }
+
+  element("text", { x: margin.left - 5, y: y(max) + 4, "text-anchor": "end" }, svg).textContent = max;
+  element("text", { x: margin.left - 5, y: y(0), "text-anchor": "end" }, svg).textContent = 0;
+  element("text", { x: x(0), y: margin.top + height + 20 }, svg).textContent = days[0].date;
+  element("text", { x: x(days.length - 1), y: margin.top + height + 20, "text-anchor": "end" }, svg)
+    .textContent = days[days.length - 1].date;
+}
+
+// A heatmap of the fraction of PRs opened each week which were merged within
+// so many days, newest week first, like a retention table.
+function renderCohorts(section, cohorts) {
+  const table = section.querySelector("table");
+  if (cohorts.length === 0) {
+    table.textContent = "No data yet.";
+    return;
+  }
+  const header = table.insertRow();
+  cell(header, "Week", "th");
+  cell(header, "Opened", "th");
+  for (const { days } of cohorts[0].merged_within) {
+    cell(header, `${days} ${days === 1 ? "day" : "days"}`, "th");
+  }
+  for (const cohort of cohorts.slice().reverse()) {
+    const row = table.insertRow();
+    cell(row, cohort.start_date);
+    cell(row, cohort.opened_prs);
+    for (const { merged_prs, fraction } of cohort.merged_within) {
+      if (fraction === null) {
+        cell(row, "");
+        continue;
+      }
+      const td = cell(row, `${(fraction * 100).toFixed(0)}%`);
+      td.title = `${merged_prs} of ${cohort.opened_prs}`;
+      td.style.background = `rgba(46, 164, 79, ${fraction})`;
+    }
+  }
+}
+
+// Metrics from `/compare` for which an increase is an improvement, and which
+// are neither better nor worse. For all others a decrease is an improvement.
+const HIGHER_IS_BETTER = ["merged_prs_per_week"];
+const NEUTRAL = ["opened_prs_per_week"];
+
+function cell(row, text, tag = "td") {
+  const el = document.createElement(tag);
+  el.textContent = text;
+  row.appendChild(el);
+  return el;
+}
+
+// A table of the changes in each metric between two periods, significant
+// changes are coloured by whether they are for the better.
+function renderComparison(table, comparison) {
+  table.textContent = "";
+  const { current, previous } = comparison;
+  const header = table.insertRow();
+  for (const text of ["", `${previous.start} to ${previous.end}`, `${current.start} to ${current.end}`, "Change", ""]) {
+    cell(header, text, "th");
+  }
+  for (const delta of comparison.metrics) {
+    const row = table.insertRow();
+    cell(row, delta.metric.replace(/_/g, " "));
+    cell(row, delta.previous.toFixed(1));
+    cell(row, delta.current.toFixed(1));
+    const relative = delta.relative === null ? "" : ` (${(delta.relative * 100).toFixed(0)}%)`;
+    const change = cell(row, `${delta.absolute >= 0 ? "+" : ""}${delta.absolute.toFixed(1)}${relative}`);
+    cell(row, delta.significant ? "significant" : "");
+    if (delta.significant && !NEUTRAL.includes(delta.metric)) {
+      const better = (delta.absolute > 0) === HIGHER_IS_BETTER.includes(delta.metric);
+      change.className = better ? "better" : "worse";
+    }
+  }
+}
+
+function setUpComparison(section) {
+  const form = section.querySelector("form");
+  form.addEventListener("submit", event => {
+    event.preventDefault();
+    const params = new URLSearchParams();
+    for (const [name, value] of new FormData(form)) {
+      if (value) {
+        params.set(name, value);
+      }
+    }
+    fetch(`/compare?${params}`)
+      .then(response => {
+        if (!response.ok) {
+          throw new Error(response.statusText);
+        }
+        return response.json();
+      })
+      .then(comparison => renderComparison(section.querySelector("table"), comparison))
+      .catch(() => {
+        section.querySelector("table").textContent = "Could not compare those periods.";
+      });
+  });
+}
+
+setUpComparison(document.getElementById("compare"));
+
+fetch("/data")
+  .then(response => response.json())
+  .then(blob => {
+    renderFlow(document.getElementById("flow"), blob.flow);
+    renderCohorts(document.getElementById("cohorts"), blob.cohorts);
+  });